@@ -467,7 +467,9 @@ impl HilState {
     fn send_device_custom_message(&mut self, esp_id: u32, line1: String, line2: String) {
         self.send_resp(
             UnixResponseData::CustomMessage {
-                esp_id,
+                esp_id: Some(esp_id),
+                rooms: Vec::new(),
+                all: false,
                 line1,
                 line2,
             },
@@ -496,6 +498,7 @@ impl HilState {
                     .map(|d| unix_utils::response::CompetitionStatusDevice {
                         esp_id: d.id,
                         sign_key: d.sign_key,
+                        room: None,
                     })
                     .collect(),
                 translations: self.status.translations.clone(),
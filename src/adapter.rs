@@ -1,10 +1,11 @@
+use crate::config::Config;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::{path::Path, time::Duration};
 use uuid::Uuid;
 
-pub fn adapter_api_url() -> String {
-    std::env::var("ADAPTER_API").unwrap_or_else(|_| {
+pub fn adapter_api_url(config: &Config) -> String {
+    config.adapter_api.clone().unwrap_or_else(|| {
         if is_running_in_docker() {
             "http://host.docker.internal:3127"
         } else {
@@ -39,7 +40,12 @@ struct RegisterMdnsApi {
     pub host_name: String,
 }
 
-pub async fn register_mdns(client: reqwest::Client, api_url: String, port: u16) -> Result<()> {
+pub async fn register_mdns(
+    client: reqwest::Client,
+    api_url: String,
+    port: u16,
+    tls_fingerprint: Option<String>,
+) -> Result<()> {
     tracing::info!("Using MDNS Adapter api!");
     let mut data = RegisterMdnsApi {
         all_interfaces: true,
@@ -50,12 +56,14 @@ pub async fn register_mdns(client: reqwest::Client, api_url: String, port: u16)
         port,
         host_name: crate::mdns::HOST_NAME.to_string(),
     };
-    if std::env::var("NO_TLS").is_ok() {
+    if let Some(fingerprint) = tls_fingerprint {
         data.properties
-            .push(("ws".to_string(), format!("ws://{{IF_IP}}:{port}")));
+            .push(("ws".to_string(), format!("wss://{{IF_IP}}:{port}")));
+        data.properties
+            .push((crate::mdns::FINGERPRINT_PROPERTY.to_string(), fingerprint));
     } else {
         data.properties
-            .push(("ws".to_string(), format!("wss://{{IF_IP}}:{port}")));
+            .push(("ws".to_string(), format!("ws://{{IF_IP}}:{port}")));
     }
 
     let data_json = serde_json::to_string(&data)?;
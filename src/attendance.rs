@@ -0,0 +1,175 @@
+use crate::{http::EspConnectInfo, socket::api::AttendanceInfo, structs::TimerPacketInner};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use unix_utils::UnixError;
+
+/// Firmware capability: device shows `AttendanceResult` (name, reason),
+/// other devices get `AttendanceMarked` or `ApiError`
+pub const ATTENDANCE_RESULT_CAPABILITY: &str = "attendance_result";
+
+const ALREADY_MARKED: &str = "Already marked";
+
+#[derive(Debug, Clone)]
+struct AttendanceMark {
+    name: Option<String>,
+    marked_at: Instant,
+}
+
+/// Recently marked attendance per (device, card), repeated scans on the same
+/// device within window aren't sent to backend
+#[derive(Debug, Clone, Default)]
+pub struct AttendanceMarks {
+    marks: HashMap<(u32, u64), AttendanceMark>,
+}
+
+impl AttendanceMarks {
+    /// Response for duplicate scan, None if card wasn't marked on this device within `window`
+    pub fn duplicate(
+        &mut self,
+        esp_id: u32,
+        card_id: u64,
+        window: Duration,
+        now: Instant,
+    ) -> Option<TimerPacketInner> {
+        self.marks
+            .retain(|_, mark| now.duration_since(mark.marked_at) < window);
+
+        let mark = self.marks.get(&(esp_id, card_id))?;
+        Some(TimerPacketInner::AttendanceResult {
+            success: true,
+            name: mark.name.clone(),
+            reason: Some(ALREADY_MARKED.to_string()),
+        })
+    }
+
+    pub fn insert(&mut self, esp_id: u32, card_id: u64, name: Option<String>, now: Instant) {
+        self.marks.insert(
+            (esp_id, card_id),
+            AttendanceMark {
+                name,
+                marked_at: now,
+            },
+        );
+    }
+}
+
+/// Device response for backend result
+pub fn result_packet(result: Result<AttendanceInfo, UnixError>) -> TimerPacketInner {
+    match result {
+        Ok(info) => TimerPacketInner::AttendanceResult {
+            success: true,
+            name: info.name,
+            reason: info.message,
+        },
+        Err(e) => TimerPacketInner::AttendanceResult {
+            success: false,
+            name: None,
+            reason: Some(e.message),
+        },
+    }
+}
+
+/// Translate `AttendanceResult` for devices without [`ATTENDANCE_RESULT_CAPABILITY`]
+pub fn for_device(data: TimerPacketInner, esp_connect_info: &EspConnectInfo) -> TimerPacketInner {
+    if esp_connect_info.has_cap(ATTENDANCE_RESULT_CAPABILITY) {
+        return data;
+    }
+
+    match data {
+        TimerPacketInner::AttendanceResult { success: true, .. } => {
+            TimerPacketInner::AttendanceMarked
+        }
+        TimerPacketInner::AttendanceResult {
+            success: false,
+            reason,
+            ..
+        } => TimerPacketInner::ApiError {
+            error: reason.unwrap_or_default(),
+            should_reset_time: false,
+        },
+        data => data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{socket::api::attendance_info, test_util::esp_info};
+    use unix_utils::response::UnixResponseData;
+
+    #[test]
+    fn duplicate_window() {
+        let mut marks = AttendanceMarks::default();
+        let window = Duration::from_secs(60);
+        let now = Instant::now();
+
+        assert!(marks.duplicate(1, 100, window, now).is_none());
+        marks.insert(1, 100, Some("John".to_string()), now);
+
+        let later = now + Duration::from_secs(30);
+        assert!(matches!(
+            marks.duplicate(1, 100, window, later),
+            Some(TimerPacketInner::AttendanceResult { success: true, name: Some(name), .. }) if name == "John"
+        ));
+
+        // same card on other device, other card on the same device
+        assert!(marks.duplicate(2, 100, window, later).is_none());
+        assert!(marks.duplicate(1, 101, window, later).is_none());
+
+        assert!(
+            marks
+                .duplicate(1, 100, window, now + Duration::from_secs(60))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn backend_response() {
+        let info = |version: &str| EspConnectInfo {
+            caps: ATTENDANCE_RESULT_CAPABILITY.to_string(),
+            ..esp_info(0x1234, version)
+        };
+
+        let packet = result_packet(attendance_info(UnixResponseData::CreateAttendanceResp {
+            name: "John".to_string(),
+            message: Some("Group 1".to_string()),
+        }));
+        assert_eq!(
+            serde_json::to_string(&packet).unwrap(),
+            r#"{"attendance_result":{"success":true,"name":"John","reason":"Group 1"}}"#
+        );
+
+        let packet = result_packet(attendance_info(UnixResponseData::Success {
+            message: "Marked".to_string(),
+        }));
+        assert!(matches!(
+            &packet,
+            TimerPacketInner::AttendanceResult { success: true, name: None, reason: Some(r) } if r == "Marked"
+        ));
+
+        let packet = result_packet(Err(UnixError {
+            message: "Competitor not found".to_string(),
+            should_reset_time: false,
+        }));
+        assert!(matches!(
+            for_device(packet.clone(), &info("v3.1.0")),
+            TimerPacketInner::AttendanceResult { success: false, .. }
+        ));
+
+        let mut old = info("v3.0.0");
+        old.caps = String::new();
+        assert!(matches!(
+            for_device(packet, &old),
+            TimerPacketInner::ApiError { error, .. } if error == "Competitor not found"
+        ));
+
+        let packet = result_packet(attendance_info(UnixResponseData::Empty));
+        let packet = for_device(packet, &old);
+        assert_eq!(
+            serde_json::to_string(&packet).unwrap(),
+            r#""attendance_marked""#
+        );
+    }
+}
@@ -0,0 +1,351 @@
+use crate::{http::EspConnectInfo, structs::SharedAppState};
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+
+/// Firmware capability: device signs packets with HMAC-SHA256 instead of sending raw sign key
+pub const HMAC_CAPABILITY: &str = "hmac";
+
+/// HMAC key size (provisioned by device in `Add` packet)
+pub const HMAC_KEY_SIZE: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct DeviceKey {
+    /// Base64 of [`HMAC_KEY_SIZE`] bytes
+    key: String,
+
+    /// Last accepted packet nonce (replay protection)
+    last_nonce: Option<u64>,
+}
+
+/// HMAC keys and last nonces of devices, persisted (replay protection survives restarts)
+#[derive(Debug)]
+pub struct DeviceKeys {
+    path: PathBuf,
+    devices: HashMap<u32, DeviceKey>,
+
+    /// Keys waiting for backend to add device (not persisted, device sends `Add` again)
+    pending: HashMap<u32, String>,
+}
+
+/// Trimmed base64 key if it has [`HMAC_KEY_SIZE`] bytes
+fn check_key(key: &str) -> Result<String> {
+    let decoded = base64::prelude::BASE64_STANDARD.decode(key.trim())?;
+    if decoded.len() != HMAC_KEY_SIZE {
+        return Err(anyhow!("Invalid HMAC key size: {}", decoded.len()));
+    }
+
+    Ok(key.trim().to_string())
+}
+
+impl DeviceKeys {
+    pub async fn load(path: PathBuf) -> Self {
+        let devices = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                tracing::error!("Device keys parse error (starting empty): {e:?}");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        Self {
+            path,
+            devices,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn has_key(&self, esp_id: u32) -> bool {
+        self.devices.contains_key(&esp_id)
+    }
+
+    /// Store key provisioned by device (nonces start again)
+    pub async fn provision(&mut self, esp_id: u32, key: &str) -> Result<()> {
+        let key = check_key(key)?;
+        self.pending.remove(&esp_id);
+        self.devices.insert(
+            esp_id,
+            DeviceKey {
+                key,
+                last_nonce: None,
+            },
+        );
+        self.save().await
+    }
+
+    /// Keep key sent by device until backend adds it (see [`DeviceKeys::confirm`])
+    pub fn propose(&mut self, esp_id: u32, key: &str) -> Result<()> {
+        let key = check_key(key)?;
+        if let Some(old) = self.pending.insert(esp_id, key.clone())
+            && old != key
+        {
+            tracing::warn!("[{esp_id:X}] Pending HMAC key replaced by another Add packet");
+        }
+
+        Ok(())
+    }
+
+    /// Backend added device, its pending key replaces stored one (if there is any)
+    pub async fn confirm(&mut self, esp_id: u32) -> Result<()> {
+        let Some(key) = self.pending.remove(&esp_id) else {
+            return Ok(());
+        };
+
+        tracing::info!("[{esp_id:X}] HMAC key confirmed by backend");
+        self.provision(esp_id, &key).await
+    }
+
+    /// Verify packet mac and nonce (nonce is stored only if accepted).
+    /// Repeated nonce is allowed for resent packets that are answered from solve ledger.
+    pub async fn verify(
+        &mut self,
+        esp_id: u32,
+        nonce: u64,
+        payload: &str,
+        mac: &str,
+        allow_resend: bool,
+    ) -> Result<()> {
+        let device = self
+            .devices
+            .get_mut(&esp_id)
+            .ok_or_else(|| anyhow!("No HMAC key provisioned (add device again)"))?;
+        let key = base64::prelude::BASE64_STANDARD.decode(&device.key)?;
+
+        if !verify_mac(&key, nonce, payload, mac) {
+            return Err(anyhow!("Wrong packet mac!"));
+        }
+
+        if device.last_nonce.is_some_and(|last| nonce <= last) {
+            if allow_resend {
+                return Ok(());
+            }
+
+            return Err(anyhow!("Replayed packet (nonce: {nonce})"));
+        }
+
+        device.last_nonce = Some(nonce);
+        self.save().await
+    }
+
+    async fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.devices)?;
+        crate::fs_util::write_atomic(&self.path, &data, true).await
+    }
+}
+
+/// Authentication fields sent by device with signed packets
+#[derive(Debug)]
+pub struct PacketAuth<'a> {
+    pub sign_key: u32,
+    pub mac: Option<&'a str>,
+    pub nonce: Option<u64>,
+
+    /// Packet is resend of already processed one (repeated nonce is allowed)
+    pub resend: bool,
+
+    /// Canonical packet fields (see [`solve_payload`] and [`card_info_payload`])
+    pub payload: String,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn solve_payload(
+    esp_id: u32,
+    solve_time: u64,
+    penalty: i64,
+    competitor_id: u64,
+    judge_id: u64,
+    timestamp: u64,
+    session_id: &str,
+    delegate: bool,
+    inspection_time: i64,
+    group_id: &str,
+) -> String {
+    format!(
+        "solve:{esp_id}:{solve_time}:{penalty}:{competitor_id}:{judge_id}:{timestamp}:{session_id}:{}:{inspection_time}:{group_id}",
+        delegate as u8
+    )
+}
+
+pub fn card_info_payload(
+    esp_id: u32,
+    card_id: u64,
+    is_competitor: bool,
+    attendance_device: Option<bool>,
+) -> String {
+    format!(
+        "card:{esp_id}:{card_id}:{}:{}",
+        is_competitor as u8,
+        attendance_device.unwrap_or(false) as u8
+    )
+}
+
+/// Check that packet was sent by device (and isn't replayed).
+/// Devices authenticated with current protocol don't get legacy packets accepted anymore.
+///
+/// Devices without [`HMAC_CAPABILITY`] still send raw sign key.
+pub async fn authenticate_packet(
+    state: &SharedAppState,
+    esp_connect_info: &EspConnectInfo,
+    auth: PacketAuth<'_>,
+) -> Result<()> {
+    check_packet(state, esp_connect_info, auth).await?;
+
+    if !crate::legacy::is_legacy(esp_connect_info) {
+        let res = state
+            .current_protocol_devices
+            .lock()
+            .await
+            .record(esp_connect_info.id)
+            .await;
+        if let Err(e) = res {
+            tracing::error!("Current protocol devices save error: {e:?}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn check_packet(
+    state: &SharedAppState,
+    esp_connect_info: &EspConnectInfo,
+    auth: PacketAuth<'_>,
+) -> Result<()> {
+    let esp_id = esp_connect_info.id;
+    let sign_key = state
+        .inner
+        .read()
+        .await
+        .devices_settings
+        .get(&esp_id)
+        .map(|settings| settings.sign_key);
+    let Some(sign_key) = sign_key else {
+        return Err(anyhow!("Device not added"));
+    };
+
+    let Some(dev_sign_key) = sign_key else {
+        return Ok(());
+    };
+
+    if !esp_connect_info.has_cap(HMAC_CAPABILITY) {
+        if dev_sign_key != auth.sign_key {
+            return Err(anyhow!("Wrong sign key!"));
+        }
+
+        return Ok(());
+    }
+
+    let (Some(mac), Some(nonce)) = (auth.mac, auth.nonce) else {
+        return Err(anyhow!("Missing packet mac!"));
+    };
+
+    state
+        .device_keys
+        .lock()
+        .await
+        .verify(esp_id, nonce, &auth.payload, mac, auth.resend)
+        .await
+}
+
+/// Constant time mac verification (mac is hex encoded)
+pub fn verify_mac(key: &[u8], nonce: u64, payload: &str, mac: &str) -> bool {
+    let Some(mac) = decode_hex(mac) else {
+        return false;
+    };
+
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    hmac::verify(&key, format!("{nonce}:{payload}").as_bytes(), &mac).is_ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const KEY: [u8; HMAC_KEY_SIZE] = [0xAB; HMAC_KEY_SIZE];
+
+    fn compute_mac(key: &[u8], nonce: u64, payload: &str) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+        let tag = hmac::sign(&key, format!("{nonce}:{payload}").as_bytes());
+
+        tag.as_ref().iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn mac_roundtrip() {
+        let payload = card_info_payload(0x1234, 69420, true, None);
+        let mac = compute_mac(&KEY, 5, &payload);
+        let mut other_key = KEY;
+        other_key[31] ^= 1;
+
+        assert!(verify_mac(&KEY, 5, &payload, &mac));
+        assert!(verify_mac(&KEY, 5, &payload, &mac.to_uppercase()));
+        assert!(!verify_mac(&KEY, 6, &payload, &mac));
+        assert!(!verify_mac(&other_key, 5, &payload, &mac));
+        assert!(!verify_mac(&KEY, 5, "card:0:0:0:0", &mac));
+        assert!(!verify_mac(&KEY, 5, &payload, &mac[1..]));
+        assert!(!verify_mac(&KEY, 5, &payload, "zz"));
+    }
+
+    #[tokio::test]
+    async fn nonces_persist() {
+        let dir = TempDir::new("device_keys");
+        let path = dir.join("device_keys.json");
+        let key = base64::prelude::BASE64_STANDARD.encode(KEY);
+        let payload = card_info_payload(0x1234, 69420, true, None);
+
+        let mut keys = DeviceKeys::load(path.clone()).await;
+        assert!(keys.provision(0x1234, "c2hvcnQ=").await.is_err());
+        keys.provision(0x1234, &key).await.unwrap();
+        assert!(
+            keys.verify(0x1234, 5, &payload, &compute_mac(&KEY, 5, &payload), false)
+                .await
+                .is_ok()
+        );
+
+        // replay protection survives restart, resent packets are allowed explicitly
+        let mut keys = DeviceKeys::load(path.clone()).await;
+        let mac = compute_mac(&KEY, 5, &payload);
+        assert!(keys.verify(0x1234, 5, &payload, &mac, false).await.is_err());
+        assert!(keys.verify(0x1234, 5, &payload, &mac, true).await.is_ok());
+        assert!(keys.verify(0x1234, 6, &payload, &mac, true).await.is_err());
+        assert!(keys.verify(0x5678, 5, &payload, &mac, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn key_replaced_only_when_confirmed() {
+        let dir = TempDir::new("device_keys_confirm");
+        let key = base64::prelude::BASE64_STANDARD.encode(KEY);
+        let other_key = base64::prelude::BASE64_STANDARD.encode([0xCD; HMAC_KEY_SIZE]);
+        let payload = card_info_payload(0x1234, 69420, true, None);
+
+        let mut keys = DeviceKeys::load(dir.join("device_keys.json")).await;
+        assert!(keys.propose(0x1234, "c2hvcnQ=").is_err());
+        keys.propose(0x1234, &key).unwrap();
+        assert!(!keys.has_key(0x1234));
+        keys.confirm(0x1234).await.unwrap();
+        assert!(keys.has_key(0x1234));
+
+        // key sent later doesn't replace confirmed one until backend adds device again
+        keys.propose(0x1234, &other_key).unwrap();
+        let mac = compute_mac(&KEY, 1, &payload);
+        assert!(keys.verify(0x1234, 1, &payload, &mac, false).await.is_ok());
+        keys.confirm(0x1234).await.unwrap();
+        let mac = compute_mac(&KEY, 2, &payload);
+        assert!(keys.verify(0x1234, 2, &payload, &mac, false).await.is_err());
+        let mac = compute_mac(&[0xCD; HMAC_KEY_SIZE], 2, &payload);
+        assert!(keys.verify(0x1234, 2, &payload, &mac, false).await.is_ok());
+    }
+}
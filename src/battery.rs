@@ -0,0 +1,252 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+};
+
+/// Max samples kept per device
+const MAX_SAMPLES: usize = 360;
+
+/// Only samples from this window are used for drain rate estimation
+const DRAIN_WINDOW_MS: i64 = 2 * 60 * 60 * 1000;
+
+/// Min time span of samples needed to estimate drain rate
+const MIN_DRAIN_SPAN_MS: i64 = 10 * 60 * 1000;
+
+/// Alert is cleared after level rises this much above threshold (charging)
+const ALERT_HYSTERESIS: f64 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BatterySample {
+    pub at_ms: i64,
+    pub level: Option<f64>,
+    pub voltage: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatteryThresholds {
+    pub low_level: f64,
+    pub low_minutes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatteryAlert {
+    pub level: f64,
+    pub voltage: Option<f64>,
+    pub minutes_left: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DeviceBattery {
+    samples: VecDeque<BatterySample>,
+    alerted: bool,
+}
+
+impl DeviceBattery {
+    /// Drain rate in % per hour (None if not discharging or not enough samples)
+    fn drain_rate(&self) -> Option<f64> {
+        let last = self.samples.back()?.at_ms;
+        let points: Vec<(f64, f64)> = self
+            .samples
+            .iter()
+            .filter(|s| last - s.at_ms <= DRAIN_WINDOW_MS)
+            .filter_map(|s| Some(((s.at_ms - last) as f64, s.level?)))
+            .collect();
+
+        let span = -points.first()?.0;
+        if points.len() < 3 || span < MIN_DRAIN_SPAN_MS as f64 {
+            return None;
+        }
+
+        // least squares slope (% per ms)
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let (num, den) = points.iter().fold((0.0, 0.0), |(num, den), (x, y)| {
+            (
+                num + (x - mean_x) * (y - mean_y),
+                den + (x - mean_x) * (x - mean_x),
+            )
+        });
+
+        let rate = -(num / den) * 60.0 * 60.0 * 1000.0;
+        (rate > 0.0).then_some(rate)
+    }
+}
+
+/// Per-device battery history, persisted to disk by [`BatteryHistory::flush`]
+#[derive(Debug)]
+pub struct BatteryHistory {
+    path: PathBuf,
+    devices: HashMap<u32, DeviceBattery>,
+
+    /// Samples recorded since last save
+    dirty: bool,
+}
+
+impl BatteryHistory {
+    pub async fn load(path: PathBuf) -> Self {
+        let devices = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                tracing::error!("Battery history parse error (starting empty): {e:?}");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        Self {
+            path,
+            devices,
+            dirty: false,
+        }
+    }
+
+    /// Record sample, returns alert if device battery just became low.
+    /// History is saved on next [`BatteryHistory::flush`].
+    pub fn record(
+        &mut self,
+        esp_id: u32,
+        sample: BatterySample,
+        thresholds: BatteryThresholds,
+    ) -> Option<BatteryAlert> {
+        self.dirty = true;
+        self.push(esp_id, sample, thresholds)
+    }
+
+    /// Save history if any sample was recorded since last save
+    pub async fn flush(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        self.save().await?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Estimated drain rate of device in % per hour
+    pub fn drain_rate(&self, esp_id: u32) -> Option<f64> {
+        self.devices.get(&esp_id)?.drain_rate()
+    }
+
+    fn push(
+        &mut self,
+        esp_id: u32,
+        sample: BatterySample,
+        thresholds: BatteryThresholds,
+    ) -> Option<BatteryAlert> {
+        let device = self.devices.entry(esp_id).or_default();
+        if device.samples.len() >= MAX_SAMPLES {
+            device.samples.pop_front();
+        }
+        device.samples.push_back(sample);
+
+        let level = sample.level?;
+        let minutes_left = device
+            .drain_rate()
+            .map(|rate| (level / rate * 60.0).round() as u64);
+
+        let low = level <= thresholds.low_level
+            || minutes_left.is_some_and(|m| m <= thresholds.low_minutes);
+
+        if low && !device.alerted {
+            device.alerted = true;
+            return Some(BatteryAlert {
+                level,
+                voltage: sample.voltage,
+                minutes_left,
+            });
+        }
+
+        if device.alerted
+            && level > thresholds.low_level + ALERT_HYSTERESIS
+            && minutes_left.is_none_or(|m| m > thresholds.low_minutes)
+        {
+            device.alerted = false;
+        }
+
+        None
+    }
+
+    async fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        crate::fs_util::write_atomic(&self.path, &serde_json::to_vec(&self.devices)?, false).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn drain_and_alerts() {
+        let thresholds = BatteryThresholds {
+            low_level: 15.0,
+            low_minutes: 30,
+        };
+        let mut history = BatteryHistory {
+            path: PathBuf::new(),
+            devices: HashMap::new(),
+            dirty: false,
+        };
+
+        let sample = |minute: i64, level: f64| BatterySample {
+            at_ms: minute * 60 * 1000,
+            level: Some(level),
+            voltage: Some(3.7),
+        };
+
+        // 1% per minute
+        for minute in 0..10 {
+            let alert = history.push(1, sample(minute, 100.0 - minute as f64), thresholds);
+            assert_eq!(alert, None);
+        }
+        assert_eq!(history.drain_rate(1), None);
+
+        assert_eq!(history.push(1, sample(10, 90.0), thresholds), None);
+        let rate = history.drain_rate(1).unwrap();
+        assert!((rate - 60.0).abs() < 0.01);
+
+        // predicted time to empty crossed
+        let alert = history.push(1, sample(70, 30.0), thresholds).unwrap();
+        assert_eq!(alert.minutes_left, Some(30));
+        assert_eq!(history.push(1, sample(71, 29.0), thresholds), None);
+
+        // level threshold (without drain estimation)
+        assert!(history.push(2, sample(0, 10.0), thresholds).is_some());
+        assert!(history.push(2, sample(1, 18.0), thresholds).is_none());
+        assert!(history.push(2, sample(2, 25.0), thresholds).is_none());
+        assert!(history.push(2, sample(3, 12.0), thresholds).is_some());
+    }
+
+    #[tokio::test]
+    async fn debounced_save() {
+        let dir = TempDir::new("battery");
+        let path = dir.join("battery_history.json");
+        let thresholds = BatteryThresholds {
+            low_level: 15.0,
+            low_minutes: 30,
+        };
+        let sample = BatterySample {
+            at_ms: 0,
+            level: Some(80.0),
+            voltage: None,
+        };
+
+        let mut history = BatteryHistory::load(path.clone()).await;
+        history.flush().await.unwrap();
+        assert!(!path.exists());
+
+        history.record(1, sample, thresholds);
+        assert!(!path.exists());
+        history.flush().await.unwrap();
+
+        let history = BatteryHistory::load(path).await;
+        assert_eq!(history.devices[&1].samples, [sample]);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::adapter::BleAdapter;
+use crate::config::Config;
 use crate::structs::SharedAppState;
 use anyhow::Result;
 use btleplug::api::{Central, Manager as _, Peripheral, ScanFilter};
@@ -8,8 +9,8 @@ use serde::Deserialize;
 //const FKM_UUID: uuid::Uuid = uuid::uuid!("f254a578-ef88-4372-b5f5-5ecf87e65884");
 const SET_WIFI_UUID: uuid::Uuid = uuid::uuid!("bcd7e573-b0b2-4775-83c0-acbf3aaf210c");
 
-pub async fn start_bluetooth_task(state: SharedAppState) -> Result<()> {
-    let adapter_api = crate::adapter::adapter_api_url();
+pub async fn start_bluetooth_task(config: &Config, state: SharedAppState) -> Result<()> {
+    let adapter_api = crate::adapter::adapter_api_url(config);
     let client = reqwest::Client::new();
     if let Ok(res) = client.get(&adapter_api).send().await
         && res.status().is_success()
@@ -89,7 +90,7 @@ async fn bluetooth_task(state: &SharedAppState) -> Result<()> {
                 properties.local_name.unwrap_or("none".to_string())
             );
 
-            let res = setup_bt_device(device).await;
+            let res = setup_bt_device(state, device).await;
             if let Err(e) = res {
                 tracing::error!("Failed to setup BT device: {:?}", e);
             }
@@ -119,26 +120,9 @@ async fn adapter_bluetooth_task(state: &SharedAppState, adapter: &mut BleAdapter
             tracing::info!("Found FKM device with name: \"{}\"!", device.local_name);
 
             tracing::trace!("Getting wifi settings");
-            let auto_setup_settings =
-                if let Ok(ass) = crate::socket::api::get_auto_setup_settings().await {
-                    ass
-                } else {
-                    std::env::var("AUTOSETUP_SETTINGS")?
-                };
-
-            let res = serde_json::from_str::<AutoSetupSettings>(&auto_setup_settings);
-            match res {
-                Ok(ass) => {
-                    if ass.ssid.is_empty() {
-                        tracing::trace!("Wifi ssid null... Skipping!");
-                        return Ok(());
-                    }
-                }
-                Err(e) => {
-                    tracing::error!("Wifi auto setup settings parse error: {e:?}");
-                    return Ok(());
-                }
-            }
+            let Some(auto_setup_settings) = get_auto_setup_payload(state).await? else {
+                return Ok(());
+            };
 
             let set_wifi_data = format!("{auto_setup_settings}\0");
             let set_wifi_data = set_wifi_data.as_bytes();
@@ -169,7 +153,56 @@ struct ConnSettings {
     pub ws_url: Option<String>,
 }
 
-async fn setup_bt_device(device: btleplug::platform::Peripheral) -> Result<()> {
+/// Get wifi settings from API or env, returns None if settings are unusable.
+///
+/// If TLS is enabled, certificate fingerprint is added to connection settings
+/// (`data.certFingerprint`) so device can pin it.
+async fn get_auto_setup_payload(state: &SharedAppState) -> Result<Option<String>> {
+    let auto_setup_settings = if let Ok(ass) = crate::socket::api::get_auto_setup_settings().await {
+        ass
+    } else {
+        state
+            .config
+            .read()
+            .await
+            .autosetup_settings
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Auto setup settings not set"))?
+    };
+
+    let res = serde_json::from_str::<AutoSetupSettings>(&auto_setup_settings);
+    match res {
+        Ok(ass) => {
+            if ass.ssid.is_empty() {
+                tracing::trace!("Wifi ssid null... Skipping!");
+                return Ok(None);
+            }
+        }
+        Err(e) => {
+            tracing::error!("Wifi auto setup settings parse error: {e:?}");
+            return Ok(None);
+        }
+    }
+
+    let Some(fingerprint) = &state.tls_fingerprint else {
+        return Ok(Some(auto_setup_settings));
+    };
+
+    let mut value: serde_json::Value = serde_json::from_str(&auto_setup_settings)?;
+    if let Some(data) = value.get_mut("data").and_then(|d| d.as_object_mut()) {
+        data.insert(
+            "certFingerprint".to_string(),
+            serde_json::Value::String(fingerprint.clone()),
+        );
+    }
+
+    Ok(Some(serde_json::to_string(&value)?))
+}
+
+async fn setup_bt_device(
+    state: &SharedAppState,
+    device: btleplug::platform::Peripheral,
+) -> Result<()> {
     if !device.is_connected().await? {
         tracing::trace!("Connecting to device");
         device.connect().await?;
@@ -187,28 +220,11 @@ async fn setup_bt_device(device: btleplug::platform::Peripheral) -> Result<()> {
         .ok_or_else(|| anyhow::anyhow!("Couldn't find SET_WIFI characteristic!"))?;
     tracing::trace!("Got characteristics");
 
-    // get wifi settings from API or env
     tracing::trace!("Getting wifi settings");
-    let auto_setup_settings = if let Ok(ass) = crate::socket::api::get_auto_setup_settings().await {
-        ass
-    } else {
-        std::env::var("AUTOSETUP_SETTINGS")?
+    let Some(auto_setup_settings) = get_auto_setup_payload(state).await? else {
+        return Ok(());
     };
 
-    let res = serde_json::from_str::<AutoSetupSettings>(&auto_setup_settings);
-    match res {
-        Ok(ass) => {
-            if ass.ssid.is_empty() {
-                tracing::trace!("Wifi ssid null... Skipping!");
-                return Ok(());
-            }
-        }
-        Err(e) => {
-            tracing::error!("Wifi auto setup settings parse error: {e:?}");
-            return Ok(());
-        }
-    }
-
     let set_wifi_data = format!("{auto_setup_settings}\0");
     let set_wifi_data = set_wifi_data.as_bytes();
     tracing::trace!("Got wifi settings");
@@ -0,0 +1,182 @@
+use crate::structs::TimerPacketInner;
+use std::time::{Duration, Instant};
+
+/// Firmware capability: device answers `TimeSyncRequest` packets
+pub const TIME_SYNC_CAPABILITY: &str = "time_sync";
+
+/// Spacing between requests in a single sync round
+pub const SAMPLE_SPACING: Duration = Duration::from_millis(250);
+const SAMPLES_PER_ROUND: u32 = 8;
+const RESYNC_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Offsets bigger than this are flagged in device logs
+pub const MAX_TRUSTED_OFFSET_MS: i64 = 2000;
+
+/// Estimated device clock offset (device clock - server clock)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockOffset {
+    pub offset_ms: i64,
+    pub delay_ms: u64,
+
+    /// Server time (ms) of estimation
+    pub measured_at: u64,
+}
+
+/// NTP-like clock offset estimation (per connection).
+///
+/// Each round sends [`SAMPLES_PER_ROUND`] requests, the sample with the lowest
+/// round trip delay is used as the offset estimate (least affected by queueing).
+#[derive(Debug)]
+pub struct ClockSync {
+    seq: u32,
+    round_start_seq: u32,
+    sent_in_round: u32,
+    next_request: Instant,
+    best: Option<ClockOffset>,
+
+    /// Server times sent in current round (echoed time must match, device can't pick it)
+    sent_times: Vec<u64>,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self {
+            seq: 0,
+            round_start_seq: 0,
+            sent_in_round: 0,
+            next_request: Instant::now(),
+            best: None,
+            sent_times: Vec::with_capacity(SAMPLES_PER_ROUND as usize),
+        }
+    }
+
+    /// Returns request packet if it's time to send next sample
+    pub fn poll(&mut self, now: Instant, server_time: u64) -> Option<TimerPacketInner> {
+        if now < self.next_request {
+            return None;
+        }
+
+        if self.sent_in_round == 0 {
+            self.round_start_seq = self.seq;
+            self.best = None;
+            self.sent_times.clear();
+        }
+
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        self.sent_in_round += 1;
+        self.sent_times.push(server_time);
+
+        if self.sent_in_round >= SAMPLES_PER_ROUND {
+            self.sent_in_round = 0;
+            self.next_request = now + RESYNC_INTERVAL;
+        } else {
+            self.next_request = now + SAMPLE_SPACING;
+        }
+
+        Some(TimerPacketInner::TimeSyncRequest { seq, server_time })
+    }
+
+    /// Last sample of round was answered, its estimate won't improve anymore
+    pub fn round_finished(&self, seq: u32) -> bool {
+        seq == self.round_start_seq + SAMPLES_PER_ROUND - 1
+    }
+
+    /// Process device response, returns best offset estimate of current round
+    pub fn on_response(
+        &mut self,
+        seq: u32,
+        server_time: u64,
+        device_time: u64,
+        received_at: u64,
+    ) -> Option<ClockOffset> {
+        if seq < self.round_start_seq || seq >= self.seq || received_at < server_time {
+            return None;
+        }
+
+        let sent_time = self.sent_times.get((seq - self.round_start_seq) as usize);
+        if sent_time != Some(&server_time) {
+            return None;
+        }
+
+        let delay_ms = received_at - server_time;
+        let offset_ms = device_time as i64 - (server_time + delay_ms / 2) as i64;
+        let sample = ClockOffset {
+            offset_ms,
+            delay_ms,
+            measured_at: received_at,
+        };
+
+        if self.best.is_none_or(|best| sample.delay_ms < best.delay_ms) {
+            self.best = Some(sample);
+        }
+
+        self.best
+    }
+}
+
+pub fn server_time_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_lowest_delay_sample() {
+        let mut sync = ClockSync::new();
+        let now = Instant::now();
+
+        let Some(TimerPacketInner::TimeSyncRequest { seq, .. }) = sync.poll(now, 1000) else {
+            panic!("expected request");
+        };
+        assert!(sync.poll(now, 1000).is_none());
+
+        // device is 500ms ahead, 200ms round trip
+        let est = sync.on_response(seq, 1000, 1600, 1200).unwrap();
+        assert_eq!(est.offset_ms, 500);
+        assert_eq!(est.delay_ms, 200);
+
+        let Some(TimerPacketInner::TimeSyncRequest { seq, .. }) =
+            sync.poll(now + SAMPLE_SPACING, 2000)
+        else {
+            panic!("expected request");
+        };
+
+        // asymmetric slow sample is ignored
+        let est = sync.on_response(seq, 2000, 3000, 2900).unwrap();
+        assert_eq!(est.offset_ms, 500);
+
+        // unknown seq
+        assert!(sync.on_response(seq + 1, 2000, 2500, 2010).is_none());
+
+        // server time that wasn't sent with this seq is ignored
+        assert!(sync.on_response(seq, 2180, 2690, 2200).is_none());
+        assert!(!sync.round_finished(seq));
+        assert!(sync.round_finished(SAMPLES_PER_ROUND - 1));
+    }
+
+    #[test]
+    fn resyncs_after_round() {
+        let mut sync = ClockSync::new();
+        let mut now = Instant::now();
+
+        for _ in 0..SAMPLES_PER_ROUND {
+            assert!(sync.poll(now, 0).is_some());
+            now += SAMPLE_SPACING;
+        }
+
+        assert!(sync.poll(now, 0).is_none());
+        assert!(sync.poll(now + RESYNC_INTERVAL, 0).is_some());
+    }
+}
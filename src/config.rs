@@ -0,0 +1,500 @@
+use crate::{
+    battery::BatteryThresholds, heartbeat::HeartbeatSettings, retention::RetentionMode,
+    rollout::RolloutSettings,
+};
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Micro-connector configuration.
+///
+/// Loaded from TOML file (`CONFIG_PATH`, default: `config.toml`), every key
+/// can be overridden by its env variable (see [`Config::apply_env`]).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub port: u16,
+    pub tls: bool,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub mdns: bool,
+    pub bluetooth: bool,
+    pub dev: bool,
+
+    /// Accept packets of legacy devices (< `v2.4`), they aren't authenticated
+    pub legacy_devices: bool,
+
+    /// Update legacy devices (< `v2.4`) that aren't added to current firmware
+    pub legacy_bootstrap: bool,
+
+    pub firmware_dir: PathBuf,
+
+    /// Ed25519 public keys (base64) of firmware signers
+    pub firmware_public_keys: Vec<String>,
+    pub socket_path: PathBuf,
+    pub device_logs: PathBuf,
+    pub data_dir: PathBuf,
+    pub adapter_api: Option<String>,
+
+    /// Local rooms mapping (TOML: room name -> list of esp ids)
+    pub rooms_file: Option<PathBuf>,
+
+    /// Max concurrent websocket connections
+    pub max_connections: usize,
+
+    // Runtime reloadable settings (SIGHUP)
+    pub autosetup_settings: Option<String>,
+    pub heartbeat_interval_ms: u64,
+    pub heartbeat_max_missed: u32,
+    pub heartbeat_warn_rtt_ms: u64,
+
+    /// Repeated attendance scans of the same card within this window are not sent to backend
+    pub attendance_duplicate_window_secs: u64,
+
+    /// Low battery alert thresholds (level in %, predicted minutes to empty)
+    pub battery_low_percentage: f64,
+    pub battery_low_minutes: u64,
+
+    /// Display width (columns) of devices that report display without its width
+    pub display_columns: usize,
+
+    /// Per-locale transliteration overrides (`[display_overrides.de]` `"ä" = "ae"`)
+    pub display_overrides: HashMap<String, HashMap<String, String>>,
+
+    /// Max simultaneous firmware transfers (other devices wait in queue)
+    pub max_concurrent_updates: usize,
+
+    /// Devices updated first in staged rollout (rollout is disabled if empty)
+    pub rollout_canaries: Vec<u32>,
+
+    /// Cumulative percentages of devices updated in following rollout waves
+    pub rollout_waves: Vec<u8>,
+
+    /// How long updated devices must stay connected before next wave starts
+    pub rollout_soak_minutes: u64,
+
+    /// Newest images kept per (hw, firmware, channel), retention is disabled if not set
+    pub firmware_keep: Option<usize>,
+
+    /// What happens with older images (archive, delete or dry_run)
+    pub firmware_retention: RetentionMode,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 8080,
+            tls: true,
+            tls_cert: None,
+            tls_key: None,
+            mdns: true,
+            bluetooth: true,
+            dev: false,
+            legacy_devices: false,
+            legacy_bootstrap: false,
+
+            firmware_dir: PathBuf::from("/tmp/fkm-build"),
+            firmware_public_keys: Vec::new(),
+            socket_path: PathBuf::from("/tmp/socket.sock"),
+            device_logs: PathBuf::from("/tmp/fkm-logs"),
+            data_dir: PathBuf::from("/tmp/fkm-data"),
+            adapter_api: None,
+
+            rooms_file: None,
+            max_connections: 512,
+
+            autosetup_settings: None,
+            heartbeat_interval_ms: 5000,
+            heartbeat_max_missed: 1,
+            heartbeat_warn_rtt_ms: 500,
+            attendance_duplicate_window_secs: 60,
+            battery_low_percentage: 15.0,
+            battery_low_minutes: 30,
+            display_columns: 16,
+            display_overrides: HashMap::new(),
+            max_concurrent_updates: 4,
+            rollout_canaries: Vec::new(),
+            rollout_waves: vec![10, 50, 100],
+            rollout_soak_minutes: 10,
+            firmware_keep: None,
+            firmware_retention: RetentionMode::Archive,
+        }
+    }
+}
+
+impl Config {
+    /// Load config file and env overrides, then validate
+    pub fn load() -> Result<Self> {
+        let path = std::env::var("CONFIG_PATH").ok();
+        let mut config = match &path {
+            Some(path) => Self::from_file(path)?,
+            None if std::path::Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(DEFAULT_CONFIG_PATH)?
+            }
+            None => Self::default(),
+        };
+
+        config.apply_env(|key| std::env::var(key).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Cannot read config file \"{path}\": {e}"))?;
+
+        toml::from_str(&data).map_err(|e| anyhow!("Config file \"{path}\" parse error: {e}"))
+    }
+
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        fn parse<T: std::str::FromStr>(key: &str, value: String) -> Result<T>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value
+                .parse()
+                .map_err(|e| anyhow!("Invalid value for {key} (\"{value}\"): {e}"))
+        }
+
+        if let Some(v) = var("PORT") {
+            self.port = parse("PORT", v)?;
+        }
+        if var("NO_TLS").is_some() {
+            self.tls = false;
+        }
+        if let Some(v) = var("TLS_CERT") {
+            self.tls_cert = Some(PathBuf::from(v));
+        }
+        if let Some(v) = var("TLS_KEY") {
+            self.tls_key = Some(PathBuf::from(v));
+        }
+        if var("NO_MDNS").is_some() {
+            self.mdns = false;
+        }
+        if var("NO_BT").is_some() {
+            self.bluetooth = false;
+        }
+        if var("DEV").is_some() {
+            self.dev = true;
+        }
+        if var("LEGACY_DEVICES").is_some() {
+            self.legacy_devices = true;
+        }
+        if var("LEGACY_BOOTSTRAP").is_some() {
+            self.legacy_bootstrap = true;
+        }
+        if let Some(v) = var("FIRMWARE_DIR") {
+            self.firmware_dir = PathBuf::from(v);
+        }
+        if let Some(v) = var("FIRMWARE_PUBLIC_KEYS") {
+            self.firmware_public_keys = v
+                .split(',')
+                .map(|k| k.trim().to_string())
+                .filter(|k| !k.is_empty())
+                .collect();
+        }
+        if let Some(v) = var("SOCKET_PATH") {
+            self.socket_path = PathBuf::from(v);
+        }
+        if let Some(v) = var("DEVICE_LOGS") {
+            self.device_logs = PathBuf::from(v);
+        }
+        if let Some(v) = var("DATA_DIR") {
+            self.data_dir = PathBuf::from(v);
+        }
+        if let Some(v) = var("ADAPTER_API") {
+            self.adapter_api = Some(v);
+        }
+        if let Some(v) = var("ROOMS_FILE") {
+            self.rooms_file = Some(PathBuf::from(v));
+        }
+        if let Some(v) = var("MAX_CONNECTIONS") {
+            self.max_connections = parse("MAX_CONNECTIONS", v)?;
+        }
+        if let Some(v) = var("AUTOSETUP_SETTINGS") {
+            self.autosetup_settings = Some(v);
+        }
+        if let Some(v) = var("HEARTBEAT_INTERVAL_MS") {
+            self.heartbeat_interval_ms = parse("HEARTBEAT_INTERVAL_MS", v)?;
+        }
+        if let Some(v) = var("HEARTBEAT_MAX_MISSED") {
+            self.heartbeat_max_missed = parse("HEARTBEAT_MAX_MISSED", v)?;
+        }
+        if let Some(v) = var("HEARTBEAT_WARN_RTT_MS") {
+            self.heartbeat_warn_rtt_ms = parse("HEARTBEAT_WARN_RTT_MS", v)?;
+        }
+        if let Some(v) = var("ATTENDANCE_DUPLICATE_WINDOW_SECS") {
+            self.attendance_duplicate_window_secs = parse("ATTENDANCE_DUPLICATE_WINDOW_SECS", v)?;
+        }
+        if let Some(v) = var("BATTERY_LOW_PERCENTAGE") {
+            self.battery_low_percentage = parse("BATTERY_LOW_PERCENTAGE", v)?;
+        }
+        if let Some(v) = var("BATTERY_LOW_MINUTES") {
+            self.battery_low_minutes = parse("BATTERY_LOW_MINUTES", v)?;
+        }
+        if let Some(v) = var("DISPLAY_COLUMNS") {
+            self.display_columns = parse("DISPLAY_COLUMNS", v)?;
+        }
+        if let Some(v) = var("MAX_CONCURRENT_UPDATES") {
+            self.max_concurrent_updates = parse("MAX_CONCURRENT_UPDATES", v)?;
+        }
+        if let Some(v) = var("ROLLOUT_CANARIES") {
+            self.rollout_canaries = v
+                .split(',')
+                .filter(|id| !id.trim().is_empty())
+                .map(|id| {
+                    let id = id.trim();
+                    match id.strip_prefix("0x") {
+                        Some(hex) => u32::from_str_radix(hex, 16).map_err(|e| {
+                            anyhow!("Invalid value for ROLLOUT_CANARIES (\"{id}\"): {e}")
+                        }),
+                        None => parse("ROLLOUT_CANARIES", id.to_string()),
+                    }
+                })
+                .collect::<Result<_>>()?;
+        }
+        if let Some(v) = var("ROLLOUT_WAVES") {
+            self.rollout_waves = v
+                .split(',')
+                .map(|p| parse("ROLLOUT_WAVES", p.trim().to_string()))
+                .collect::<Result<_>>()?;
+        }
+        if let Some(v) = var("ROLLOUT_SOAK_MINUTES") {
+            self.rollout_soak_minutes = parse("ROLLOUT_SOAK_MINUTES", v)?;
+        }
+        if let Some(v) = var("FIRMWARE_KEEP") {
+            self.firmware_keep = Some(parse("FIRMWARE_KEEP", v)?);
+        }
+        if let Some(v) = var("FIRMWARE_RETENTION") {
+            self.firmware_retention = parse("FIRMWARE_RETENTION", v)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = Vec::new();
+
+        if self.port == 0 {
+            errors.push("port must be greater than 0".to_string());
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            errors.push("tls_cert and tls_key must be set together".to_string());
+        }
+        for key in &self.firmware_public_keys {
+            if let Err(e) = crate::signature::parse_public_key(key) {
+                errors.push(format!("firmware_public_keys: \"{key}\" is invalid: {e}"));
+            }
+        }
+        if self.max_connections == 0 {
+            errors.push("max_connections must be greater than 0".to_string());
+        }
+        if self.max_concurrent_updates == 0 {
+            errors.push("max_concurrent_updates must be greater than 0".to_string());
+        }
+        if self.firmware_keep == Some(0) {
+            errors.push("firmware_keep must be greater than 0".to_string());
+        }
+        if self.heartbeat_interval_ms == 0 {
+            errors.push("heartbeat_interval_ms must be greater than 0".to_string());
+        }
+        if self.heartbeat_max_missed == 0 {
+            errors.push("heartbeat_max_missed must be greater than 0".to_string());
+        }
+        if !(0.0..=100.0).contains(&self.battery_low_percentage) {
+            errors.push("battery_low_percentage must be between 0 and 100".to_string());
+        }
+        if self.display_columns == 0 {
+            errors.push("display_columns must be greater than 0".to_string());
+        }
+        if self.rollout_waves.iter().any(|p| *p > 100)
+            || !self.rollout_waves.is_sorted()
+            || (!self.rollout_canaries.is_empty() && self.rollout_waves.last() != Some(&100))
+        {
+            errors.push(
+                "rollout_waves must be ascending percentages (<= 100) ending with 100".to_string(),
+            );
+        }
+        for (locale, table) in &self.display_overrides {
+            for c in table.keys().filter(|c| c.chars().count() != 1) {
+                errors.push(format!(
+                    "display_overrides.{locale}: \"{c}\" must be single character"
+                ));
+            }
+        }
+        if let Some(settings) = &self.autosetup_settings
+            && let Err(e) = serde_json::from_str::<serde_json::Value>(settings)
+        {
+            errors.push(format!("autosetup_settings is not valid JSON: {e}"));
+        }
+
+        for (name, dir) in [
+            ("firmware_dir", &self.firmware_dir),
+            ("device_logs", &self.device_logs),
+            ("data_dir", &self.data_dir),
+        ] {
+            if dir.exists() && !dir.is_dir() {
+                errors.push(format!("{name} ({}) is not a directory", dir.display()));
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+
+        Err(anyhow!(
+            "Invalid configuration:\n - {}",
+            errors.join("\n - ")
+        ))
+    }
+
+    /// Apply runtime reloadable settings from new config.
+    /// Returns names of changed settings that require restart.
+    pub fn reload(&mut self, new: Config) -> Vec<&'static str> {
+        let mut restart_required = Vec::new();
+        macro_rules! restart_only {
+            ($($field:ident),+) => {
+                $(if self.$field != new.$field {
+                    restart_required.push(stringify!($field));
+                })+
+            };
+        }
+
+        restart_only!(
+            port,
+            tls,
+            tls_cert,
+            tls_key,
+            mdns,
+            bluetooth,
+            dev,
+            firmware_dir,
+            socket_path,
+            device_logs,
+            data_dir,
+            adapter_api,
+            max_connections
+        );
+
+        self.legacy_devices = new.legacy_devices;
+        self.legacy_bootstrap = new.legacy_bootstrap;
+        self.rooms_file = new.rooms_file;
+        self.firmware_public_keys = new.firmware_public_keys;
+        self.autosetup_settings = new.autosetup_settings;
+        self.heartbeat_interval_ms = new.heartbeat_interval_ms;
+        self.heartbeat_max_missed = new.heartbeat_max_missed;
+        self.heartbeat_warn_rtt_ms = new.heartbeat_warn_rtt_ms;
+        self.attendance_duplicate_window_secs = new.attendance_duplicate_window_secs;
+        self.battery_low_percentage = new.battery_low_percentage;
+        self.battery_low_minutes = new.battery_low_minutes;
+        self.display_columns = new.display_columns;
+        self.display_overrides = new.display_overrides;
+        self.max_concurrent_updates = new.max_concurrent_updates;
+        self.rollout_canaries = new.rollout_canaries;
+        self.rollout_waves = new.rollout_waves;
+        self.rollout_soak_minutes = new.rollout_soak_minutes;
+        self.firmware_keep = new.firmware_keep;
+        self.firmware_retention = new.firmware_retention;
+        restart_required
+    }
+
+    pub fn heartbeat(&self) -> HeartbeatSettings {
+        HeartbeatSettings {
+            interval: Duration::from_millis(self.heartbeat_interval_ms),
+            max_missed: self.heartbeat_max_missed,
+            warn_rtt: Duration::from_millis(self.heartbeat_warn_rtt_ms),
+        }
+    }
+
+    pub fn battery_thresholds(&self) -> BatteryThresholds {
+        BatteryThresholds {
+            low_level: self.battery_low_percentage,
+            low_minutes: self.battery_low_minutes,
+        }
+    }
+
+    pub fn rollout(&self) -> RolloutSettings {
+        RolloutSettings {
+            canaries: self.rollout_canaries.clone(),
+            waves: self.rollout_waves.clone(),
+            soak: Duration::from_secs(self.rollout_soak_minutes * 60),
+        }
+    }
+
+    pub fn tls_paths(&self) -> (PathBuf, PathBuf) {
+        let tls_dir = self.data_dir.join("tls");
+        (
+            self.tls_cert
+                .clone()
+                .unwrap_or_else(|| tls_dir.join("cert.pem")),
+            self.tls_key
+                .clone()
+                .unwrap_or_else(|| tls_dir.join("key.pem")),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_and_env_overrides() {
+        let mut config: Config = toml::from_str(
+            r#"
+            port = 9000
+            mdns = false
+            heartbeat_max_missed = 3
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.port, 9000);
+        assert!(!config.mdns);
+        assert!(config.tls);
+
+        config
+            .apply_env(|key| match key {
+                "PORT" => Some("9001".to_string()),
+                "NO_TLS" => Some(String::new()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(config.port, 9001);
+        assert!(!config.tls);
+        assert_eq!(config.heartbeat_max_missed, 3);
+
+        let err = config
+            .apply_env(|key| (key == "PORT").then(|| "abc".to_string()))
+            .unwrap_err();
+        assert!(err.to_string().contains("PORT"));
+
+        assert!(toml::from_str::<Config>("prot = 1").is_err());
+    }
+
+    #[test]
+    fn validation_and_reload() {
+        let mut config = Config {
+            port: 0,
+            heartbeat_max_missed: 0,
+            ..Default::default()
+        };
+
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("port") && err.contains("heartbeat_max_missed"));
+
+        config.port = 8080;
+        config.heartbeat_max_missed = 1;
+        assert!(config.validate().is_ok());
+
+        let restart = config.reload(Config {
+            port: 8081,
+            heartbeat_interval_ms: 10000,
+            ..Default::default()
+        });
+        assert_eq!(restart, vec!["port"]);
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.heartbeat_interval_ms, 10000);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::http::EspConnectInfo;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+
+pub const CONNECTED_DEVICES_FILE: &str = "connected_devices.json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectedDevice {
+    pub esp_id: u32,
+    pub hw: String,
+    pub firmware: String,
+    pub version: String,
+}
+
+/// Currently connected added devices, mirrored to `connected_devices.json` (read by `fw devices`).
+/// File is written on [`ConnectedDevices::flush`], not on every connect.
+#[derive(Debug)]
+pub struct ConnectedDevices {
+    path: PathBuf,
+
+    /// esp_id -> (connection id, device), device can reconnect before old connection is closed
+    devices: HashMap<u32, (u64, ConnectedDevice)>,
+    next_connection: u64,
+    dirty: bool,
+}
+
+impl ConnectedDevices {
+    /// Starts empty (file left by previous run is overwritten)
+    pub async fn new(path: PathBuf) -> Self {
+        let devices = Self {
+            path,
+            devices: HashMap::new(),
+            next_connection: 0,
+            dirty: false,
+        };
+
+        if let Err(e) = devices.save().await {
+            tracing::error!("Connected devices save error: {e:?}");
+        }
+
+        devices
+    }
+
+    /// Returns connection id (passed to `disconnect`)
+    pub fn connect(&mut self, esp_connect_info: &EspConnectInfo) -> u64 {
+        let connection = self.next_connection;
+        self.next_connection += 1;
+        self.devices.insert(
+            esp_connect_info.id,
+            (
+                connection,
+                ConnectedDevice {
+                    esp_id: esp_connect_info.id,
+                    hw: esp_connect_info.hw.clone(),
+                    firmware: esp_connect_info.firmware.clone(),
+                    version: esp_connect_info.version.clone(),
+                },
+            ),
+        );
+
+        self.dirty = true;
+        connection
+    }
+
+    pub fn disconnect(&mut self, esp_id: u32, connection: u64) {
+        if self
+            .devices
+            .get(&esp_id)
+            .is_none_or(|(current, _)| *current != connection)
+        {
+            return;
+        }
+
+        self.devices.remove(&esp_id);
+        self.dirty = true;
+    }
+
+    /// Save devices if any connected or disconnected since last save
+    pub async fn flush(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        self.save().await?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    async fn save(&self) -> Result<()> {
+        let mut devices: Vec<&ConnectedDevice> = self.devices.values().map(|(_, d)| d).collect();
+        devices.sort_by_key(|d| d.esp_id);
+
+        crate::fs_util::write_atomic(&self.path, &serde_json::to_vec_pretty(&devices)?, false).await
+    }
+
+    /// Read devices written by running server
+    pub async fn read(path: &std::path::Path) -> Result<Vec<ConnectedDevice>> {
+        Ok(serde_json::from_slice(&tokio::fs::read(path).await?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, esp_info};
+
+    #[tokio::test]
+    async fn debounced_save() {
+        let dir = TempDir::new("connected");
+        let path = dir.join(CONNECTED_DEVICES_FILE);
+        let info = esp_info(0x1234, "v3.0.0");
+
+        let mut devices = ConnectedDevices::new(path.clone()).await;
+        let old = devices.connect(&info);
+        assert!(ConnectedDevices::read(&path).await.unwrap().is_empty());
+
+        // reconnect before old connection is closed
+        let new = devices.connect(&info);
+        devices.disconnect(info.id, old);
+        devices.flush().await.unwrap();
+        let saved = ConnectedDevices::read(&path).await.unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].version, "v3.0.0");
+
+        devices.disconnect(info.id, new);
+        devices.flush().await.unwrap();
+        assert!(ConnectedDevices::read(&path).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,210 @@
+use crate::structs::TimerPacketInner;
+use std::collections::{HashMap, HashSet};
+
+/// Firmware capability: device scrolls lines longer than display width
+pub const SCROLL_CAPABILITY: &str = "scroll";
+
+/// Firmware capability: text must fit display (`display` or `display=<columns>`)
+pub const DISPLAY_CAPABILITY: &str = "display";
+
+/// Built-in transliterations that differ from plain unidecode
+const LOCALE_OVERRIDES: &[(&str, &[(char, &str)])] = &[
+    (
+        "de",
+        &[
+            ('ä', "ae"),
+            ('ö', "oe"),
+            ('ü', "ue"),
+            ('Ä', "Ae"),
+            ('Ö', "Oe"),
+            ('Ü', "Ue"),
+        ],
+    ),
+    ("da", &[('ø', "oe"), ('Ø', "Oe"), ('å', "aa"), ('Å', "Aa")]),
+    ("nb", &[('ø', "oe"), ('Ø', "Oe"), ('å', "aa"), ('Å', "Aa")]),
+];
+
+/// Per-locale transliteration overrides (locale -> char -> replacement)
+pub type LocaleOverrides = HashMap<String, HashMap<char, String>>;
+
+/// Built-in overrides extended (or replaced) by overrides from config
+pub fn locale_overrides(config: &HashMap<String, HashMap<String, String>>) -> LocaleOverrides {
+    let mut overrides: LocaleOverrides = LOCALE_OVERRIDES
+        .iter()
+        .map(|(locale, table)| {
+            let table = table.iter().map(|(c, s)| (*c, s.to_string())).collect();
+            (locale.to_string(), table)
+        })
+        .collect();
+
+    for (locale, table) in config {
+        let entry = overrides.entry(locale.clone()).or_default();
+        for (c, replacement) in table {
+            if let Some(c) = c.chars().next() {
+                entry.insert(c, replacement.clone());
+            }
+        }
+    }
+
+    overrides
+}
+
+/// Text rendering for single device display
+#[derive(Debug, Clone)]
+pub struct Display {
+    pub columns: usize,
+    pub scroll: bool,
+
+    /// Device reported its display (capability or display info), only then lines are truncated
+    truncate: bool,
+
+    /// Locale of texts that aren't translations (reported by device)
+    pub locale: Option<String>,
+
+    /// Competition default locale (used if device didn't report its locale)
+    pub default_locale: String,
+
+    /// Non-ASCII glyphs supported by device (sent as is)
+    glyphs: HashSet<char>,
+    overrides: LocaleOverrides,
+}
+
+impl Display {
+    pub fn new(columns: usize, scroll: bool, overrides: LocaleOverrides) -> Self {
+        Self {
+            columns,
+            scroll,
+            truncate: false,
+            locale: None,
+            default_locale: String::new(),
+            glyphs: HashSet::new(),
+            overrides,
+        }
+    }
+
+    /// Truncate lines to display width (own width of device or default one)
+    pub fn set_width(&mut self, columns: Option<usize>) {
+        if let Some(columns) = columns.filter(|c| *c > 0) {
+            self.columns = columns;
+        }
+
+        self.truncate = true;
+    }
+
+    /// Apply display info reported by device
+    pub fn set_info(&mut self, columns: Option<usize>, glyphs: &str, locale: Option<String>) {
+        self.set_width(columns);
+        self.glyphs = glyphs.chars().filter(|c| !c.is_ascii()).collect();
+        if locale.is_some() {
+            self.locale = locale;
+        }
+    }
+
+    pub fn transliterate(&self, text: &str, locale: &str) -> String {
+        let overrides = self.overrides.get(locale);
+        let mut out = String::with_capacity(text.len());
+
+        for c in text.chars() {
+            if c.is_ascii() || self.glyphs.contains(&c) {
+                out.push(c);
+            } else if let Some(replacement) = overrides.and_then(|o| o.get(&c)) {
+                out.push_str(replacement);
+            } else {
+                out.push_str(unidecode::unidecode_char(c));
+            }
+        }
+
+        out
+    }
+
+    /// Transliterate and fit text to display width
+    /// (if device reported its display and doesn't scroll long lines)
+    pub fn line(&self, text: &str) -> String {
+        let locale = self.locale.as_deref().unwrap_or(&self.default_locale);
+        let text = self.transliterate(text, locale);
+        if self.scroll || !self.truncate || text.chars().count() <= self.columns {
+            return text;
+        }
+
+        text.chars().take(self.columns).collect()
+    }
+
+    /// Render every display string in packet
+    pub fn render_packet(&self, packet: &mut TimerPacketInner) {
+        match packet {
+            TimerPacketInner::SolveConfirm { message, .. } => *message = self.line(message),
+            TimerPacketInner::ApiError { error, .. } => *error = self.line(error),
+            TimerPacketInner::CustomMessage { line1, line2 } => {
+                *line1 = self.line(line1);
+                *line2 = self.line(line2);
+            }
+            TimerPacketInner::CardInfoResponse {
+                display,
+                possible_groups,
+                ..
+            } => {
+                *display = self.line(display);
+                for group in possible_groups {
+                    group.name = self.line(&group.name);
+                    if let Some(secondary_text) = &mut group.secondary_text {
+                        *secondary_text = self.line(secondary_text);
+                    }
+                }
+            }
+            TimerPacketInner::AttendanceResult { name, reason, .. } => {
+                for text in [name, reason].into_iter().flatten() {
+                    *text = self.line(text);
+                }
+            }
+            TimerPacketInner::DeviceSettings { locales, .. } => {
+                // translations are formatted by firmware, so they aren't truncated
+                for locale in locales {
+                    for record in &mut locale.translations {
+                        record.translation =
+                            self.transliterate(&record.translation, &locale.locale);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_text() {
+        let mut config = HashMap::new();
+        config.insert(
+            "pl".to_string(),
+            HashMap::from([("ł".to_string(), "l".to_string())]),
+        );
+
+        let mut display = Display::new(16, false, locale_overrides(&config));
+        assert_eq!(display.transliterate("Müller", "en"), "Muller");
+        assert_eq!(display.transliterate("Müller", "de"), "Mueller");
+        assert_eq!(display.transliterate("Łukasz Żółw", "pl"), "Lukasz Zolw");
+
+        // text isn't cut for devices that didn't report display
+        assert_eq!(
+            display.line("Jan Kowalski (123456)"),
+            "Jan Kowalski (123456)"
+        );
+        display.set_width(None);
+        assert_eq!(display.line("Jan Kowalski (123456)"), "Jan Kowalski (12");
+
+        display.set_info(Some(20), "ąęł", Some("pl".to_string()));
+        assert_eq!(
+            display.line("Michał Gąsienica (1234)"),
+            "Michał Gąsienica (12"
+        );
+
+        display.scroll = true;
+        assert_eq!(
+            display.line("Michał Gąsienica (1234)"),
+            "Michał Gąsienica (1234)"
+        );
+    }
+}
@@ -0,0 +1,342 @@
+use crate::{
+    signature,
+    structs::SharedAppState,
+    updater::{Firmware, FirmwareImage, FirmwareMetadata, Version},
+};
+use anyhow::{Result, anyhow};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Release channel (dev mode serves only dev builds, otherwise only stable ones)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    Stable,
+    Dev,
+}
+
+impl Channel {
+    pub fn of(version: &Version) -> Option<Self> {
+        match version {
+            Version::Stable(_) => Some(Self::Stable),
+            Version::Dev(_) => Some(Self::Dev),
+            Version::Other => None,
+        }
+    }
+
+    pub fn for_mode(dev_mode: bool) -> Self {
+        if dev_mode { Self::Dev } else { Self::Stable }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CatalogueKey {
+    pub hw: String,
+    pub firmware: String,
+    pub channel: Channel,
+}
+
+#[derive(Debug, Clone)]
+pub struct CatalogueEntry {
+    pub path: PathBuf,
+    pub hw: String,
+    pub firmware: String,
+    pub version: Version,
+    pub build_time: u64,
+}
+
+impl CatalogueEntry {
+    pub fn key(&self) -> Option<CatalogueKey> {
+        Some(CatalogueKey {
+            hw: self.hw.clone(),
+            firmware: self.firmware.clone(),
+            channel: Channel::of(&self.version)?,
+        })
+    }
+
+    /// Firmware to send (image is loaded when update starts)
+    pub fn firmware(&self) -> Firmware {
+        Firmware {
+            image: FirmwareImage::File(self.path.clone()),
+            version: self.version.clone(),
+            build_time: self.build_time,
+            firmware: self.firmware.clone(),
+        }
+    }
+}
+
+/// Used to detect changed files (and detached signatures) without reading them
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FileStamp {
+    len: u64,
+    modified: SystemTime,
+    signature_modified: Option<SystemTime>,
+}
+
+/// Index of firmware images in `firmware_dir`, built from image trailers
+#[derive(Debug, Default)]
+pub struct FirmwareCatalogue {
+    /// Every scanned file (None if it's not valid firmware image)
+    files: HashMap<PathBuf, (FileStamp, Option<CatalogueEntry>)>,
+
+    /// Valid images, newest first
+    index: HashMap<CatalogueKey, Vec<CatalogueEntry>>,
+}
+
+impl FirmwareCatalogue {
+    pub fn latest(&self, hw: &str, firmware: &str, channel: Channel) -> Option<&CatalogueEntry> {
+        let key = CatalogueKey {
+            hw: hw.to_string(),
+            firmware: firmware.to_string(),
+            channel,
+        };
+
+        self.index.get(&key)?.first()
+    }
+
+    /// Images per (hw, firmware, channel), newest first
+    pub fn groups(&self) -> impl Iterator<Item = &[CatalogueEntry]> {
+        self.index.values().map(|entries| entries.as_slice())
+    }
+
+    /// Image with exact version (from any channel)
+    pub fn find(&self, hw: &str, firmware: &str, version: &Version) -> Option<&CatalogueEntry> {
+        [Channel::Stable, Channel::Dev]
+            .into_iter()
+            .filter_map(|channel| {
+                self.index.get(&CatalogueKey {
+                    hw: hw.to_string(),
+                    firmware: firmware.to_string(),
+                    channel,
+                })
+            })
+            .flatten()
+            .find(|entry| entry.version == *version)
+    }
+
+    /// Forget indexed files (everything is indexed again on next refresh)
+    pub fn clear(&mut self) {
+        self.files.clear();
+        self.index.clear();
+    }
+
+    /// Index new and changed files, drop removed ones. Returns true if anything changed.
+    pub async fn refresh(&mut self, dir: &Path, allow_unsigned: bool) -> Result<bool> {
+        let files = scan_dir(dir).await?;
+        let indexed = index_files(self.changed(&files), allow_unsigned).await;
+        Ok(self.apply(&files, indexed))
+    }
+
+    /// Stop offering image that failed verification (until the file or its signature changes)
+    pub fn reject(&mut self, path: &Path) {
+        if let Some((_, entry @ Some(_))) = self.files.get_mut(path) {
+            *entry = None;
+            self.rebuild_index();
+        }
+    }
+
+    /// Files that are new or changed since they were indexed
+    fn changed(&self, files: &[(PathBuf, FileStamp)]) -> Vec<(PathBuf, FileStamp)> {
+        files
+            .iter()
+            .filter(|(path, stamp)| self.files.get(path).is_none_or(|(s, _)| s != stamp))
+            .cloned()
+            .collect()
+    }
+
+    /// Swap in indexed files and drop files that are gone. Returns true if anything changed.
+    fn apply(&mut self, files: &[(PathBuf, FileStamp)], indexed: Vec<IndexedFile>) -> bool {
+        let mut changed = !indexed.is_empty();
+        for (path, stamp, entry) in indexed {
+            self.files.insert(path, (stamp, entry));
+        }
+
+        let seen: HashSet<&PathBuf> = files.iter().map(|(path, _)| path).collect();
+        let count = self.files.len();
+        self.files.retain(|path, _| seen.contains(path));
+        changed |= count != self.files.len();
+
+        if changed {
+            self.rebuild_index();
+        }
+
+        changed
+    }
+
+    fn rebuild_index(&mut self) {
+        self.index.clear();
+        for entry in self.files.values().filter_map(|(_, entry)| entry.as_ref()) {
+            if let Some(key) = entry.key() {
+                self.index.entry(key).or_default().push(entry.clone());
+            }
+        }
+
+        for entries in self.index.values_mut() {
+            entries.sort_by(|a, b| {
+                if a.version.is_newer(&b.version) {
+                    std::cmp::Ordering::Greater
+                } else if b.version.is_newer(&a.version) {
+                    std::cmp::Ordering::Less
+                } else {
+                    std::cmp::Ordering::Equal
+                }
+            });
+        }
+    }
+}
+
+/// Indexed file (entry is None if it's not valid firmware image)
+type IndexedFile = (PathBuf, FileStamp, Option<CatalogueEntry>);
+
+/// Firmware images in dir (signatures are stamped with their image).
+/// Hidden files (temporary files of atomic writes, retention state) are skipped.
+async fn scan_dir(dir: &Path) -> Result<Vec<(PathBuf, FileStamp)>> {
+    let mut files = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file()
+            || entry.file_name().as_encoded_bytes().starts_with(b".")
+            || path.extension() == Some(OsStr::new(signature::SIGNATURE_EXTENSION))
+        {
+            continue;
+        }
+
+        let signature_modified = tokio::fs::metadata(signature::detached_path(&path))
+            .await
+            .and_then(|m| m.modified())
+            .ok();
+        let stamp = FileStamp {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            signature_modified,
+        };
+
+        files.push((path, stamp));
+    }
+
+    Ok(files)
+}
+
+async fn index_files(files: Vec<(PathBuf, FileStamp)>, allow_unsigned: bool) -> Vec<IndexedFile> {
+    let mut indexed = Vec::with_capacity(files.len());
+    for (path, stamp) in files {
+        let entry = match index_file(&path, allow_unsigned).await {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                tracing::error!("Firmware {path:?} not indexed: {e}");
+                None
+            }
+        };
+
+        indexed.push((path, stamp, entry));
+    }
+
+    indexed
+}
+
+/// Only the trailer is read, image bytes are loaded (and verified) when update starts
+async fn index_file(path: &Path, allow_unsigned: bool) -> Result<CatalogueEntry> {
+    let metadata = FirmwareMetadata::read_trailer(path).await?;
+    let (hw, firmware, version) = metadata
+        .strings()
+        .ok_or_else(|| anyhow!("Wrong firmware metadata utf8"))?;
+
+    let version = Version::from_str(version);
+    if Channel::of(&version).is_none() {
+        return Err(anyhow!("Unsupported version: {version}"));
+    }
+
+    if !allow_unsigned && !signature::is_signed(path).await? {
+        return Err(anyhow!("Firmware image is not signed"));
+    }
+
+    Ok(CatalogueEntry {
+        path: path.to_path_buf(),
+        hw: hw.to_string(),
+        firmware: firmware.to_string(),
+        version,
+        build_time: metadata.build_time,
+    })
+}
+
+/// Refresh catalogue from `firmware_dir` set in config
+pub async fn refresh(state: &SharedAppState) -> Result<bool> {
+    let firmware_dir = state.config.read().await.firmware_dir.clone();
+
+    // trailers are read without holding the catalogue lock
+    let files = scan_dir(&firmware_dir).await?;
+    let changed = state.firmware_catalogue.read().await.changed(&files);
+    let indexed = index_files(changed, state.dev_mode).await;
+
+    Ok(state
+        .firmware_catalogue
+        .write()
+        .await
+        .apply(&files, indexed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, image};
+
+    #[tokio::test]
+    async fn index_firmware_dir() {
+        let tmp = TempDir::new("catalogue");
+        let dir = tmp.path();
+        std::fs::write(dir.join("a.bin"), image("v3", "STATION", "v3.0.0")).unwrap();
+        std::fs::write(dir.join("b.bin"), image("v3", "STATION", "v3.1.0")).unwrap();
+        std::fs::write(dir.join("c.bin"), image("v3", "STATION", "D1714320292")).unwrap();
+        std::fs::write(dir.join("d.bin"), image("v2", "STATION", "v2.9.0")).unwrap();
+        std::fs::write(dir.join(".e.bin.tmp"), [0; 16]).unwrap();
+        std::fs::write(dir.join(".retention_deleted.json"), "[]").unwrap();
+
+        let mut catalogue = FirmwareCatalogue::default();
+        assert!(catalogue.refresh(dir, true).await.unwrap());
+        assert!(!catalogue.refresh(dir, true).await.unwrap());
+        assert_eq!(catalogue.files.len(), 4);
+
+        let latest = catalogue.latest("v3", "STATION", Channel::Stable).unwrap();
+        assert_eq!(latest.version, Version::from_str("v3.1.0"));
+        assert_eq!(latest.path, dir.join("b.bin"));
+        let latest = catalogue.latest("v3", "STATION", Channel::Dev).unwrap();
+        assert_eq!(latest.version, Version::from_str("D1714320292"));
+        assert!(catalogue.latest("v2", "STATION", Channel::Dev).is_none());
+        let pinned = catalogue.find("v3", "STATION", &Version::from_str("v3.0.0"));
+        assert_eq!(pinned.unwrap().path, dir.join("a.bin"));
+
+        // image replaced after indexing isn't sent with stale version
+        let firmware = catalogue
+            .latest("v3", "STATION", Channel::Stable)
+            .unwrap()
+            .firmware();
+        assert!(firmware.load().await.is_ok());
+        std::fs::write(dir.join("b.bin"), image("v3", "STATION", "v3.2.0")).unwrap();
+        assert!(firmware.load().await.is_err());
+
+        std::fs::remove_file(dir.join("b.bin")).unwrap();
+        assert!(catalogue.refresh(dir, true).await.unwrap());
+        let latest = catalogue.latest("v3", "STATION", Channel::Stable).unwrap();
+        assert_eq!(latest.version, Version::from_str("v3.0.0"));
+
+        // image that failed verification isn't offered until it changes
+        catalogue.reject(&dir.join("a.bin"));
+        assert!(catalogue.latest("v3", "STATION", Channel::Stable).is_none());
+        assert!(!catalogue.refresh(dir, true).await.unwrap());
+        std::fs::write(dir.join("a.bin.sig"), [0; 64]).unwrap();
+        assert!(catalogue.refresh(dir, true).await.unwrap());
+        assert!(catalogue.latest("v3", "STATION", Channel::Stable).is_some());
+
+        // unsigned images aren't indexed outside dev mode (signature is verified on update)
+        catalogue.clear();
+        catalogue.refresh(dir, false).await.unwrap();
+        let latest = catalogue.latest("v3", "STATION", Channel::Stable).unwrap();
+        assert_eq!(latest.path, dir.join("a.bin"));
+        assert!(catalogue.latest("v3", "STATION", Channel::Dev).is_none());
+    }
+}
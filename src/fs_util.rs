@@ -0,0 +1,34 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// Hidden temporary file next to `path` (`dir/.name.tmp`, ignored by directory watchers)
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Write file through temporary file and rename, so readers never see partial content.
+/// Private files (keys) are created with 0600 permissions from the start.
+pub async fn write_atomic(path: &Path, data: &[u8], private: bool) -> Result<()> {
+    let tmp_path = tmp_path(path);
+
+    // leftover tmp file could have wrong permissions (mode is applied only on create)
+    _ = tokio::fs::remove_file(&tmp_path).await;
+
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    if private {
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(&tmp_path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
@@ -0,0 +1,416 @@
+use crate::{
+    config::Config,
+    connected_devices::{CONNECTED_DEVICES_FILE, ConnectedDevices},
+    firmware_catalogue::{Channel, FirmwareCatalogue},
+    pins::FirmwarePins,
+    retention::{self, RetentionMode},
+    signature,
+    updater::{FirmwareMetadata, METADATA_SIZE, Version},
+};
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+use tokio::io::AsyncWriteExt;
+
+const USAGE: &str = "Usage: backend fw <command>
+  inspect <image>                     print trailer, CRC32 and signature state
+  validate <image>                    exit with error if image can't be served
+  trailer <image> --hw <hw> --firmware <firmware> --version <version>
+          [--build-time <unix secs>] [--rewrite]
+                                      append (or replace) metadata trailer
+  keygen <private key>                generate ed25519 signing key
+  sign <image> --key <private key> [--embed]
+                                      write detached (<image>.sig) or embedded signature
+  devices <image>                     list connected devices image applies to
+  gc [--keep <n>] [--delete] [--dry-run]
+                                      archive (or delete) images over retention";
+
+/// Boolean flags (other options take value)
+const FLAGS: [&str; 4] = ["rewrite", "embed", "delete", "dry-run"];
+
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+    flags: HashSet<String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut parsed = Args {
+            positional: Vec::new(),
+            options: HashMap::new(),
+            flags: HashSet::new(),
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                parsed.positional.push(arg.clone());
+                continue;
+            };
+
+            if FLAGS.contains(&name) {
+                parsed.flags.insert(name.to_string());
+                continue;
+            }
+
+            let value = args
+                .next()
+                .ok_or_else(|| anyhow!("Missing value for --{name}"))?;
+            parsed.options.insert(name.to_string(), value.clone());
+        }
+
+        Ok(parsed)
+    }
+
+    fn image(&self) -> Result<PathBuf> {
+        match self.positional.as_slice() {
+            [path] => Ok(PathBuf::from(path)),
+            _ => Err(anyhow!("{USAGE}")),
+        }
+    }
+
+    fn option(&self, name: &str) -> Result<&str> {
+        self.options
+            .get(name)
+            .map(|v| v.as_str())
+            .ok_or_else(|| anyhow!("Missing --{name}"))
+    }
+}
+
+/// `backend fw ...` entry point
+pub async fn run(args: &[String]) -> Result<()> {
+    let Some((command, args)) = args.split_first() else {
+        return Err(anyhow!("{USAGE}"));
+    };
+
+    let args = Args::parse(args)?;
+    match command.as_str() {
+        "inspect" => inspect(&args.image()?).await,
+        "validate" => validate(&args.image()?).await,
+        "trailer" => write_trailer_file(&args).await,
+        "keygen" => keygen(&args.image()?).await,
+        "sign" => sign(&args).await,
+        "devices" => devices(&args.image()?).await,
+        "gc" => gc(&args).await,
+        _ => Err(anyhow!("{USAGE}")),
+    }
+}
+
+/// Trailer with supported version (plain image can end with bytes that look like valid utf8)
+fn read_trailer(image: &[u8]) -> Option<(FirmwareMetadata, String, String, Version)> {
+    let metadata = FirmwareMetadata::from_trailer(image)?;
+    let (hw, firmware, version) = metadata.strings()?;
+    let (hw, firmware, version) = (hw.to_string(), firmware.to_string(), version.to_string());
+    let version = Version::from_str(&version);
+    if hw.is_empty() || firmware.is_empty() || Channel::of(&version).is_none() {
+        return None;
+    }
+
+    Some((metadata, hw, firmware, version))
+}
+
+/// Problems that would prevent server from serving the image
+async fn check(path: &Path, config: Option<&Config>) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let (image, image_signature) = signature::load_image(path).await?;
+    if read_trailer(&image).is_none() {
+        problems.push("Missing or invalid metadata trailer".to_string());
+    }
+
+    match config {
+        Some(config) => {
+            if let Err(e) = signature::verify(
+                &image,
+                image_signature.as_deref(),
+                &config.firmware_public_keys,
+                config.dev,
+            ) {
+                problems.push(e.to_string());
+            }
+        }
+        None => problems.push("Config not loaded, signature not verified".to_string()),
+    }
+
+    Ok(problems)
+}
+
+async fn inspect(path: &Path) -> Result<()> {
+    let data = tokio::fs::read(path).await?;
+    let (image, embedded) = signature::split_embedded(&data);
+
+    println!("File:       {}", path.display());
+    println!("Size:       {} bytes", image.len());
+    println!("CRC32:      {:08X}", crc32fast::hash(image));
+    match read_trailer(image) {
+        Some((metadata, hw, firmware, version)) => {
+            let build_time = chrono::DateTime::from_timestamp(metadata.build_time as i64, 0)
+                .map(|t| t.to_rfc3339())
+                .unwrap_or_default();
+
+            println!("Hardware:   {hw}");
+            println!("Firmware:   {firmware}");
+            let channel = Channel::of(&version).expect("Checked in read_trailer");
+            println!("Version:    {} ({channel:?})", version.inner_version());
+            println!("Build time: {} ({build_time})", metadata.build_time);
+        }
+        None => println!("Trailer:    none"),
+    }
+
+    let detached = signature::detached_path(path);
+    if embedded.is_some() {
+        println!("Signature:  embedded");
+    } else if tokio::fs::try_exists(&detached).await? {
+        println!("Signature:  detached ({})", detached.display());
+    } else {
+        println!("Signature:  none");
+    }
+
+    let config = Config::load().ok();
+    let problems = check(path, config.as_ref()).await?;
+    for problem in &problems {
+        println!("Problem:    {problem}");
+    }
+
+    Ok(())
+}
+
+async fn validate(path: &Path) -> Result<()> {
+    let config = Config::load()?;
+    let problems = check(path, Some(&config)).await?;
+    if !problems.is_empty() {
+        return Err(anyhow!("Invalid firmware image: {}", problems.join(", ")));
+    }
+
+    println!("{}: OK", path.display());
+    Ok(())
+}
+
+/// Append trailer to image (or replace existing one if `rewrite`)
+fn write_trailer(image: &[u8], metadata: &FirmwareMetadata, rewrite: bool) -> Result<Vec<u8>> {
+    let image = match read_trailer(image) {
+        Some(_) if !rewrite => return Err(anyhow!("Image already has trailer (use --rewrite)")),
+        Some(_) => &image[..image.len() - METADATA_SIZE],
+        None => image,
+    };
+
+    let mut data = image.to_vec();
+    data.extend_from_slice(&metadata.to_bytes());
+    Ok(data)
+}
+
+async fn write_trailer_file(args: &Args) -> Result<()> {
+    let path = args.image()?;
+    let version = args.option("version")?;
+    if Channel::of(&Version::from_str(version)).is_none() {
+        return Err(anyhow!("Unsupported version: {version}"));
+    }
+
+    let build_time = match args.options.get("build-time") {
+        Some(build_time) => build_time.parse()?,
+        None => chrono::Utc::now().timestamp() as u64,
+    };
+    let metadata = FirmwareMetadata::new(
+        args.option("hw")?,
+        args.option("firmware")?,
+        version,
+        build_time,
+    )?;
+
+    let data = tokio::fs::read(&path).await?;
+    let (image, embedded) = signature::split_embedded(&data);
+    let data = write_trailer(image, &metadata, args.flags.contains("rewrite"))?;
+    crate::fs_util::write_atomic(&path, &data, false).await?;
+
+    println!("Trailer written to {}", path.display());
+    if embedded.is_some() {
+        println!("Embedded signature removed, sign image again");
+    } else if tokio::fs::try_exists(signature::detached_path(&path)).await? {
+        println!("Detached signature is no longer valid, sign image again");
+    }
+
+    Ok(())
+}
+
+async fn keygen(path: &Path) -> Result<()> {
+    let rng = ring::rand::SystemRandom::new();
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).map_err(|_| anyhow!("Keygen failed"))?;
+    let key_pair =
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(|_| anyhow!("Keygen failed"))?;
+
+    // key is readable only by owner from the start, existing key is never replaced
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .await
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => anyhow!("{} already exists", path.display()),
+            _ => e.into(),
+        })?;
+    file.write_all(
+        base64::prelude::BASE64_STANDARD
+            .encode(pkcs8.as_ref())
+            .as_bytes(),
+    )
+    .await?;
+    file.sync_all().await?;
+
+    println!("Private key written to {}", path.display());
+    println!(
+        "Public key (add to firmware_public_keys): {}",
+        base64::prelude::BASE64_STANDARD.encode(key_pair.public_key())
+    );
+    Ok(())
+}
+
+/// Private key file: PKCS#8 (base64 written by `keygen` or raw DER)
+async fn load_private_key(path: &Path) -> Result<Ed25519KeyPair> {
+    let data = tokio::fs::read(path).await?;
+    let pkcs8 = match core::str::from_utf8(&data) {
+        Ok(text) => base64::prelude::BASE64_STANDARD.decode(text.trim())?,
+        Err(_) => data,
+    };
+
+    Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| anyhow!("Invalid private key"))
+}
+
+async fn sign(args: &Args) -> Result<()> {
+    let path = args.image()?;
+    let key_pair = load_private_key(Path::new(args.option("key")?)).await?;
+
+    let data = tokio::fs::read(&path).await?;
+    let (image, _) = signature::split_embedded(&data);
+    if read_trailer(image).is_none() {
+        return Err(anyhow!("Image has no trailer (add it before signing)"));
+    }
+
+    let image_signature = key_pair.sign(image);
+    if args.flags.contains("embed") {
+        let mut signed = image.to_vec();
+        signed.extend_from_slice(image_signature.as_ref());
+        signed.extend_from_slice(signature::SIGNATURE_MAGIC);
+        crate::fs_util::write_atomic(&path, &signed, false).await?;
+        println!("Signature embedded into {}", path.display());
+    } else {
+        let detached = signature::detached_path(&path);
+        let encoded = base64::prelude::BASE64_STANDARD.encode(image_signature.as_ref());
+        tokio::fs::write(&detached, encoded).await?;
+        println!("Signature written to {}", detached.display());
+    }
+
+    println!(
+        "Signed with public key: {}",
+        base64::prelude::BASE64_STANDARD.encode(key_pair.public_key())
+    );
+    Ok(())
+}
+
+async fn devices(path: &Path) -> Result<()> {
+    let (image, _) = signature::load_image(path).await?;
+    let (_, hw, firmware, version) =
+        read_trailer(&image).ok_or_else(|| anyhow!("Image has no trailer"))?;
+
+    let config = Config::load()?;
+    let devices_path = config.data_dir.join(CONNECTED_DEVICES_FILE);
+    let devices = ConnectedDevices::read(&devices_path).await.map_err(|e| {
+        anyhow!(
+            "Cannot read {} (is server running?): {e}",
+            devices_path.display()
+        )
+    })?;
+
+    let matching: Vec<_> = devices
+        .iter()
+        .filter(|d| d.hw == hw && d.firmware == firmware)
+        .collect();
+
+    println!("{hw}/{firmware} {}", version.inner_version());
+    for device in &matching {
+        let current = Version::from_str(&device.version);
+        let status = if current == version {
+            "installed"
+        } else if current.is_newer(&version) {
+            "update"
+        } else {
+            "older (pin only)"
+        };
+
+        println!("  {:X} {} -> {status}", device.esp_id, device.version);
+    }
+
+    println!(
+        "{} of {} connected devices match",
+        matching.len(),
+        devices.len()
+    );
+    Ok(())
+}
+
+/// Apply retention to `firmware_dir` (running server picks up changes on its own)
+async fn gc(args: &Args) -> Result<()> {
+    let config = Config::load()?;
+    let keep = match args.options.get("keep") {
+        Some(keep) => keep.parse()?,
+        None => config
+            .firmware_keep
+            .ok_or_else(|| anyhow!("firmware_keep isn't set (use --keep)"))?,
+    };
+    if keep == 0 {
+        return Err(anyhow!("--keep must be greater than 0"));
+    }
+
+    let mode = if args.flags.contains("dry-run") {
+        RetentionMode::DryRun
+    } else if args.flags.contains("delete") {
+        RetentionMode::Delete
+    } else {
+        config.firmware_retention
+    };
+
+    let mut catalogue = FirmwareCatalogue::default();
+    catalogue.refresh(&config.firmware_dir, config.dev).await?;
+    let pins = FirmwarePins::load(config.data_dir.join("pins.json")).await;
+
+    let removed = retention::plan(&catalogue, keep, &pins);
+    for entry in &removed {
+        retention::apply(&config.firmware_dir, entry, mode).await?;
+        println!("{}", retention::report_line(mode, entry));
+    }
+
+    println!("{} images over retention (keep {keep})", removed.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trailer_append_rewrite() {
+        let image = vec![0xE9; 1000];
+        let metadata = FirmwareMetadata::new("v3", "STATION", "v3.1.0", 1714320292).unwrap();
+
+        let data = write_trailer(&image, &metadata, false).unwrap();
+        assert_eq!(data.len(), image.len() + METADATA_SIZE);
+        let (trailer, hw, firmware, version) = read_trailer(&data).unwrap();
+        assert_eq!((hw.as_str(), firmware.as_str()), ("v3", "STATION"));
+        assert_eq!(version, Version::from_str("v3.1.0"));
+        assert_eq!(trailer.build_time, 1714320292);
+
+        assert!(write_trailer(&data, &metadata, false).is_err());
+        let metadata = FirmwareMetadata::new("v3", "STATION", "v3.2.0", 0).unwrap();
+        let data = write_trailer(&data, &metadata, true).unwrap();
+        assert_eq!(data.len(), image.len() + METADATA_SIZE);
+        assert_eq!(read_trailer(&data).unwrap().3, Version::from_str("v3.2.0"));
+
+        // zero padded image isn't mistaken for trailer
+        assert!(read_trailer(&[0; 100]).is_none());
+        assert!(FirmwareMetadata::new("v3", "STATION_WITH_LONG_NAME", "v3.2.0", 0).is_err());
+    }
+}
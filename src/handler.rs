@@ -1,11 +1,99 @@
 use crate::{
+    attendance,
+    auth::{self, PacketAuth},
+    battery::BatterySample,
+    clock_sync::{self, ClockOffset, ClockSync},
+    display::{self, Display},
+    heartbeat::{Heartbeat, HeartbeatEvent, HeartbeatSettings, LatencyChange},
     http::EspConnectInfo,
+    legacy,
+    rate_limit::{self, RateLimit, RateLimiter},
+    rollout, shutdown,
+    solve_ledger::SolveLedgerEntry,
     structs::{SharedAppState, TimerPacket, TimerPacketInner},
+    translations::{self, TranslationsUpdate},
+    update_scheduler::UpdateSlot,
+    updater::{self, Firmware},
 };
 use anyhow::Result;
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{CloseFrame, Message, WebSocket, close_code};
+use std::time::{Duration, Instant};
 use tracing::{error, info, trace};
 
+const LOW_BATTERY_MESSAGE: &str = "Low battery";
+
+/// Per-connection device state
+#[derive(Debug)]
+struct DeviceSession {
+    heartbeat: Heartbeat,
+    clock_sync: Option<ClockSync>,
+
+    /// Clock offset measured in this session (device clock can be reset between connections)
+    clock_offset: Option<ClockOffset>,
+    rate_limiter: RateLimiter,
+    display: Display,
+
+    /// Hash of translations held by device (if it supports delta updates)
+    translations_hash: Option<String>,
+
+    /// Update waiting in scheduler queue
+    pending_update: Option<PendingUpdate>,
+
+    /// Device sent authenticated packet in this session (rollout and update checks are fed only then)
+    authenticated: bool,
+}
+
+#[derive(Debug)]
+struct PendingUpdate {
+    slot: tokio::sync::oneshot::Receiver<UpdateSlot>,
+
+    /// Forced firmware (otherwise newest firmware is checked again when slot is granted)
+    firmware: Option<Firmware>,
+}
+
+enum UpdateRequest {
+    Granted(UpdateSlot, Firmware),
+    Queued(PendingUpdate),
+}
+
+/// Ask scheduler for update slot
+fn request_update(
+    state: &SharedAppState,
+    esp_id: u32,
+    firmware: Firmware,
+    forced: bool,
+) -> UpdateRequest {
+    let mut slot = state.update_scheduler.request(esp_id);
+    match slot.try_recv() {
+        Ok(slot) => UpdateRequest::Granted(slot, firmware),
+        Err(_) => {
+            info!("[{esp_id:X}] Waiting for update slot");
+            UpdateRequest::Queued(PendingUpdate {
+                slot,
+                firmware: forced.then_some(firmware),
+            })
+        }
+    }
+}
+
+/// Send update holding scheduler slot. Returns true if connection should be closed
+async fn run_update(
+    socket: &mut WebSocket,
+    esp_connect_info: &EspConnectInfo,
+    state: &SharedAppState,
+    slot: UpdateSlot,
+    firmware: Firmware,
+) -> Result<bool> {
+    let target = firmware.version.clone();
+    let res = updater::update_client(socket, esp_connect_info, state, firmware).await;
+    match res {
+        Ok(true) => slot.complete(),
+        _ => slot.fail(target),
+    }
+
+    res
+}
+
 pub async fn handle_client(
     mut socket: WebSocket,
     esp_connect_info: &EspConnectInfo,
@@ -16,72 +104,231 @@ pub async fn handle_client(
         "============= Client connected! ============="
     );
 
+    let version = updater::Version::from_str(&esp_connect_info.version);
+    state
+        .update_scheduler
+        .reconnected(esp_connect_info.id, &version);
+
+    let mut pending_update = None;
     {
         let state_inner = state.inner.read().await;
-        if state_inner.should_update
-            && let Some(firmware) = super::updater::should_update(&state, esp_connect_info).await?
+        if (state_inner.should_update || legacy::is_legacy(esp_connect_info))
+            && let Some(firmware) = updater::should_update(&state, esp_connect_info).await?
         {
-            tracing::info!(
-                file = format!("device_{:X}", esp_connect_info.id),
-                "Starting update."
-            );
-            super::updater::update_client(&mut socket, esp_connect_info, firmware).await?;
+            drop(state_inner);
+            match request_update(&state, esp_connect_info.id, firmware, false) {
+                UpdateRequest::Granted(slot, firmware) => {
+                    tracing::info!(
+                        file = format!("device_{:X}", esp_connect_info.id),
+                        "Starting update."
+                    );
+                    run_update(&mut socket, esp_connect_info, &state, slot, firmware).await?;
 
-            return Ok(());
+                    return Ok(());
+                }
+                UpdateRequest::Queued(pending) => pending_update = Some(pending),
+            }
         }
     }
 
-    send_epoch_time(&mut socket).await?;
-    send_device_status(&mut socket, esp_connect_info, &state).await?;
-    let mut bc = state.get_bc().await;
+    let config = state.config.read().await;
+    let heartbeat = config.heartbeat();
+    let mut display = Display::new(
+        config.display_columns,
+        esp_connect_info.has_cap(display::SCROLL_CAPABILITY),
+        display::locale_overrides(&config.display_overrides),
+    );
+    if let Some(columns) = esp_connect_info.cap_value(display::DISPLAY_CAPABILITY) {
+        display.set_width(columns.parse().ok());
+    }
+    drop(config);
+
+    let mut session = DeviceSession {
+        heartbeat: Heartbeat::new(heartbeat),
+        clock_sync: esp_connect_info
+            .has_cap(clock_sync::TIME_SYNC_CAPABILITY)
+            .then(ClockSync::new),
+        clock_offset: None,
+        rate_limiter: RateLimiter::new(Instant::now()),
+        display,
+        translations_hash: esp_connect_info.tr_hash.clone(),
+        pending_update,
+        authenticated: false,
+    };
+
+    let res = run_session(
+        &mut socket,
+        esp_connect_info,
+        &state,
+        &mut session,
+        heartbeat,
+    )
+    .await;
+    if session.authenticated {
+        let settings = state.config.read().await.rollout();
+        let events = state.rollouts.lock().await.on_disconnect(
+            &settings,
+            esp_connect_info.id,
+            rollout::now_ms(),
+        );
+        rollout::handle_events(&state, events).await;
+    }
+
+    res
+}
+
+/// First authenticated packet of session: device is who it claims to be (and is added),
+/// so its version can confirm sent update and rollout stage
+async fn on_authenticated(
+    state: &SharedAppState,
+    esp_connect_info: &EspConnectInfo,
+    session: &mut DeviceSession,
+) {
+    if std::mem::replace(&mut session.authenticated, true) {
+        return;
+    }
+
+    let settings = state.config.read().await.rollout();
+    let events =
+        state
+            .rollouts
+            .lock()
+            .await
+            .on_connect(&settings, esp_connect_info, rollout::now_ms());
+    rollout::handle_events(state, events).await;
+    updater::verify_update(state, esp_connect_info).await;
+}
 
-    let interval_time = std::time::Duration::from_secs(5);
-    let mut hb_interval = tokio::time::interval(interval_time);
-    let mut hb_received = true;
+/// Session loop, runs until connection is closed
+async fn run_session(
+    socket: &mut WebSocket,
+    esp_connect_info: &EspConnectInfo,
+    state: &SharedAppState,
+    session: &mut DeviceSession,
+    heartbeat: HeartbeatSettings,
+) -> Result<()> {
+    let mut hb_interval = tokio::time::interval(heartbeat.interval);
+    let mut clock_interval = tokio::time::interval(clock_sync::SAMPLE_SPACING);
+
+    send_epoch_time(socket, esp_connect_info).await?;
+    send_device_status(socket, esp_connect_info, state, session).await?;
+    let mut bc = state.get_bc().await;
 
     loop {
         tokio::select! {
             _ = hb_interval.tick() => {
-                if !hb_received {
-                    error!("Closing connection due to no heartbeat ({:X})", esp_connect_info.id);
-                    tracing::error!(file = format!("device_{:X}", esp_connect_info.id), "============= Closing connection (due to no heartbeat) =============");
-                    break;
+                match session.heartbeat.tick() {
+                    HeartbeatEvent::Ping(payload) => {
+                        socket.send(Message::Ping(payload.into())).await?;
+                    }
+                    HeartbeatEvent::Missed(missed) => {
+                        tracing::warn!(
+                            file = format!("device_{:X}", esp_connect_info.id),
+                            "Missed heartbeat ({missed}/{})",
+                            heartbeat.max_missed
+                        );
+                    }
+                    HeartbeatEvent::Dead => {
+                        error!("Closing connection due to no heartbeat ({:X})", esp_connect_info.id);
+                        tracing::error!(file = format!("device_{:X}", esp_connect_info.id), "============= Closing connection (due to no heartbeat) =============");
+                        break;
+                    }
                 }
+            }
+            _ = clock_interval.tick(), if session.clock_sync.is_some() => {
+                let request = session
+                    .clock_sync
+                    .as_mut()
+                    .and_then(|c| c.poll(Instant::now(), clock_sync::server_time_ms()));
+
+                if let Some(request) = request {
+                    let packet = TimerPacket {
+                        tag: None,
+                        data: request,
+                    };
+
+                    send_packet(socket, esp_connect_info, packet).await?;
+                }
+            }
+            slot = async { (&mut session.pending_update.as_mut().expect("Checked in precondition").slot).await }, if session.pending_update.is_some() => {
+                let pending = session.pending_update.take().expect("Checked in precondition");
+                let Ok(slot) = slot else {
+                    continue;
+                };
 
-                let msg = Message::Ping(vec![].into());
-                socket.send(msg).await?;
-                hb_received = false;
+                let firmware = match pending.firmware {
+                    Some(firmware) => Ok(Some(firmware)),
+                    None => updater::should_update(state, esp_connect_info).await,
+                };
+
+                // device doesn't need update anymore, slot goes to next device
+                let Ok(Some(firmware)) = firmware else {
+                    slot.cancel();
+                    firmware?;
+                    continue;
+                };
+
+                tracing::info!(
+                    file = format!("device_{:X}", esp_connect_info.id),
+                    "Starting update."
+                );
+
+                if run_update(socket, esp_connect_info, state, slot, firmware).await? {
+                    break;
+                }
             }
             Ok(res) = bc.recv() => {
                 match res {
                     crate::structs::BroadcastPacket::Build => {
                         let inner_state = state.inner.read().await;
-                        if !inner_state.should_update {
+                        if (!inner_state.should_update && !legacy::is_legacy(esp_connect_info))
+                            || session.pending_update.is_some()
+                        {
                             continue;
                         }
+                        drop(inner_state);
 
-                        let firmware = super::updater::should_update(&state, esp_connect_info).await?;
+                        let firmware = updater::should_update(state, esp_connect_info).await?;
                         if let Some(firmware) = firmware {
-                            let res = super::updater::update_client(&mut socket, esp_connect_info, firmware).await?;
-                            if res {
-                                break;
+                            match request_update(state, esp_connect_info.id, firmware, false) {
+                                UpdateRequest::Granted(slot, firmware) => {
+                                    if run_update(socket, esp_connect_info, state, slot, firmware).await? {
+                                        break;
+                                    }
+                                }
+                                UpdateRequest::Queued(pending) => session.pending_update = Some(pending),
                             }
                         }
                     },
                     crate::structs::BroadcastPacket::Resp((esp_id, packet)) => {
                         if esp_connect_info.id == esp_id {
-                            let resp = serde_json::to_string(&packet)?;
-                            socket.send(Message::Text(resp.into())).await?;
+                            send_display_packet(socket, esp_connect_info, &session.display, packet).await?;
                         }
                     },
+                    crate::structs::BroadcastPacket::RespAll(packet) => {
+                        send_display_packet(socket, esp_connect_info, &session.display, packet).await?;
+                    },
                     crate::structs::BroadcastPacket::UpdateDeviceSettings => {
-                        send_device_status(&mut socket, esp_connect_info, &state).await?;
+                        send_device_status(socket, esp_connect_info, state, session).await?;
+                    }
+                    crate::structs::BroadcastPacket::Shutdown => {
+                        let frame = CloseFrame {
+                            code: close_code::RESTART,
+                            reason: shutdown::SHUTDOWN_REASON.into(),
+                        };
+
+                        _ = socket.send(Message::Close(Some(frame))).await;
+                        break;
                     }
                     crate::structs::BroadcastPacket::ForceUpdate((hw, firmware)) => {
                         if firmware.firmware == esp_connect_info.firmware && hw == esp_connect_info.hw {
-                            let res = super::updater::update_client(&mut socket, esp_connect_info, firmware).await?;
-                            if res {
-                                break;
+                            match request_update(state, esp_connect_info.id, firmware, true) {
+                                UpdateRequest::Granted(slot, firmware) => {
+                                    if run_update(socket, esp_connect_info, state, slot, firmware).await? {
+                                        break;
+                                    }
+                                }
+                                UpdateRequest::Queued(pending) => session.pending_update = Some(pending),
                             }
                         }
                     }
@@ -89,7 +336,7 @@ pub async fn handle_client(
             }
             msg = socket.recv() => {
                 let msg = msg.ok_or_else(|| anyhow::anyhow!("Frame option is null"))??;
-                let res = on_ws_msg(&mut socket, msg, esp_connect_info, &mut hb_received, &state).await;
+                let res = on_ws_msg(socket, msg, esp_connect_info, session, state).await;
 
                 match res {
                     Ok(true) => break,
@@ -102,6 +349,13 @@ pub async fn handle_client(
         }
     }
 
+    if let Some(latency) = session.heartbeat.stats.snapshot() {
+        tracing::info!(
+            file = format!("device_{:X}", esp_connect_info.id),
+            "Connection latency: {latency}"
+        );
+    }
+
     Ok(())
 }
 
@@ -109,20 +363,42 @@ async fn send_device_status(
     socket: &mut WebSocket,
     esp_connect_info: &EspConnectInfo,
     state: &SharedAppState,
+    session: &mut DeviceSession,
 ) -> Result<()> {
     let state = state.inner.read().await;
+    session.display.default_locale = state.default_locale.clone();
+
+    let delta_capable = esp_connect_info.has_cap(translations::TRANSLATIONS_DELTA_CAPABILITY);
+    let translations_hash = delta_capable.then(|| state.translation_cache.hash.clone());
+    let update = if delta_capable {
+        state
+            .translation_cache
+            .update_for(session.translations_hash.as_deref(), &state.locales)
+    } else {
+        TranslationsUpdate::Full
+    };
+
+    let (locales, translations_delta, removed_translations) = match update {
+        TranslationsUpdate::Full => (state.locales.clone(), false, Vec::new()),
+        TranslationsUpdate::Unchanged => (Vec::new(), true, Vec::new()),
+        TranslationsUpdate::Delta { changed, removed } => (changed, true, removed),
+    };
+
     let settings = state.devices_settings.get(&esp_connect_info.id);
     let settings_frame = if let Some(_settings) = settings {
         TimerPacket {
             tag: None,
             data: TimerPacketInner::DeviceSettings {
                 added: true,
-                locales: state.locales.clone(),
+                locales,
                 default_locale: state.default_locale.clone(),
                 fkm_token: state.fkm_token,
                 secure_rfid: state.secure_rfid,
                 auto_setup: state.auto_setup,
                 sound_enabled: state.sound_enabled,
+                translations_hash,
+                translations_delta,
+                removed_translations,
             },
         }
     } else {
@@ -130,23 +406,25 @@ async fn send_device_status(
             tag: None,
             data: TimerPacketInner::DeviceSettings {
                 added: false,
-                locales: state.locales.clone(),
+                locales,
                 default_locale: state.default_locale.clone(),
                 fkm_token: 0,
                 secure_rfid: false,
                 auto_setup: false,
                 sound_enabled: state.sound_enabled,
+                translations_hash,
+                translations_delta,
+                removed_translations,
             },
         }
     };
 
     drop(state);
-    let response = serde_json::to_string(&settings_frame)?;
-    socket.send(Message::Text(response.into())).await?;
+    send_display_packet(socket, esp_connect_info, &session.display, settings_frame).await?;
     Ok(())
 }
 
-async fn send_epoch_time(socket: &mut WebSocket) -> Result<()> {
+async fn send_epoch_time(socket: &mut WebSocket, esp_connect_info: &EspConnectInfo) -> Result<()> {
     let packet = TimerPacket {
         tag: None,
         data: TimerPacketInner::EpochTime {
@@ -156,8 +434,42 @@ async fn send_epoch_time(socket: &mut WebSocket) -> Result<()> {
         },
     };
 
-    let resp = serde_json::to_string(&packet)?;
-    socket.send(Message::Text(resp.into())).await?;
+    send_packet(socket, esp_connect_info, packet).await?;
+    Ok(())
+}
+
+/// Render display texts for device, then send packet
+async fn send_display_packet(
+    socket: &mut WebSocket,
+    esp_connect_info: &EspConnectInfo,
+    display: &Display,
+    mut packet: TimerPacket,
+) -> Result<()> {
+    display.render_packet(&mut packet.data);
+    send_packet(socket, esp_connect_info, packet).await
+}
+
+/// Send packet to device (translated to legacy structures for old firmware)
+pub async fn send_packet(
+    socket: &mut WebSocket,
+    esp_connect_info: &EspConnectInfo,
+    packet: TimerPacket,
+) -> Result<()> {
+    let payload = if legacy::is_legacy(esp_connect_info) {
+        let Some(packet) = legacy::to_legacy(packet, esp_connect_info.id) else {
+            trace!(
+                "Packet not supported by legacy device [{:X}]",
+                esp_connect_info.id
+            );
+            return Ok(());
+        };
+
+        serde_json::to_string(&packet)?
+    } else {
+        serde_json::to_string(&packet)?
+    };
+
+    socket.send(Message::Text(payload.into())).await?;
     Ok(())
 }
 
@@ -165,7 +477,7 @@ async fn on_ws_msg(
     socket: &mut WebSocket,
     msg: Message,
     esp_connect_info: &EspConnectInfo,
-    hb_received: &mut bool,
+    session: &mut DeviceSession,
     state: &SharedAppState,
 ) -> Result<bool> {
     match msg {
@@ -188,19 +500,58 @@ async fn on_ws_msg(
             }
             return Ok(true);
         }
-        Message::Pong(_) => {
-            *hb_received = true;
+        Message::Pong(payload) => {
+            let change = session.heartbeat.on_pong(&payload);
+            let latency = session.heartbeat.stats.snapshot();
+
+            match (change, latency) {
+                (Some(LatencyChange::Degraded), Some(latency)) => {
+                    tracing::warn!(
+                        file = format!("device_{:X}", esp_connect_info.id),
+                        "Connection latency degraded: {latency}"
+                    );
+                }
+                (Some(LatencyChange::Recovered), Some(latency)) => {
+                    tracing::info!(
+                        file = format!("device_{:X}", esp_connect_info.id),
+                        "Connection latency recovered: {latency}"
+                    );
+                }
+                _ => {}
+            }
         }
         Message::Text(payload) => {
             tracing::trace!("WS payload recv [{:X}]: {payload}", esp_connect_info.id);
 
-            let response: TimerPacket = serde_json::from_str(&payload)?;
-            let res = on_timer_response(socket, response, esp_connect_info, state).await;
+            let response = if legacy::is_legacy(esp_connect_info) {
+                match legacy::translate_incoming(state, esp_connect_info, &payload).await {
+                    Ok(Some(packet)) => packet,
+                    Ok(None) => {
+                        trace!(
+                            "Unsupported legacy packet [{:X}]: {payload}",
+                            esp_connect_info.id
+                        );
+                        session.heartbeat.alive();
+                        return Ok(false);
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            file = format!("device_{:X}", esp_connect_info.id),
+                            "Legacy packet rejected: {e}"
+                        );
+                        return Ok(false);
+                    }
+                }
+            } else {
+                serde_json::from_str(&payload)?
+            };
+
+            let res = on_timer_response(socket, response, esp_connect_info, session, state).await;
             if let Err(e) = res {
                 error!("on_timer_response error: {e:?}");
             }
 
-            *hb_received = true;
+            session.heartbeat.alive();
         }
         Message::Binary(buf) => {
             let esp_id = esp_connect_info.id;
@@ -297,7 +648,7 @@ async fn on_ws_msg(
                 }
             }
 
-            *hb_received = true;
+            session.heartbeat.alive();
         }
 
         _ => {}
@@ -306,46 +657,114 @@ async fn on_ws_msg(
     Ok(false)
 }
 
+/// Mark attendance (or reuse recent mark of the same card) and build device response
+async fn mark_attendance(esp_id: u32, card_id: u64, state: &SharedAppState) -> TimerPacketInner {
+    let window = Duration::from_secs(state.config.read().await.attendance_duplicate_window_secs);
+
+    let duplicate = state.inner.write().await.attendance_marks.duplicate(
+        esp_id,
+        card_id,
+        window,
+        Instant::now(),
+    );
+    if let Some(data) = duplicate {
+        tracing::info!(
+            file = format!("device_{esp_id:X}"),
+            "Duplicate attendance scan ({card_id}), not sent to backend"
+        );
+
+        return data;
+    }
+
+    let result = crate::socket::api::mark_attendance(esp_id, card_id).await;
+    match &result {
+        Ok(info) => state.inner.write().await.attendance_marks.insert(
+            esp_id,
+            card_id,
+            info.name.clone(),
+            Instant::now(),
+        ),
+        Err(e) => tracing::warn!(
+            file = format!("device_{esp_id:X}"),
+            "Attendance rejected ({card_id}): {}",
+            e.message
+        ),
+    }
+
+    attendance::result_packet(result)
+}
+
 async fn on_timer_response(
     socket: &mut WebSocket,
     response: TimerPacket,
     esp_connect_info: &EspConnectInfo,
+    session: &mut DeviceSession,
     state: &SharedAppState,
 ) -> Result<()> {
     let esp_id = esp_connect_info.id;
 
+    match session.rate_limiter.check(&response.data, Instant::now()) {
+        RateLimit::Allowed => {}
+        RateLimit::Recovered(throttled) => {
+            tracing::info!(
+                file = format!("device_{esp_id:X}"),
+                "Rate limit recovered ({throttled} packets throttled)"
+            );
+        }
+        RateLimit::Throttled(throttled) => {
+            if throttled == 1 {
+                tracing::warn!(
+                    file = format!("device_{esp_id:X}"),
+                    "Rate limit exceeded, throttling packets"
+                );
+            }
+            trace!("Throttled packet [{esp_id:X}]: {:?}", response.data);
+
+            if matches!(
+                response.data,
+                TimerPacketInner::CardInfoRequest { .. } | TimerPacketInner::Solve { .. }
+            ) {
+                let resp = TimerPacket {
+                    tag: response.tag,
+                    data: TimerPacketInner::ApiError {
+                        error: rate_limit::THROTTLED_ERROR.to_string(),
+                        should_reset_time: false,
+                    },
+                };
+                send_display_packet(socket, esp_connect_info, &session.display, resp).await?;
+            }
+
+            return Ok(());
+        }
+    }
+
     match response.data {
         TimerPacketInner::CardInfoRequest {
             card_id,
             is_competitor,
             attendance_device,
             sign_key,
+            mac,
+            nonce,
         } => {
-            let Some(device_settings) = state
-                .inner
-                .read()
-                .await
-                .devices_settings
-                .get(&esp_id)
-                .cloned()
-            else {
-                return Err(anyhow::anyhow!("Device not added"));
+            let auth = PacketAuth {
+                sign_key,
+                mac: mac.as_deref(),
+                nonce,
+                resend: false,
+                payload: auth::card_info_payload(esp_id, card_id, is_competitor, attendance_device),
             };
-
-            if let Some(dev_sign_key) = device_settings.sign_key
-                && dev_sign_key != sign_key
-            {
-                return Err(anyhow::anyhow!("Wrong sign key!"));
-            }
+            auth::authenticate_packet(state, esp_connect_info, auth).await?;
+            on_authenticated(state, esp_connect_info, session).await;
 
             let attendance_device = attendance_device.unwrap_or(false);
             if attendance_device {
-                _ = crate::socket::api::mark_attendance(esp_id, card_id).await;
-                let resp = serde_json::to_string(&TimerPacket {
+                let data = mark_attendance(esp_id, card_id, state).await;
+                let resp = TimerPacket {
                     tag: response.tag,
-                    data: TimerPacketInner::AttendanceMarked,
-                })?;
-                socket.send(Message::Text(resp.into())).await?;
+                    data: attendance::for_device(data, esp_connect_info),
+                };
+                send_display_packet(socket, esp_connect_info, &session.display, resp).await?;
 
                 return Ok(());
             }
@@ -385,8 +804,7 @@ async fn on_timer_response(
                 },
             };
 
-            let response = serde_json::to_string(&response)?;
-            socket.send(Message::Text(response.into())).await?;
+            send_display_packet(socket, esp_connect_info, &session.display, response).await?;
         }
         TimerPacketInner::Solve {
             solve_time,
@@ -399,31 +817,76 @@ async fn on_timer_response(
             inspection_time,
             group_id,
             sign_key,
+            mac,
+            nonce,
         } => {
-            let Some(device_settings) = state
-                .inner
-                .read()
-                .await
-                .devices_settings
-                .get(&esp_id)
-                .cloned()
-            else {
-                return Err(anyhow::anyhow!("Device not added"));
+            // solve resent after reconnect is answered from ledger (with its original nonce)
+            let entry = state.solve_ledger.lock().await.get(esp_id, &session_id);
+            let auth = PacketAuth {
+                sign_key,
+                mac: mac.as_deref(),
+                nonce,
+                resend: entry.is_some(),
+                payload: auth::solve_payload(
+                    esp_id,
+                    solve_time,
+                    penalty,
+                    competitor_id,
+                    judge_id,
+                    timestamp,
+                    &session_id,
+                    delegate,
+                    inspection_time,
+                    &group_id,
+                ),
             };
-
-            if let Some(dev_sign_key) = device_settings.sign_key
-                && dev_sign_key != sign_key
-            {
-                return Err(anyhow::anyhow!("Wrong sign key!"));
-            }
+            auth::authenticate_packet(state, esp_connect_info, auth).await?;
+            on_authenticated(state, esp_connect_info, session).await;
 
             trace!(
                 "Solve: {solve_time} ({penalty}) {competitor_id} {esp_id:X} {timestamp} {session_id} {delegate} {group_id}"
             );
+
+            if let Some(entry) = entry {
+                tracing::info!(
+                    file = format!("device_{esp_id:X}"),
+                    "Duplicate solve ({session_id}), replaying confirm"
+                );
+
+                if entry.delegate {
+                    return Ok(());
+                }
+
+                let resp = TimerPacket {
+                    tag: response.tag,
+                    data: TimerPacketInner::SolveConfirm {
+                        session_id,
+                        competitor_id: entry.competitor_id,
+                        message: entry.message,
+                    },
+                };
+
+                send_display_packet(socket, esp_connect_info, &session.display, resp).await?;
+                return Ok(());
+            }
+
+            let mut solved_at_ms = timestamp as i64 * 1000;
+            if let Some(clock_offset) = session.clock_offset {
+                solved_at_ms -= clock_offset.offset_ms;
+
+                if clock_offset.offset_ms.abs() > clock_sync::MAX_TRUSTED_OFFSET_MS {
+                    tracing::warn!(
+                        file = format!("device_{esp_id:X}"),
+                        "Device clock off by {}ms, corrected solve timestamp ({session_id})",
+                        clock_offset.offset_ms
+                    );
+                }
+            }
+
             let res = crate::socket::api::send_solve_entry(
                 solve_time,
                 penalty,
-                timestamp,
+                solved_at_ms,
                 esp_id,
                 judge_id,
                 competitor_id,
@@ -435,21 +898,29 @@ async fn on_timer_response(
             .await;
 
             let resp = match res {
-                Ok(unix_utils::response::UnixResponseData::EnterAttemptResp { message }) => {
-                    if delegate {
-                        return Ok(());
-                    }
+                Ok(data) => {
+                    let message = match data {
+                        unix_utils::response::UnixResponseData::EnterAttemptResp { message } => {
+                            message
+                        }
+                        _ => "None".to_string(),
+                    };
 
-                    TimerPacket {
-                        tag: response.tag,
-                        data: TimerPacketInner::SolveConfirm {
-                            session_id,
-                            competitor_id,
-                            message,
-                        },
+                    let entry = SolveLedgerEntry {
+                        competitor_id,
+                        delegate,
+                        message: message.clone(),
+                    };
+                    if let Err(e) = state
+                        .solve_ledger
+                        .lock()
+                        .await
+                        .insert(esp_id, &session_id, entry)
+                        .await
+                    {
+                        error!("Solve ledger save error: {e:?}");
                     }
-                }
-                Ok(_) => {
+
                     if delegate {
                         return Ok(());
                     }
@@ -459,7 +930,7 @@ async fn on_timer_response(
                         data: TimerPacketInner::SolveConfirm {
                             session_id,
                             competitor_id,
-                            message: "None".to_string(),
+                            message,
                         },
                     }
                 }
@@ -472,19 +943,84 @@ async fn on_timer_response(
                 },
             };
 
-            let response = serde_json::to_string(&resp)?;
-            socket.send(Message::Text(response.into())).await?;
+            send_display_packet(socket, esp_connect_info, &session.display, resp).await?;
         }
-        TimerPacketInner::Battery { level, voltage: _ } => {
+        TimerPacketInner::Battery { level, voltage } => {
             let inner_state = state.inner.read().await;
-            if inner_state.devices_settings.contains_key(&esp_id) {
-                _ = crate::socket::api::send_battery_status(esp_id, level).await;
+            if !inner_state.devices_settings.contains_key(&esp_id) {
+                return Ok(());
+            }
+            drop(inner_state);
+
+            let sample = BatterySample {
+                at_ms: chrono::Utc::now().timestamp_millis(),
+                level,
+                voltage,
+            };
+
+            let thresholds = state.config.read().await.battery_thresholds();
+            let alert = state
+                .battery_history
+                .lock()
+                .await
+                .record(esp_id, sample, thresholds);
+
+            _ = crate::socket::api::send_battery_status(esp_id, level).await;
+            if let Some(alert) = alert {
+                tracing::warn!(
+                    file = format!("device_{esp_id:X}"),
+                    "Low battery: {:.0}% ({:?}V, {:?} minutes left)",
+                    alert.level,
+                    alert.voltage,
+                    alert.minutes_left
+                );
+
+                _ = crate::socket::api::send_battery_alert(esp_id, &alert).await;
+
+                let line2 = match alert.minutes_left {
+                    Some(minutes) => format!("{:.0}% ~{minutes}min", alert.level),
+                    None => format!("{:.0}%", alert.level),
+                };
+
+                let resp = TimerPacket {
+                    tag: None,
+                    data: TimerPacketInner::CustomMessage {
+                        line1: LOW_BATTERY_MESSAGE.to_string(),
+                        line2,
+                    },
+                };
+                send_display_packet(socket, esp_connect_info, &session.display, resp).await?;
             }
         }
-        TimerPacketInner::Add { firmware, sign_key } => {
+        TimerPacketInner::Add {
+            firmware,
+            sign_key,
+            hmac_key,
+        } => {
             let inner_state = state.inner.read().await;
-            if !inner_state.devices_settings.contains_key(&esp_id) {
-                drop(inner_state);
+            let settings = inner_state.devices_settings.get(&esp_id);
+            let added = settings.is_some();
+            let signed = settings
+                .and_then(|settings| settings.sign_key)
+                .is_some_and(|key| key == sign_key);
+            drop(inner_state);
+
+            // key is stored right away only for added device without key that sent its
+            // sign key, otherwise it waits until backend adds device (again), see README
+            if let Some(hmac_key) = hmac_key {
+                let mut device_keys = state.device_keys.lock().await;
+                let res = if signed && !device_keys.has_key(esp_id) {
+                    device_keys.provision(esp_id, &hmac_key).await
+                } else {
+                    device_keys.propose(esp_id, &hmac_key)
+                };
+
+                if let Err(e) = res {
+                    tracing::error!("HMAC key provisioning failed [{esp_id:X}]: {e:?}");
+                }
+            }
+
+            if !added {
                 _ = crate::socket::api::add_device(
                     esp_id,
                     sign_key,
@@ -495,6 +1031,58 @@ async fn on_timer_response(
                 trace!("Add device: {:X}", esp_id);
             }
         }
+        TimerPacketInner::TimeSyncResponse {
+            seq,
+            server_time,
+            device_time,
+        } => {
+            let Some(clock_sync) = session.clock_sync.as_mut() else {
+                return Ok(());
+            };
+
+            let estimate =
+                clock_sync.on_response(seq, server_time, device_time, clock_sync::server_time_ms());
+
+            if let Some(estimate) = estimate {
+                trace!(
+                    "Clock offset [{esp_id:X}]: {}ms (delay: {}ms)",
+                    estimate.offset_ms, estimate.delay_ms
+                );
+
+                let old = session.clock_offset.replace(estimate);
+
+                let was_trusted =
+                    old.is_none_or(|o| o.offset_ms.abs() <= clock_sync::MAX_TRUSTED_OFFSET_MS);
+                if was_trusted && estimate.offset_ms.abs() > clock_sync::MAX_TRUSTED_OFFSET_MS {
+                    tracing::warn!(
+                        file = format!("device_{esp_id:X}"),
+                        "Device clock offset too big: {}ms (delay: {}ms)",
+                        estimate.offset_ms,
+                        estimate.delay_ms
+                    );
+                }
+
+                // final estimate of round is recorded by backend
+                if clock_sync.round_finished(seq) {
+                    _ = crate::socket::api::send_clock_offset(esp_id, &estimate).await;
+                }
+            }
+        }
+        TimerPacketInner::DisplayInfo {
+            columns,
+            glyphs,
+            locale,
+        } => {
+            session.display.set_info(columns, &glyphs, locale);
+
+            // rendered translations depend on display glyphs, so full set is resent
+            session.translations_hash = None;
+            send_device_status(socket, esp_connect_info, state, session).await?;
+        }
+        TimerPacketInner::TranslationsAck { hash } => {
+            trace!("Translations ack [{esp_id:X}]: {hash}");
+            session.translations_hash = Some(hash);
+        }
         TimerPacketInner::TestAck(snapshot) => {
             let inner_state = state.inner.read().await;
             if inner_state.devices_settings.contains_key(&esp_id) {
@@ -0,0 +1,214 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+const LATENCY_WINDOW: usize = 64;
+const MAX_PENDING_PINGS: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatSettings {
+    pub interval: Duration,
+
+    /// How many heartbeat intervals without any frame before connection is dropped
+    pub max_missed: u32,
+
+    /// RTT (p95) above which latency is considered degraded
+    pub warn_rtt: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LatencySnapshot {
+    pub min: Duration,
+    pub avg: Duration,
+    pub p95: Duration,
+    pub samples: usize,
+}
+
+impl std::fmt::Display for LatencySnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "min: {}ms, avg: {}ms, p95: {}ms ({} samples)",
+            self.min.as_millis(),
+            self.avg.as_millis(),
+            self.p95.as_millis(),
+            self.samples
+        )
+    }
+}
+
+/// Rolling window of ping/pong round trip times
+#[derive(Debug, Default)]
+pub struct LatencyStats {
+    samples: VecDeque<Duration>,
+}
+
+impl LatencyStats {
+    pub fn push(&mut self, rtt: Duration) {
+        if self.samples.len() >= LATENCY_WINDOW {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(rtt);
+    }
+
+    pub fn snapshot(&self) -> Option<LatencySnapshot> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort();
+
+        let p95_idx = (sorted.len() * 95).div_ceil(100) - 1;
+        Some(LatencySnapshot {
+            min: sorted[0],
+            avg: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+            p95: sorted[p95_idx],
+            samples: sorted.len(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HeartbeatEvent {
+    /// Send ping with this payload
+    Ping(Vec<u8>),
+
+    /// Beat missed, but connection is still within allowed limit
+    Missed(u32),
+
+    /// Too many missed beats, connection should be dropped
+    Dead,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LatencyChange {
+    Degraded,
+    Recovered,
+}
+
+/// Per-connection heartbeat state
+#[derive(Debug)]
+pub struct Heartbeat {
+    settings: HeartbeatSettings,
+    received: bool,
+    missed: u32,
+    seq: u64,
+    pending: VecDeque<(u64, Instant)>,
+    degraded: bool,
+    pub stats: LatencyStats,
+}
+
+impl Heartbeat {
+    pub fn new(settings: HeartbeatSettings) -> Self {
+        Self {
+            settings,
+            received: true,
+            missed: 0,
+            seq: 0,
+            pending: VecDeque::new(),
+            degraded: false,
+            stats: LatencyStats::default(),
+        }
+    }
+
+    /// Called on every heartbeat interval tick
+    pub fn tick(&mut self) -> HeartbeatEvent {
+        if self.received {
+            self.received = false;
+            self.missed = 0;
+        } else {
+            self.missed += 1;
+            if self.missed >= self.settings.max_missed {
+                return HeartbeatEvent::Dead;
+            }
+
+            return HeartbeatEvent::Missed(self.missed);
+        }
+
+        self.seq += 1;
+        if self.pending.len() >= MAX_PENDING_PINGS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((self.seq, Instant::now()));
+
+        HeartbeatEvent::Ping(self.seq.to_be_bytes().to_vec())
+    }
+
+    /// Any frame received from device counts as heartbeat
+    pub fn alive(&mut self) {
+        self.received = true;
+    }
+
+    /// Record pong RTT, returns latency state change (if any)
+    pub fn on_pong(&mut self, payload: &[u8]) -> Option<LatencyChange> {
+        self.alive();
+
+        let seq = u64::from_be_bytes(payload.try_into().ok()?);
+        let idx = self.pending.iter().position(|(s, _)| *s == seq)?;
+        let (_, sent_at) = self.pending.remove(idx)?;
+        self.pending.retain(|(s, _)| *s > seq);
+
+        self.stats.push(sent_at.elapsed());
+        let p95 = self.stats.snapshot()?.p95;
+
+        let degraded = p95 > self.settings.warn_rtt;
+        if degraded == self.degraded {
+            return None;
+        }
+
+        self.degraded = degraded;
+        Some(if degraded {
+            LatencyChange::Degraded
+        } else {
+            LatencyChange::Recovered
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(max_missed: u32) -> HeartbeatSettings {
+        HeartbeatSettings {
+            interval: Duration::from_secs(5),
+            max_missed,
+            warn_rtt: Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn missed_beats() {
+        let mut hb = Heartbeat::new(settings(1));
+        assert!(matches!(hb.tick(), HeartbeatEvent::Ping(_)));
+        assert_eq!(hb.tick(), HeartbeatEvent::Dead);
+
+        let mut hb = Heartbeat::new(settings(3));
+        let HeartbeatEvent::Ping(payload) = hb.tick() else {
+            panic!("expected ping");
+        };
+        assert_eq!(hb.tick(), HeartbeatEvent::Missed(1));
+        assert_eq!(hb.tick(), HeartbeatEvent::Missed(2));
+        assert_eq!(hb.on_pong(&payload), None);
+        assert!(matches!(hb.tick(), HeartbeatEvent::Ping(_)));
+        assert_eq!(hb.stats.snapshot().map(|s| s.samples), Some(1));
+    }
+
+    #[test]
+    fn latency_stats() {
+        let mut stats = LatencyStats::default();
+        assert!(stats.snapshot().is_none());
+
+        for ms in 1..=100 {
+            stats.push(Duration::from_millis(ms));
+        }
+
+        let snapshot = stats.snapshot().unwrap();
+        assert_eq!(snapshot.samples, LATENCY_WINDOW);
+        assert_eq!(snapshot.min, Duration::from_millis(37));
+        assert_eq!(snapshot.p95, Duration::from_millis(97));
+    }
+}
@@ -1,17 +1,17 @@
 use crate::handler::handle_client;
+use crate::shutdown::ActiveGuard;
 use crate::structs::SharedAppState;
+use crate::tls::TlsIdentity;
 use aes::Aes128;
 use aes::cipher::{Array, BlockCipherEncrypt, KeyInit};
 use anyhow::Result;
 use axum::Router;
 use axum::extract::ws::WebSocket;
 use axum::extract::{Query, State};
-use axum::http::HeaderMap;
-use axum::response::IntoResponse;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum::{extract::WebSocketUpgrade, routing::get};
 use axum_server::tls_rustls::RustlsConfig;
-use rcgen::CertifiedKey;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use serde::Deserialize;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -41,30 +41,45 @@ pub struct EspConnectInfo {
 
     #[serde(default = "default_random")]
     pub random: u64,
+
+    /// Comma separated firmware capabilities (like `hmac`)
+    #[serde(default)]
+    pub caps: String,
+
+    /// Hash of translations held by device (see `translations`)
+    #[serde(default)]
+    pub tr_hash: Option<String>,
+}
+
+impl EspConnectInfo {
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.split(',').any(|c| c.trim() == cap)
+    }
+
+    /// Value of capability with parameter (`cap=value`), empty if it has no value
+    pub fn cap_value(&self, cap: &str) -> Option<&str> {
+        self.caps.split(',').find_map(|c| {
+            let (name, value) = c.trim().split_once('=').unwrap_or((c.trim(), ""));
+            (name == cap).then_some(value)
+        })
+    }
 }
 
 impl core::fmt::Display for EspConnectInfo {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(
             f,
-            "EspConnectInfo {{ id: {:08X}, version: \"{}\", firmware: \"{}\", hw: \"{}\", random: {} }}",
-            self.id, self.version, self.firmware, self.hw, self.random
+            "EspConnectInfo {{ id: {:08X}, version: \"{}\", firmware: \"{}\", hw: \"{}\", random: {}, caps: \"{}\" }}",
+            self.id, self.version, self.firmware, self.hw, self.random, self.caps
         )
     }
 }
 
-fn cert_from_str(cert: &str) -> Result<Vec<CertificateDer<'static>>> {
-    rustls_pemfile::certs(&mut cert.as_bytes())
-        .collect::<std::io::Result<_>>()
-        .map_err(anyhow::Error::from)
-}
-
-fn key_from_str(key: &str) -> Result<PrivateKeyDer<'static>> {
-    rustls_pemfile::private_key(&mut key.as_bytes())?
-        .ok_or_else(|| anyhow::anyhow!("Private ket returned None"))
-}
-
-pub async fn start_server(port: u16, state: SharedAppState) -> Result<()> {
+pub async fn start_server(
+    port: u16,
+    tls: Option<TlsIdentity>,
+    state: SharedAppState,
+) -> Result<()> {
     let addr: SocketAddr = format!("0.0.0.0:{port}").parse()?;
     info!("Server started, listening on {addr}");
 
@@ -76,28 +91,23 @@ pub async fn start_server(port: u16, state: SharedAppState) -> Result<()> {
         )
         .with_state(state);
 
-    if std::env::var("NO_TLS").is_ok() {
-        let listener = TcpListener::bind(addr).await?;
-        axum::serve(listener, app.into_make_service()).await?;
-    } else {
+    if let Some(tls) = tls {
         rustls::crypto::ring::default_provider()
             .install_default()
             .expect("Ring default provider install error");
 
-        let CertifiedKey { cert, signing_key } =
-            rcgen::generate_simple_self_signed(vec!["micro-connector.local".to_string()])?;
-        let crt = cert_from_str(&cert.pem())?;
-        let key = key_from_str(&signing_key.serialize_pem())?;
-
         let mut config = rustls::server::ServerConfig::builder()
             .with_no_client_auth()
-            .with_single_cert(crt, key)?;
+            .with_single_cert(tls.certs, tls.key)?;
         config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
 
         let config = RustlsConfig::from_config(Arc::new(config));
         axum_server::bind_rustls(addr, config)
             .serve(app.into_make_service())
             .await?;
+    } else {
+        let listener = TcpListener::bind(addr).await?;
+        axum::serve(listener, app.into_make_service()).await?;
     }
     Ok(())
 }
@@ -106,8 +116,19 @@ async fn ws_handler(
     ws: WebSocketUpgrade,
     Query(esp_connect_info): Query<EspConnectInfo>,
     State(state): State<SharedAppState>,
-) -> impl IntoResponse {
+) -> Response {
     let mut headers = HeaderMap::new();
+    if state.is_shutting_down() {
+        return (StatusCode::SERVICE_UNAVAILABLE, headers).into_response();
+    }
+
+    let Ok(slot) = state.connection_slots.clone().try_acquire_owned() else {
+        error!(
+            "Connection limit reached, rejecting device: {:X}",
+            esp_connect_info.id
+        );
+        return (StatusCode::SERVICE_UNAVAILABLE, headers).into_response();
+    };
 
     let inner = state.inner.read().await;
     if let Some(device_settings) = inner.devices_settings.get(&esp_connect_info.id)
@@ -136,18 +157,48 @@ async fn ws_handler(
 
     (
         headers,
-        ws.on_upgrade(move |socket| handle_socket(socket, esp_connect_info, state)),
+        ws.on_upgrade(move |socket| async move {
+            handle_socket(socket, esp_connect_info, state).await;
+            drop(slot);
+        }),
     )
+        .into_response()
 }
 
 async fn handle_socket(socket: WebSocket, esp_connect_info: EspConnectInfo, state: SharedAppState) {
     info!("Client connected: {esp_connect_info}");
+    let _connection = ActiveGuard::new(&state.connections);
+    let added = state
+        .inner
+        .read()
+        .await
+        .devices_settings
+        .contains_key(&esp_connect_info.id);
+    let connection = if added {
+        Some(
+            state
+                .connected_devices
+                .lock()
+                .await
+                .connect(&esp_connect_info),
+        )
+    } else {
+        None
+    };
 
-    let res = handle_client(socket, &esp_connect_info, state).await;
+    let res = handle_client(socket, &esp_connect_info, state.clone()).await;
     if let Err(e) = res {
         error!("Handle client error: {e}");
     }
 
+    if let Some(connection) = connection {
+        state
+            .connected_devices
+            .lock()
+            .await
+            .disconnect(esp_connect_info.id, connection);
+    }
+
     info!("Client disconnected: {esp_connect_info}");
     tracing::info!(
         file = format!("device_{:X}", esp_connect_info.id),
@@ -0,0 +1,458 @@
+use crate::{
+    http::EspConnectInfo,
+    structs::{SharedAppState, TimerPacket, TimerPacketInner},
+    updater::Version,
+};
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, path::PathBuf};
+
+/// First firmware version using current packet structures
+const FIRST_CURRENT_VERSION: &str = "v2.4";
+
+/// Devices that authenticated with current protocol (persisted).
+/// Legacy protocol has no packet authentication, so legacy packets of these devices
+/// (sent by connection with spoofed `ver`) are rejected.
+#[derive(Debug)]
+pub struct CurrentProtocolDevices {
+    path: PathBuf,
+    devices: BTreeSet<u32>,
+}
+
+impl CurrentProtocolDevices {
+    pub async fn load(path: PathBuf) -> Self {
+        let devices = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                tracing::error!("Current protocol devices parse error (starting empty): {e:?}");
+                BTreeSet::new()
+            }),
+            Err(_) => BTreeSet::new(),
+        };
+
+        Self { path, devices }
+    }
+
+    pub fn contains(&self, esp_id: u32) -> bool {
+        self.devices.contains(&esp_id)
+    }
+
+    /// Saved only if device wasn't recorded yet
+    pub async fn record(&mut self, esp_id: u32) -> Result<()> {
+        if !self.devices.insert(esp_id) {
+            return Ok(());
+        }
+
+        let data = serde_json::to_vec_pretty(&self.devices)?;
+        crate::fs_util::write_atomic(&self.path, &data, false).await
+    }
+}
+
+/// Devices with stable firmware older than `v2.4` use legacy protocol
+pub fn is_legacy(esp_connect_info: &EspConnectInfo) -> bool {
+    is_legacy_version(&Version::from_str(&esp_connect_info.version))
+}
+
+pub fn is_legacy_version(version: &Version) -> bool {
+    version.is_stable() && version.is_newer(&Version::from_str(FIRST_CURRENT_VERSION))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LegacyTimerPacket {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<u64>,
+    pub data: LegacyTimerPacketInner,
+}
+
+/// Packet structures used by firmware < `v2.4`.
+/// Every device packet carried its `esp_id`, there were no sign keys, crc or group ids.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum LegacyTimerPacketInner {
+    StartUpdate {
+        esp_id: u32,
+        version: String,
+        build_time: u64,
+        size: u32,
+        firmware: String,
+    },
+    Solve {
+        solve_time: u64,
+        penalty: i64,
+        competitor_id: u64,
+        judge_id: u64,
+        esp_id: u32,
+        timestamp: u64,
+        session_id: String,
+        delegate: bool,
+        inspection_time: i64,
+    },
+    SolveConfirm {
+        esp_id: u32,
+        competitor_id: u64,
+        session_id: String,
+    },
+    DelegateResponse {
+        esp_id: u32,
+        should_scan_cards: bool,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        solve_time: Option<u64>,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        penalty: Option<i64>,
+    },
+    ApiError {
+        esp_id: u32,
+        error: String,
+        should_reset_time: bool,
+    },
+    CardInfoRequest {
+        card_id: u64,
+        esp_id: u32,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        attendance_device: Option<bool>,
+    },
+    CardInfoResponse {
+        card_id: u64,
+        esp_id: u32,
+        display: String,
+        country_iso2: String,
+        can_compete: bool,
+    },
+    AttendanceMarked {
+        esp_id: u32,
+    },
+    DeviceSettings {
+        esp_id: u32,
+        use_inspection: bool,
+        secure_rfid: bool,
+        added: bool,
+    },
+    Battery {
+        esp_id: u32,
+        level: Option<f64>,
+        voltage: Option<f64>,
+    },
+    Add {
+        esp_id: u32,
+        firmware: String,
+    },
+    EpochTime {
+        current_epoch: u64,
+    },
+}
+
+/// Translate packet received from legacy device, Err if legacy packets aren't accepted
+/// (`legacy_devices` disabled, device has HMAC key or authenticated with current protocol).
+/// Returns None for packets that have no current equivalent.
+pub async fn translate_incoming(
+    state: &SharedAppState,
+    esp_connect_info: &EspConnectInfo,
+    payload: &str,
+) -> Result<Option<TimerPacket>> {
+    let esp_id = esp_connect_info.id;
+    if !state.config.read().await.legacy_devices {
+        return Err(anyhow!("Legacy devices are disabled"));
+    }
+
+    if state.device_keys.lock().await.has_key(esp_id) {
+        return Err(anyhow!("Device has HMAC key"));
+    }
+
+    if state.current_protocol_devices.lock().await.contains(esp_id) {
+        return Err(anyhow!("Device authenticated with current protocol before"));
+    }
+
+    let packet: LegacyTimerPacket = serde_json::from_str(payload)?;
+    let sign_key = state
+        .inner
+        .read()
+        .await
+        .devices_settings
+        .get(&esp_id)
+        .and_then(|settings| settings.sign_key)
+        .unwrap_or(0);
+
+    Ok(from_legacy(packet, sign_key))
+}
+
+/// Translate packet received from legacy device.
+/// Legacy firmware doesn't send sign key, signed packets get `sign_key` of the added
+/// device (packets of devices that aren't added are still rejected).
+/// Returns None for packets that have no current equivalent.
+pub fn from_legacy(packet: LegacyTimerPacket, sign_key: u32) -> Option<TimerPacket> {
+    let data = match packet.data {
+        LegacyTimerPacketInner::Solve {
+            solve_time,
+            penalty,
+            competitor_id,
+            judge_id,
+            esp_id: _,
+            timestamp,
+            session_id,
+            delegate,
+            inspection_time,
+        } => TimerPacketInner::Solve {
+            solve_time,
+            penalty,
+            competitor_id,
+            judge_id,
+            timestamp,
+            session_id,
+            delegate,
+            inspection_time,
+            group_id: String::new(),
+            sign_key,
+            mac: None,
+            nonce: None,
+        },
+        LegacyTimerPacketInner::CardInfoRequest {
+            card_id,
+            esp_id: _,
+            attendance_device,
+        } => TimerPacketInner::CardInfoRequest {
+            card_id,
+            // legacy firmware doesn't distinguish competitor and judge scans
+            is_competitor: true,
+            attendance_device,
+            sign_key,
+            mac: None,
+            nonce: None,
+        },
+        LegacyTimerPacketInner::Battery {
+            esp_id: _,
+            level,
+            voltage,
+        } => TimerPacketInner::Battery { level, voltage },
+        LegacyTimerPacketInner::Add {
+            esp_id: _,
+            firmware,
+        } => TimerPacketInner::Add {
+            firmware,
+            sign_key: 0,
+            hmac_key: None,
+        },
+        _ => return None,
+    };
+
+    Some(TimerPacket {
+        tag: packet.tag,
+        data,
+    })
+}
+
+/// Translate packet sent to legacy device.
+/// Returns None for packets that legacy firmware doesn't understand.
+pub fn to_legacy(packet: TimerPacket, esp_id: u32) -> Option<LegacyTimerPacket> {
+    let data = match packet.data {
+        TimerPacketInner::StartUpdate {
+            version,
+            build_time,
+            size,
+            crc: _,
+            firmware,
+            resume: _,
+            chunk_size: _,
+            window: _,
+        } => LegacyTimerPacketInner::StartUpdate {
+            esp_id,
+            version,
+            build_time,
+            size,
+            firmware,
+        },
+        TimerPacketInner::SolveConfirm {
+            competitor_id,
+            session_id,
+            message: _,
+        } => LegacyTimerPacketInner::SolveConfirm {
+            esp_id,
+            competitor_id,
+            session_id,
+        },
+        TimerPacketInner::DelegateResponse {
+            should_scan_cards,
+            solve_time,
+            penalty,
+        } => LegacyTimerPacketInner::DelegateResponse {
+            esp_id,
+            should_scan_cards,
+            solve_time,
+            penalty,
+        },
+        TimerPacketInner::ApiError {
+            error,
+            should_reset_time,
+        } => LegacyTimerPacketInner::ApiError {
+            esp_id,
+            error,
+            should_reset_time,
+        },
+        TimerPacketInner::CardInfoResponse {
+            card_id,
+            display,
+            country_iso2,
+            can_compete,
+            possible_groups: _,
+        } => LegacyTimerPacketInner::CardInfoResponse {
+            card_id,
+            esp_id,
+            display,
+            country_iso2,
+            can_compete,
+        },
+        TimerPacketInner::AttendanceMarked => LegacyTimerPacketInner::AttendanceMarked { esp_id },
+        TimerPacketInner::DeviceSettings {
+            added, secure_rfid, ..
+        } => LegacyTimerPacketInner::DeviceSettings {
+            esp_id,
+            use_inspection: true,
+            secure_rfid,
+            added,
+        },
+        TimerPacketInner::EpochTime { current_epoch } => {
+            LegacyTimerPacketInner::EpochTime { current_epoch }
+        }
+        _ => return None,
+    };
+
+    Some(LegacyTimerPacket {
+        tag: packet.tag,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        structs::DeviceSettings,
+        test_util::{TempDir, esp_info},
+    };
+    use base64::Engine;
+
+    #[tokio::test]
+    async fn forged_packets_rejected() {
+        let dir = TempDir::new("legacy");
+        let config = crate::config::Config {
+            data_dir: dir.path().to_path_buf(),
+            legacy_devices: true,
+            ..Default::default()
+        };
+        let state = SharedAppState::new(config, None).await;
+        for esp_id in [1, 2, 3] {
+            state.inner.write().await.devices_settings.insert(
+                esp_id,
+                DeviceSettings {
+                    sign_key: Some(0xABCD),
+                    room: None,
+                },
+            );
+        }
+
+        let key = base64::prelude::BASE64_STANDARD.encode([0xAB; crate::auth::HMAC_KEY_SIZE]);
+        state
+            .device_keys
+            .lock()
+            .await
+            .provision(2, &key)
+            .await
+            .unwrap();
+        state
+            .current_protocol_devices
+            .lock()
+            .await
+            .record(3)
+            .await
+            .unwrap();
+
+        let solve = |esp_id: u32| {
+            format!(
+                r#"{{"data":{{"solve":{{"solve_time":1000,"penalty":0,"competitor_id":1,"judge_id":2,"esp_id":{esp_id},"timestamp":0,"session_id":"s","delegate":false,"inspection_time":0}}}}}}"#
+            )
+        };
+        let translate = |esp_id: u32| {
+            let state = state.clone();
+            async move { translate_incoming(&state, &esp_info(esp_id, "v2.3"), &solve(esp_id)).await }
+        };
+
+        assert!(matches!(
+            translate(1).await,
+            Ok(Some(TimerPacket {
+                data: TimerPacketInner::Solve {
+                    sign_key: 0xABCD,
+                    ..
+                },
+                ..
+            }))
+        ));
+        assert!(translate(2).await.is_err());
+        assert!(translate(3).await.is_err());
+
+        // persisted across restarts
+        let state = SharedAppState::new(state.config.read().await.clone(), None).await;
+        let info = esp_info(3, "v2.3");
+        assert!(translate_incoming(&state, &info, &solve(3)).await.is_err());
+
+        state.config.write().await.legacy_devices = false;
+        let info = esp_info(1, "v2.3");
+        assert!(translate_incoming(&state, &info, &solve(1)).await.is_err());
+    }
+
+    #[test]
+    fn translate_packets() {
+        let info = |version: &str| esp_info(0x1234, version);
+        assert!(is_legacy(&info("v2.3.1")));
+        assert!(!is_legacy(&info("v2.4")));
+        assert!(!is_legacy(&info("v3.0.0")));
+        assert!(!is_legacy(&info("D1714320292")));
+
+        let legacy: LegacyTimerPacket = serde_json::from_str(
+            r#"{"tag":5,"data":{"add":{"esp_id":4660,"firmware":"STATION"}}}"#,
+        )
+        .unwrap();
+        let packet = from_legacy(legacy, 0xABCD).unwrap();
+        assert_eq!(packet.tag, Some(5));
+        assert!(matches!(
+            packet.data,
+            TimerPacketInner::Add { sign_key: 0, .. }
+        ));
+
+        let legacy: LegacyTimerPacket =
+            serde_json::from_str(r#"{"data":{"card_info_request":{"card_id":1,"esp_id":4660}}}"#)
+                .unwrap();
+        assert!(matches!(
+            from_legacy(legacy, 0xABCD).unwrap().data,
+            TimerPacketInner::CardInfoRequest {
+                sign_key: 0xABCD,
+                ..
+            }
+        ));
+
+        let packet = TimerPacket {
+            tag: None,
+            data: TimerPacketInner::StartUpdate {
+                version: "3.0.0".to_string(),
+                build_time: 0,
+                size: 1024,
+                crc: 0xDEADBEEF,
+                firmware: "STATION".to_string(),
+                resume: false,
+                chunk_size: None,
+                window: None,
+            },
+        };
+        let json = serde_json::to_string(&to_legacy(packet, 0x1234).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            r#"{"data":{"start_update":{"esp_id":4660,"version":"3.0.0","build_time":0,"size":1024,"firmware":"STATION"}}}"#
+        );
+
+        let packet = TimerPacket {
+            tag: None,
+            data: TimerPacketInner::DumpCrashLog,
+        };
+        assert!(to_legacy(packet, 0x1234).is_none());
+    }
+}
@@ -1,16 +1,41 @@
 use anyhow::Result;
-use std::{os::unix::fs::PermissionsExt, path::PathBuf};
+use std::os::unix::fs::PermissionsExt;
 
 mod adapter;
+mod attendance;
+mod auth;
+mod battery;
 mod bluetooth;
+mod clock_sync;
+mod config;
+mod connected_devices;
+mod display;
 mod error_log;
+mod firmware_catalogue;
+mod fs_util;
+mod fw_cli;
 mod github;
 mod handler;
+mod heartbeat;
 mod http;
+mod legacy;
 mod log_subscriber;
 mod mdns;
+mod pins;
+mod rate_limit;
+mod retention;
+mod rollout;
+mod rooms;
+mod shutdown;
+mod signature;
 mod socket;
+mod solve_ledger;
 mod structs;
+#[cfg(test)]
+mod test_util;
+mod tls;
+mod translations;
+mod update_scheduler;
 mod updater;
 mod watchers;
 
@@ -20,51 +45,113 @@ pub static UNIX_SOCKET: socket::Socket = socket::Socket::const_new();
 async fn main() -> Result<()> {
     _ = dotenvy::dotenv();
 
-    let logs_path = env_or_default("DEVICE_LOGS", "/tmp/fkm-logs");
-    log_subscriber::MinimalTracer::register(PathBuf::from(logs_path))?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|a| a == "fw") {
+        return fw_cli::run(&args[1..]).await;
+    }
+
+    let config = config::Config::load()?;
+    log_subscriber::MinimalTracer::register(config.device_logs.clone())?;
 
-    let firmware_dir = env_or_default("FIRMWARE_DIR", "/tmp/fkm-build");
-    let firmware_dir = std::path::PathBuf::from(firmware_dir);
+    let firmware_dir = &config.firmware_dir;
     if !firmware_dir.exists() {
         tokio::fs::create_dir_all(&firmware_dir).await?;
         let mut perms = tokio::fs::metadata(&firmware_dir).await?.permissions();
         perms.set_mode(0o777);
     }
 
-    let dev_mode = std::env::var("DEV").is_ok();
-    let state = structs::SharedAppState::new(dev_mode).await;
+    tokio::fs::create_dir_all(&config.data_dir).await?;
 
-    let socket_path = env_or_default("SOCKET_PATH", "/tmp/socket.sock");
-    let port: u16 = env_or_default("PORT", "8080").parse()?;
-    UNIX_SOCKET.init(&socket_path, state.clone()).await?;
+    let tls = if config.tls {
+        let (cert_path, key_path) = config.tls_paths();
+        let tls = tls::TlsIdentity::load_or_generate(&cert_path, &key_path).await?;
+        tracing::info!("TLS certificate fingerprint (SHA-256): {}", tls.fingerprint);
+        Some(tls)
+    } else {
+        None
+    };
 
-    if std::env::var("NO_MDNS").is_err() {
-        mdns::register_mdns(&port).await?;
-    }
+    let port = config.port;
+    let socket_path = config.socket_path.clone();
+    let tls_fingerprint = tls.as_ref().map(|t| t.fingerprint.clone());
+    let state = structs::SharedAppState::new(config, tls_fingerprint).await;
+    UNIX_SOCKET
+        .init(&socket_path.to_string_lossy(), state.clone())
+        .await?;
+
+    rooms::reload_rooms(&state).await?;
 
-    if std::env::var("NO_BT").is_err() {
-        let res = bluetooth::start_bluetooth_task(state.clone()).await;
+    let config = state.config.read().await.clone();
+    let mdns = if config.mdns {
+        Some(mdns::register_mdns(&config, state.tls_fingerprint.as_deref()).await?)
+    } else {
+        None
+    };
+
+    if config.bluetooth {
+        let res = bluetooth::start_bluetooth_task(&config, state.clone()).await;
         if let Err(e) = res {
             tracing::error!("Cannot spawn bluetooth task: {e:?}");
         }
     }
 
     watchers::spawn_watchers(state.clone()).await?;
-    tokio::task::spawn(http::start_server(port, state));
+    tokio::task::spawn(http::start_server(port, tls, state.clone()));
 
     let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
-    tokio::select! {
-        _ = sigterm.recv() => {
-            tracing::info!("Received SIGTERM, stopping server!");
-        }
-        _ = tokio::signal::ctrl_c() => {
-            tracing::info!("Received SIGINT, stopping server!");
+    let mut sighup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            _ = sigterm.recv() => {
+                tracing::info!("Received SIGTERM, stopping server!");
+                break;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                tracing::info!("Received SIGINT, stopping server!");
+                break;
+            }
+            _ = sighup.recv() => {
+                tracing::info!("Received SIGHUP, reloading config!");
+                reload_config(&state).await;
+            }
         }
     }
 
+    shutdown::graceful_shutdown(&state, mdns).await;
     Ok(())
 }
 
-fn env_or_default(key: &str, default: &str) -> String {
-    std::env::var(key).unwrap_or_else(|_| default.to_string())
+async fn reload_config(state: &structs::SharedAppState) {
+    let new_config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Config reload failed (keeping old config): {e}");
+            return;
+        }
+    };
+
+    let max_concurrent_updates = new_config.max_concurrent_updates;
+    let restart_required = state.config.write().await.reload(new_config);
+    state
+        .update_scheduler
+        .set_max_active(max_concurrent_updates);
+    if let Err(e) = state.firmware_pins.lock().await.reload().await {
+        tracing::error!("Firmware pins reload failed: {e}");
+    }
+
+    // images rejected with previous keys are offered again
+    state.firmware_catalogue.write().await.clear();
+    if let Err(e) = firmware_catalogue::refresh(state).await {
+        tracing::error!("Firmware catalogue refresh failed: {e}");
+    }
+    if let Err(e) = rooms::reload_rooms(state).await {
+        tracing::error!("Rooms reload failed: {e}");
+    }
+
+    if !restart_required.is_empty() {
+        tracing::warn!(
+            "Changed settings require restart: {}",
+            restart_required.join(", ")
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use crate::config::Config;
 use anyhow::Result;
 use mdns_sd::{ServiceDaemon, ServiceInfo};
 
@@ -5,16 +6,50 @@ pub const SERVICE_TYPE: &str = "_fkmtime._tcp.local.";
 pub const INSTANCE_NAME: &str = "fkmtime_microconnector";
 pub const HOST_NAME: &str = "fkmtime.local.";
 
-pub async fn register_mdns(port: &u16) -> Result<()> {
-    let adapter_api = crate::adapter::adapter_api_url();
+/// Registered mDNS services (kept to unregister them on shutdown)
+pub enum MdnsHandle {
+    Daemons(Vec<(ServiceDaemon, String)>),
+    Adapter(tokio::task::JoinHandle<Result<()>>),
+}
+
+impl MdnsHandle {
+    pub fn unregister(self) {
+        match self {
+            Self::Daemons(daemons) => {
+                for (mdns, fullname) in daemons {
+                    if let Err(e) = mdns.unregister(&fullname) {
+                        tracing::error!("mDNS unregister error: {e:?}");
+                    }
+
+                    _ = mdns.shutdown();
+                }
+            }
+            Self::Adapter(task) => task.abort(),
+        }
+    }
+}
+
+/// mDNS TXT record key with TLS certificate SHA-256 fingerprint
+pub const FINGERPRINT_PROPERTY: &str = "fp";
+
+pub async fn register_mdns(config: &Config, tls_fingerprint: Option<&str>) -> Result<MdnsHandle> {
+    let port = &config.port;
+    let adapter_api = crate::adapter::adapter_api_url(config);
     let client = reqwest::Client::new();
     if let Ok(res) = client.get(&adapter_api).send().await
         && res.status().is_success()
     {
-        tokio::task::spawn(crate::adapter::register_mdns(client, adapter_api, *port));
-        return Ok(());
+        let task = tokio::task::spawn(crate::adapter::register_mdns(
+            client,
+            adapter_api,
+            *port,
+            tls_fingerprint.map(str::to_string),
+        ));
+        return Ok(MdnsHandle::Adapter(task));
     }
 
+    let mut daemons = Vec::new();
+
     let network_interfaces = local_ip_address::list_afinet_netifas().expect("afinet list failed");
     for (_, ip) in network_interfaces.iter() {
         if ip.is_loopback() || ip.is_multicast() || ip.is_unspecified() || ip.is_ipv6() {
@@ -24,11 +59,13 @@ pub async fn register_mdns(port: &u16) -> Result<()> {
         let mdns = ServiceDaemon::new()?;
 
         let ip = ip.to_string();
-        let properties = if std::env::var("NO_TLS").is_ok() {
-            [("ws", format!("ws://{ip}:{port}"))]
+        let mut properties = Vec::new();
+        if let Some(fingerprint) = tls_fingerprint {
+            properties.push(("ws", format!("wss://{ip}:{port}")));
+            properties.push((FINGERPRINT_PROPERTY, fingerprint.to_string()));
         } else {
-            [("ws", format!("wss://{ip}:{port}"))]
-        };
+            properties.push(("ws", format!("ws://{ip}:{port}")));
+        }
 
         let my_service = ServiceInfo::new(
             SERVICE_TYPE,
@@ -38,8 +75,10 @@ pub async fn register_mdns(port: &u16) -> Result<()> {
             *port,
             &properties[..],
         )?;
+        let fullname = my_service.get_fullname().to_string();
         mdns.register(my_service)?;
+        daemons.push((mdns, fullname));
     }
 
-    Ok(())
+    Ok(MdnsHandle::Daemons(daemons))
 }
@@ -0,0 +1,136 @@
+use crate::updater::Version;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct PinsFile {
+    /// esp_id -> version
+    #[serde(default)]
+    devices: HashMap<u32, String>,
+
+    /// hardware -> version
+    #[serde(default)]
+    hardware: HashMap<String, String>,
+}
+
+/// Firmware versions pinned per device or hardware type (pushed even if older).
+/// Persisted to disk, device pin takes precedence over hardware pin.
+#[derive(Debug)]
+pub struct FirmwarePins {
+    path: PathBuf,
+    inner: PinsFile,
+}
+
+impl FirmwarePins {
+    pub async fn load(path: PathBuf) -> Self {
+        let inner = Self::read(&path).await.unwrap_or_else(|e| {
+            tracing::error!("Firmware pins parse error (starting empty): {e:?}");
+            PinsFile::default()
+        });
+
+        Self { path, inner }
+    }
+
+    async fn read(path: &PathBuf) -> Result<PinsFile> {
+        match tokio::fs::read(path).await {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(PinsFile::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Reload pins edited by hand (SIGHUP)
+    pub async fn reload(&mut self) -> Result<()> {
+        self.inner = Self::read(&self.path).await?;
+        Ok(())
+    }
+
+    pub fn get(&self, esp_id: u32, hw: &str) -> Option<&str> {
+        self.inner
+            .devices
+            .get(&esp_id)
+            .or_else(|| self.inner.hardware.get(hw))
+            .map(|v| v.as_str())
+    }
+
+    /// Version is pinned for hardware or any device (device hardware isn't known)
+    pub fn is_pinned(&self, hw: &str, version: &Version) -> bool {
+        self.inner
+            .devices
+            .values()
+            .chain(self.inner.hardware.get(hw))
+            .any(|pin| Version::from_str(pin) == *version)
+    }
+
+    /// Set (or remove if version is None) device pin
+    pub async fn set_device(&mut self, esp_id: u32, version: Option<String>) -> Result<()> {
+        let mut inner = self.inner.clone();
+        match version {
+            Some(version) => inner.devices.insert(esp_id, version),
+            None => inner.devices.remove(&esp_id),
+        };
+
+        self.save(inner).await
+    }
+
+    /// Set (or remove if version is None) hardware pin
+    pub async fn set_hardware(&mut self, hw: String, version: Option<String>) -> Result<()> {
+        let mut inner = self.inner.clone();
+        match version {
+            Some(version) => inner.hardware.insert(hw, version),
+            None => inner.hardware.remove(&hw),
+        };
+
+        self.save(inner).await
+    }
+
+    /// Pins are changed in memory only if they were saved
+    async fn save(&mut self, inner: PinsFile) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        crate::fs_util::write_atomic(&self.path, &serde_json::to_vec_pretty(&inner)?, false)
+            .await?;
+        self.inner = inner;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[tokio::test]
+    async fn pins_persist() {
+        let dir = TempDir::new("pins");
+        let path = dir.join("pins.json");
+
+        let mut pins = FirmwarePins::load(path.clone()).await;
+        pins.set_hardware("v3".to_string(), Some("v3.0.0".to_string()))
+            .await
+            .unwrap();
+        pins.set_device(0x1234, Some("v3.1.0".to_string()))
+            .await
+            .unwrap();
+
+        let mut pins = FirmwarePins::load(path.clone()).await;
+        assert_eq!(pins.get(0x1234, "v3"), Some("v3.1.0"));
+        assert_eq!(pins.get(0x5678, "v3"), Some("v3.0.0"));
+        assert_eq!(pins.get(0x5678, "v2"), None);
+
+        pins.set_device(0x1234, None).await.unwrap();
+        assert_eq!(pins.get(0x1234, "v3"), Some("v3.0.0"));
+
+        // failed save doesn't change pins
+        let mut pins = FirmwarePins::load(path.join("not_a_dir.json")).await;
+        assert!(
+            pins.set_device(0x1234, Some("v3.1.0".to_string()))
+                .await
+                .is_err()
+        );
+        assert_eq!(pins.get(0x1234, "v3"), None);
+    }
+}
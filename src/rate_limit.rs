@@ -0,0 +1,141 @@
+use crate::structs::TimerPacketInner;
+use std::{collections::HashMap, time::Instant};
+
+/// All packets from single device (burst, refill per second)
+const DEVICE_LIMIT: (u32, f64) = (30, 10.0);
+
+/// Error sent to device (as `ApiError`) when request is throttled
+pub const THROTTLED_ERROR: &str = "Too many requests";
+
+/// Per packet type limits, only for packets causing backend round trip
+fn packet_limit(packet: &TimerPacketInner) -> Option<(&'static str, u32, f64)> {
+    match packet {
+        TimerPacketInner::CardInfoRequest { .. } => Some(("card_info_request", 5, 1.0)),
+        TimerPacketInner::Solve { .. } => Some(("solve", 5, 0.5)),
+        TimerPacketInner::Battery { .. } => Some(("battery", 2, 0.1)),
+        TimerPacketInner::Add { .. } => Some(("add", 2, 0.1)),
+        TimerPacketInner::TestAck(_) => Some(("test_ack", 20, 10.0)),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, refill_per_sec: f64, now: Instant) -> Self {
+        Self {
+            capacity: capacity as f64,
+            refill_per_sec,
+            tokens: capacity as f64,
+            last_refill: now,
+        }
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RateLimit {
+    Allowed,
+
+    /// Allowed after this many throttled packets
+    Recovered(u32),
+
+    /// Throttled, this many packets in a row (including this one)
+    Throttled(u32),
+}
+
+/// Per-connection packet rate limiter
+#[derive(Debug)]
+pub struct RateLimiter {
+    device: TokenBucket,
+    packets: HashMap<&'static str, TokenBucket>,
+    throttled: u32,
+}
+
+impl RateLimiter {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            device: TokenBucket::new(DEVICE_LIMIT.0, DEVICE_LIMIT.1, now),
+            packets: HashMap::new(),
+            throttled: 0,
+        }
+    }
+
+    pub fn check(&mut self, packet: &TimerPacketInner, now: Instant) -> RateLimit {
+        let mut allowed = self.device.try_take(now);
+        if allowed && let Some((kind, capacity, refill_per_sec)) = packet_limit(packet) {
+            allowed = self
+                .packets
+                .entry(kind)
+                .or_insert_with(|| TokenBucket::new(capacity, refill_per_sec, now))
+                .try_take(now);
+        }
+
+        if !allowed {
+            self.throttled += 1;
+            return RateLimit::Throttled(self.throttled);
+        }
+
+        match std::mem::take(&mut self.throttled) {
+            0 => RateLimit::Allowed,
+            throttled => RateLimit::Recovered(throttled),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn card_info_flood() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(now);
+        let packet = TimerPacketInner::CardInfoRequest {
+            card_id: 1,
+            is_competitor: true,
+            attendance_device: None,
+            sign_key: 0,
+            mac: None,
+            nonce: None,
+        };
+
+        for _ in 0..5 {
+            assert_eq!(limiter.check(&packet, now), RateLimit::Allowed);
+        }
+        assert_eq!(limiter.check(&packet, now), RateLimit::Throttled(1));
+        assert_eq!(limiter.check(&packet, now), RateLimit::Throttled(2));
+
+        // other packet types are still allowed
+        let battery = TimerPacketInner::Battery {
+            level: None,
+            voltage: None,
+        };
+        assert_eq!(limiter.check(&battery, now), RateLimit::Recovered(2));
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.check(&packet, later), RateLimit::Allowed);
+        assert_eq!(limiter.check(&packet, later), RateLimit::Throttled(1));
+    }
+}
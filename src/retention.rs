@@ -0,0 +1,329 @@
+use crate::{
+    firmware_catalogue::{CatalogueEntry, FirmwareCatalogue},
+    pins::FirmwarePins,
+    signature,
+    structs::SharedAppState,
+};
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+/// Subdirectory of `firmware_dir` for archived images (not indexed)
+pub const ARCHIVE_DIR: &str = "archive";
+
+/// File names deleted by retention (in `firmware_dir`), they aren't downloaded again
+pub const DELETED_FILE: &str = ".retention_deleted.json";
+
+/// What happens with images over `firmware_keep`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionMode {
+    /// Move to `firmware_dir/archive`
+    #[default]
+    Archive,
+    Delete,
+
+    /// Only log images that would be removed
+    DryRun,
+}
+
+impl std::str::FromStr for RetentionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "archive" => Ok(Self::Archive),
+            "delete" => Ok(Self::Delete),
+            "dry_run" => Ok(Self::DryRun),
+            _ => Err(anyhow!("expected archive, delete or dry_run")),
+        }
+    }
+}
+
+impl RetentionMode {
+    pub fn action(&self) -> &'static str {
+        match self {
+            Self::Archive => "Archived",
+            Self::Delete => "Deleted",
+            Self::DryRun => "Would remove",
+        }
+    }
+}
+
+/// Images over retention: all but `keep` newest per (hw, firmware, channel).
+/// Pinned versions are always kept.
+pub fn plan(
+    catalogue: &FirmwareCatalogue,
+    keep: usize,
+    pins: &FirmwarePins,
+) -> Vec<CatalogueEntry> {
+    let mut removed: Vec<CatalogueEntry> = catalogue
+        .groups()
+        .flat_map(|entries| entries.iter().skip(keep))
+        .filter(|entry| !pins.is_pinned(&entry.hw, &entry.version))
+        .cloned()
+        .collect();
+
+    removed.sort_by(|a, b| a.path.cmp(&b.path));
+    removed
+}
+
+/// Report line of image over retention (logged by server, printed by `fw gc`)
+pub fn report_line(mode: RetentionMode, entry: &CatalogueEntry) -> String {
+    format!(
+        "{} firmware {} ({}/{} {})",
+        mode.action(),
+        entry.path.display(),
+        entry.hw,
+        entry.firmware,
+        entry.version.inner_version()
+    )
+}
+
+/// Archive or delete image (with its detached signature).
+/// Names of deleted files are added to [`DELETED_FILE`].
+pub async fn apply(firmware_dir: &Path, entry: &CatalogueEntry, mode: RetentionMode) -> Result<()> {
+    let files = [entry.path.clone(), signature::detached_path(&entry.path)];
+    match mode {
+        RetentionMode::Archive => {
+            let archive_dir = firmware_dir.join(ARCHIVE_DIR);
+            tokio::fs::create_dir_all(&archive_dir).await?;
+            let target = archive_target(&archive_dir, &entry.path).await?;
+            let targets = [target.clone(), signature::detached_path(&target)];
+            for (path, target) in files.iter().zip(targets) {
+                if tokio::fs::try_exists(path).await? {
+                    tokio::fs::rename(path, target).await?;
+                }
+            }
+        }
+        RetentionMode::Delete => {
+            let names = files
+                .iter()
+                .filter_map(|path| Some(path.file_name()?.to_str()?.to_string()));
+            let mut deleted = load_deleted(firmware_dir).await;
+            deleted.extend(names);
+            crate::fs_util::write_atomic(
+                &firmware_dir.join(DELETED_FILE),
+                &serde_json::to_vec_pretty(&deleted)?,
+                false,
+            )
+            .await?;
+
+            for path in files {
+                match tokio::fs::remove_file(&path).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        RetentionMode::DryRun => {}
+    }
+
+    Ok(())
+}
+
+/// Archive path for image, archived image with the same name is never replaced
+/// (`name.bin`, `name.1.bin`, `name.2.bin`, ...)
+async fn archive_target(archive_dir: &Path, image: &Path) -> Result<PathBuf> {
+    let name = image
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid image path: {image:?}"))?;
+    let stem = image.file_stem().unwrap_or(name).to_string_lossy();
+    let extension = image.extension().map(|e| e.to_string_lossy());
+
+    let mut target = archive_dir.join(name);
+    for i in 1.. {
+        if !tokio::fs::try_exists(&target).await?
+            && !tokio::fs::try_exists(signature::detached_path(&target)).await?
+        {
+            break;
+        }
+
+        target = archive_dir.join(match &extension {
+            Some(extension) => format!("{stem}.{i}.{extension}"),
+            None => format!("{stem}.{i}"),
+        });
+    }
+
+    Ok(target)
+}
+
+/// Path of image archived by retention
+pub fn archived_path(firmware_dir: &Path, name: &str) -> PathBuf {
+    firmware_dir.join(ARCHIVE_DIR).join(name)
+}
+
+/// Names of files deleted by retention (empty if nothing was deleted yet)
+pub async fn load_deleted(firmware_dir: &Path) -> BTreeSet<String> {
+    match tokio::fs::read(firmware_dir.join(DELETED_FILE)).await {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            tracing::error!("Deleted firmware list parse error: {e:?}");
+            BTreeSet::new()
+        }),
+        Err(_) => BTreeSet::new(),
+    }
+}
+
+/// Release asset was removed by retention (archived or deleted), so it isn't downloaded again
+pub async fn is_removed(firmware_dir: &Path, name: &str, deleted: &BTreeSet<String>) -> bool {
+    deleted.contains(name)
+        || tokio::fs::try_exists(archived_path(firmware_dir, name))
+            .await
+            .unwrap_or(false)
+}
+
+/// Apply retention from config (disabled if `firmware_keep` isn't set),
+/// catalogue is refreshed if any image was removed. Returns logged report.
+pub async fn run(state: &SharedAppState) -> Result<Vec<String>> {
+    let config = state.config.read().await;
+    let (Some(keep), mode, firmware_dir) = (
+        config.firmware_keep,
+        config.firmware_retention,
+        config.firmware_dir.clone(),
+    ) else {
+        return Ok(Vec::new());
+    };
+    drop(config);
+
+    let removed = {
+        let catalogue = state.firmware_catalogue.read().await;
+        let pins = state.firmware_pins.lock().await;
+        plan(&catalogue, keep, &pins)
+    };
+
+    let mut report = Vec::with_capacity(removed.len());
+    for entry in &removed {
+        apply(&firmware_dir, entry, mode).await?;
+        let line = report_line(mode, entry);
+        tracing::info!("{line}");
+        report.push(line);
+    }
+
+    if !removed.is_empty() && mode != RetentionMode::DryRun {
+        crate::firmware_catalogue::refresh(state).await?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{TempDir, image},
+        updater::Version,
+    };
+
+    #[tokio::test]
+    async fn retention() {
+        let tmp = TempDir::new("retention");
+        let dir = tmp.path();
+        for (name, hw, version) in [
+            ("a.bin", "v3", "v3.0.0"),
+            ("b.bin", "v3", "v3.1.0"),
+            ("c.bin", "v3", "v3.2.0"),
+            ("d.bin", "v3", "v3.3.0"),
+            ("e.bin", "v3", "D1714320292"),
+            ("f.bin", "v2", "v2.9.0"),
+        ] {
+            std::fs::write(dir.join(name), image(hw, "STATION", version)).unwrap();
+        }
+        std::fs::write(dir.join("b.bin.sig"), [0; signature::SIGNATURE_SIZE]).unwrap();
+
+        let mut catalogue = FirmwareCatalogue::default();
+        catalogue.refresh(dir, true).await.unwrap();
+        let mut pins = FirmwarePins::load(dir.join("data").join("pins.json")).await;
+        pins.set_device(0x1234, Some("v3.0.0".to_string()))
+            .await
+            .unwrap();
+
+        let removed = plan(&catalogue, 2, &pins);
+        let paths: Vec<_> = removed.iter().map(|e| e.path.clone()).collect();
+        assert_eq!(paths, vec![dir.join("b.bin")]);
+
+        apply(dir, &removed[0], RetentionMode::Archive)
+            .await
+            .unwrap();
+        assert!(!dir.join("b.bin").exists());
+        assert!(archived_path(dir, "b.bin").exists());
+        assert!(archived_path(dir, "b.bin.sig").exists());
+
+        catalogue.refresh(dir, true).await.unwrap();
+        assert!(plan(&catalogue, 2, &pins).is_empty());
+        let removed = plan(&catalogue, 1, &pins);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].version, Version::from_str("v3.2.0"));
+
+        apply(dir, &removed[0], RetentionMode::Delete)
+            .await
+            .unwrap();
+        assert!(!dir.join("c.bin").exists());
+
+        // deleted and archived release assets aren't downloaded again
+        let deleted = load_deleted(dir).await;
+        assert!(deleted.contains("c.bin") && deleted.contains("c.bin.sig"));
+        assert!(is_removed(dir, "c.bin", &deleted).await);
+        assert!(is_removed(dir, "b.bin", &deleted).await);
+        assert!(is_removed(dir, "b.bin.sig", &deleted).await);
+        assert!(!is_removed(dir, "g.bin", &deleted).await);
+
+        // image with the same name as archived one doesn't replace it
+        std::fs::write(dir.join("b.bin"), image("v3", "STATION", "v3.1.0")).unwrap();
+        std::fs::write(dir.join("b.bin.sig"), [1; signature::SIGNATURE_SIZE]).unwrap();
+        catalogue.refresh(dir, true).await.unwrap();
+        let removed = plan(&catalogue, 1, &pins);
+        assert_eq!(removed.len(), 1);
+        apply(dir, &removed[0], RetentionMode::Archive)
+            .await
+            .unwrap();
+        let archive = dir.join(ARCHIVE_DIR);
+        assert_eq!(
+            std::fs::read(archive.join("b.bin.sig")).unwrap(),
+            [0; signature::SIGNATURE_SIZE]
+        );
+        assert_eq!(
+            std::fs::read(archive.join("b.1.bin.sig")).unwrap(),
+            [1; signature::SIGNATURE_SIZE]
+        );
+        assert!(archive.join("b.1.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn dry_run_report() {
+        let tmp = TempDir::new("dry_run");
+        let dir = tmp.path();
+        std::fs::write(dir.join("a.bin"), image("v3", "STATION", "v3.0.0")).unwrap();
+        std::fs::write(dir.join("b.bin"), image("v3", "STATION", "v3.1.0")).unwrap();
+
+        let config = crate::config::Config {
+            dev: true,
+            firmware_dir: dir.to_path_buf(),
+            data_dir: dir.join("data"),
+            firmware_keep: Some(1),
+            firmware_retention: RetentionMode::DryRun,
+            ..Default::default()
+        };
+        let state = SharedAppState::new(config, None).await;
+        crate::firmware_catalogue::refresh(&state).await.unwrap();
+
+        assert_eq!(
+            run(&state).await.unwrap(),
+            [format!(
+                "Would remove firmware {} (v3/STATION 3.0.0)",
+                dir.join("a.bin").display()
+            )]
+        );
+        assert!(dir.join("a.bin").exists());
+        assert!(!dir.join(ARCHIVE_DIR).exists());
+        assert!(load_deleted(dir).await.is_empty());
+        let catalogue = state.firmware_catalogue.read().await;
+        assert!(
+            catalogue
+                .find("v3", "STATION", &Version::from_str("v3.0.0"))
+                .is_some()
+        );
+    }
+}
@@ -0,0 +1,611 @@
+use crate::{http::EspConnectInfo, structs::SharedAppState, updater::Version};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+/// Device that started update must reconnect (with any version) within this time
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Every canary of rollout model must start update within this time after rollout starts (or resumes)
+const CANARY_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct RolloutSettings {
+    /// Devices updated first (rollout is disabled if empty)
+    pub canaries: Vec<u32>,
+
+    /// Cumulative percentages of devices updated in following waves
+    pub waves: Vec<u8>,
+
+    /// How long updated devices must stay connected before next wave starts
+    pub soak: Duration,
+}
+
+/// Device state in rollout (times are unix ms, so they survive restart)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DeviceState {
+    Updating { since: i64 },
+    Confirmed { since: i64 },
+    Healthy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Rollout {
+    /// `hw/firmware`
+    model: String,
+    version: Version,
+    stage: usize,
+    devices: HashMap<u32, DeviceState>,
+    halted: Option<String>,
+
+    /// Unix ms of start (or resume), see [`CANARY_TIMEOUT`]
+    #[serde(default)]
+    started: i64,
+}
+
+/// Last seen canary device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Canary {
+    model: String,
+    version: Version,
+
+    #[serde(skip)]
+    connected: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RolloutsFile {
+    #[serde(default)]
+    rollouts: HashMap<String, Rollout>,
+
+    #[serde(default)]
+    canaries: HashMap<u32, Canary>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RolloutEvent {
+    Advanced { key: String, stage: usize },
+    Halted { key: String, reason: String },
+}
+
+/// Staged firmware rollouts, keyed by `hw/firmware/version`.
+/// Persisted to disk, so restart doesn't reset stages (see [`Rollouts::flush`]).
+#[derive(Debug)]
+pub struct Rollouts {
+    path: PathBuf,
+    inner: RolloutsFile,
+
+    /// Changed since last save
+    dirty: bool,
+}
+
+/// Stable device bucket (0..100) for percentage waves
+fn bucket(esp_id: u32) -> u8 {
+    (esp_id.wrapping_mul(2654435761).rotate_right(16) % 100) as u8
+}
+
+fn model(hw: &str, firmware: &str) -> String {
+    format!("{hw}/{firmware}")
+}
+
+fn elapsed(since: i64, now: i64) -> Duration {
+    Duration::from_millis(now.saturating_sub(since).max(0) as u64)
+}
+
+impl Rollout {
+    fn eligible(&self, settings: &RolloutSettings, esp_id: u32) -> bool {
+        if settings.canaries.contains(&esp_id) {
+            return true;
+        }
+
+        match self.stage {
+            0 => false,
+            stage => settings
+                .waves
+                .get(stage - 1)
+                .or(settings.waves.last())
+                .is_some_and(|percentage| bucket(esp_id) < *percentage),
+        }
+    }
+
+    /// All devices updated so far reconnected and stayed healthy,
+    /// first stage also needs every canary of this hw/firmware (if it has any)
+    fn stage_passed(&self, settings: &RolloutSettings, canaries: &[u32], now: i64) -> bool {
+        let soaked = |state: &DeviceState| match state {
+            DeviceState::Healthy => true,
+            DeviceState::Confirmed { since } => elapsed(*since, now) >= settings.soak,
+            DeviceState::Updating { .. } => false,
+        };
+
+        if self.stage == 0
+            && !canaries
+                .iter()
+                .all(|id| self.devices.get(id).is_some_and(soaked))
+        {
+            return false;
+        }
+
+        self.devices.values().all(soaked)
+    }
+
+    fn halt(&mut self, key: &str, reason: String, events: &mut Vec<RolloutEvent>) {
+        if self.halted.is_none() {
+            self.halted = Some(reason.clone());
+            events.push(RolloutEvent::Halted {
+                key: key.to_string(),
+                reason,
+            });
+        }
+    }
+}
+
+impl Rollouts {
+    pub async fn load(path: PathBuf) -> Self {
+        let inner = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                tracing::error!("Rollouts parse error (starting empty): {e:?}");
+                RolloutsFile::default()
+            }),
+            Err(_) => RolloutsFile::default(),
+        };
+
+        Self {
+            path,
+            inner,
+            dirty: false,
+        }
+    }
+
+    pub fn key(hw: &str, firmware: &str, version: &Version) -> String {
+        format!("{}/{}", model(hw, firmware), version.inner_version())
+    }
+
+    /// Configured canaries of this hw/firmware (or not seen yet)
+    fn canaries(&self, settings: &RolloutSettings, model: &str) -> Vec<u32> {
+        settings
+            .canaries
+            .iter()
+            .filter(|id| {
+                self.inner
+                    .canaries
+                    .get(id)
+                    .is_none_or(|canary| canary.model == model)
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Check if device can be updated to this version now.
+    /// Device is tracked only after transfer starts ([`Rollouts::begin`]).
+    pub fn allow(
+        &mut self,
+        settings: &RolloutSettings,
+        hw: &str,
+        firmware: &str,
+        version: &Version,
+        esp_id: u32,
+        now: i64,
+    ) -> bool {
+        if settings.canaries.is_empty() {
+            return true;
+        }
+
+        let key = Self::key(hw, firmware, version);
+        if !self.inner.rollouts.contains_key(&key) {
+            // newer build replaces rollout of older one
+            let model = model(hw, firmware);
+            self.inner.rollouts.retain(|_, r| r.model != model);
+
+            // canaries already running this version count as updated
+            let devices = self
+                .inner
+                .canaries
+                .iter()
+                .filter(|(id, canary)| {
+                    settings.canaries.contains(id)
+                        && canary.connected
+                        && canary.model == model
+                        && canary.version == *version
+                })
+                .map(|(id, _)| (*id, DeviceState::Confirmed { since: now }))
+                .collect();
+
+            tracing::info!("Starting staged rollout of {key}");
+            if self.canaries(settings, &model).is_empty() {
+                tracing::warn!("No canary configured for {model}, {key} starts with first wave");
+            }
+            self.inner.rollouts.insert(
+                key.clone(),
+                Rollout {
+                    model,
+                    version: version.clone(),
+                    stage: 0,
+                    devices,
+                    halted: None,
+                    started: now,
+                },
+            );
+            self.dirty = true;
+        }
+
+        let rollout = &self.inner.rollouts[&key];
+        rollout.halted.is_none() && rollout.eligible(settings, esp_id)
+    }
+
+    /// Firmware transfer to device started
+    pub fn begin(&mut self, hw: &str, firmware: &str, version: &Version, esp_id: u32, now: i64) {
+        let key = Self::key(hw, firmware, version);
+        if let Some(rollout) = self.inner.rollouts.get_mut(&key) {
+            rollout
+                .devices
+                .insert(esp_id, DeviceState::Updating { since: now });
+            self.dirty = true;
+        }
+    }
+
+    /// Transfer didn't complete, device stays on its version (and can retry later)
+    pub fn cancel(&mut self, hw: &str, firmware: &str, version: &Version, esp_id: u32) {
+        let key = Self::key(hw, firmware, version);
+        if let Some(rollout) = self.inner.rollouts.get_mut(&key)
+            && let Some(DeviceState::Updating { .. }) = rollout.devices.get(&esp_id)
+        {
+            rollout.devices.remove(&esp_id);
+            self.dirty = true;
+        }
+    }
+
+    /// Device connected, check if it's running version it was updated to
+    /// (canaries already running rollout version are confirmed too)
+    pub fn on_connect(
+        &mut self,
+        settings: &RolloutSettings,
+        esp_connect_info: &EspConnectInfo,
+        now: i64,
+    ) -> Vec<RolloutEvent> {
+        let mut events = Vec::new();
+        let esp_id = esp_connect_info.id;
+        let model = model(&esp_connect_info.hw, &esp_connect_info.firmware);
+        let version = Version::from_str(&esp_connect_info.version);
+
+        let canary = settings.canaries.contains(&esp_id);
+        if canary {
+            self.inner.canaries.insert(
+                esp_id,
+                Canary {
+                    model: model.clone(),
+                    version: version.clone(),
+                    connected: true,
+                },
+            );
+            self.dirty = true;
+        }
+
+        for (key, rollout) in &mut self.inner.rollouts {
+            match rollout.devices.get_mut(&esp_id) {
+                Some(state @ DeviceState::Updating { .. }) => {
+                    if version == rollout.version {
+                        *state = DeviceState::Confirmed { since: now };
+                    } else {
+                        let reason = format!("{esp_id:X} reconnected with version {version}");
+                        rollout.halt(key, reason, &mut events);
+                    }
+                    self.dirty = true;
+                }
+                None if canary && rollout.model == model && version == rollout.version => {
+                    rollout
+                        .devices
+                        .insert(esp_id, DeviceState::Confirmed { since: now });
+                    self.dirty = true;
+                }
+                _ => {}
+            }
+        }
+
+        events
+    }
+
+    /// Device disconnected, updated devices must stay connected for soak period
+    pub fn on_disconnect(
+        &mut self,
+        settings: &RolloutSettings,
+        esp_id: u32,
+        now: i64,
+    ) -> Vec<RolloutEvent> {
+        let mut events = Vec::new();
+
+        if let Some(canary) = self.inner.canaries.get_mut(&esp_id) {
+            canary.connected = false;
+        }
+
+        for (key, rollout) in &mut self.inner.rollouts {
+            let Some(state) = rollout.devices.get_mut(&esp_id) else {
+                continue;
+            };
+
+            if let DeviceState::Confirmed { since } = *state {
+                if elapsed(since, now) >= settings.soak {
+                    *state = DeviceState::Healthy;
+                } else {
+                    let reason = format!("{esp_id:X} disconnected during soak period");
+                    rollout.halt(key, reason, &mut events);
+                }
+                self.dirty = true;
+            }
+        }
+
+        events
+    }
+
+    /// Check timeouts and advance stages
+    pub fn poll(&mut self, settings: &RolloutSettings, now: i64) -> Vec<RolloutEvent> {
+        let mut events = Vec::new();
+
+        let keys: Vec<String> = self.inner.rollouts.keys().cloned().collect();
+        for key in keys {
+            let rollout = &self.inner.rollouts[&key];
+            let canaries = self.canaries(settings, &rollout.model);
+            let rollout = self.inner.rollouts.get_mut(&key).expect("Key from map");
+            if rollout.halted.is_some() {
+                continue;
+            }
+
+            let timed_out = rollout.devices.iter().find(|(_, state)| {
+                matches!(state, DeviceState::Updating { since } if elapsed(*since, now) > RECONNECT_TIMEOUT)
+            });
+
+            if let Some((esp_id, _)) = timed_out {
+                let reason = format!("{esp_id:X} didn't reconnect after update");
+                rollout.halt(&key, reason, &mut events);
+                self.dirty = true;
+                continue;
+            }
+
+            let missing_canary = canaries.iter().find(|id| !rollout.devices.contains_key(id));
+            if rollout.stage == 0
+                && elapsed(rollout.started, now) > CANARY_TIMEOUT
+                && let Some(esp_id) = missing_canary
+            {
+                let reason = format!(
+                    "canary {esp_id:X} wasn't updated within {} minutes",
+                    CANARY_TIMEOUT.as_secs() / 60
+                );
+                rollout.halt(&key, reason, &mut events);
+                self.dirty = true;
+                continue;
+            }
+
+            if rollout.stage < settings.waves.len()
+                && rollout.stage_passed(settings, &canaries, now)
+            {
+                rollout.stage += 1;
+                self.dirty = true;
+                events.push(RolloutEvent::Advanced {
+                    key: key.clone(),
+                    stage: rollout.stage,
+                });
+            }
+        }
+
+        events
+    }
+
+    /// Resume halted rollouts (from their current stage), requested by backend
+    pub fn resume(&mut self, now: i64) {
+        for (key, rollout) in &mut self.inner.rollouts {
+            if let Some(reason) = rollout.halted.take() {
+                tracing::info!("Resuming rollout of {key} (halted: {reason})");
+                rollout
+                    .devices
+                    .retain(|_, state| !matches!(state, DeviceState::Updating { .. }));
+                rollout.started = now;
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Save state if it changed since last save
+    pub async fn flush(&mut self) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        crate::fs_util::write_atomic(&self.path, &serde_json::to_vec(&self.inner)?, false).await?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+pub fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Save rollouts state (if changed)
+pub async fn flush(state: &SharedAppState) {
+    if let Err(e) = state.rollouts.lock().await.flush().await {
+        tracing::error!("Rollouts save error: {e:?}");
+    }
+}
+
+/// Save rollouts, log events and start next wave (if any stage advanced)
+pub async fn handle_events(state: &SharedAppState, events: Vec<RolloutEvent>) {
+    flush(state).await;
+
+    let mut advanced = false;
+    for event in events {
+        match event {
+            RolloutEvent::Advanced { key, stage } => {
+                tracing::info!("Rollout of {key} advanced to stage {stage}");
+                advanced = true;
+            }
+            RolloutEvent::Halted { key, reason } => {
+                tracing::error!("Rollout of {key} halted: {reason}");
+            }
+        }
+    }
+
+    if advanced {
+        _ = state.build_broadcast().await;
+    }
+}
+
+/// Called periodically (watchers)
+pub async fn poll(state: &SharedAppState) {
+    let settings = state.config.read().await.rollout();
+    let events = state.rollouts.lock().await.poll(&settings, now_ms());
+    handle_events(state, events).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, esp_info};
+
+    #[tokio::test]
+    async fn staged_rollout() {
+        let settings = RolloutSettings {
+            canaries: vec![1],
+            waves: vec![50, 100],
+            soak: Duration::from_secs(60),
+        };
+        let version = Version::from_str("v3.1.0");
+        let wave_device = (2..).find(|id| bucket(*id) < 50).unwrap();
+        let last_device = (2..).find(|id| bucket(*id) >= 50).unwrap();
+        let dir = TempDir::new("rollouts");
+        let path = dir.join("rollouts.json");
+
+        let now = 1_000_000;
+        let mut rollouts = Rollouts::load(path.clone()).await;
+        assert!(!rollouts.allow(&settings, "v3", "STATION", &version, wave_device, now));
+        assert!(rollouts.allow(&settings, "v3", "STATION", &version, 1, now));
+
+        // allowed device isn't tracked until transfer starts (and after it failed)
+        assert!(
+            rollouts
+                .on_connect(&settings, &esp_info(1, "v3.0.0"), now)
+                .is_empty()
+        );
+        rollouts.begin("v3", "STATION", &version, 1, now);
+        rollouts.cancel("v3", "STATION", &version, 1);
+        assert!(
+            rollouts
+                .on_connect(&settings, &esp_info(1, "v3.0.0"), now)
+                .is_empty()
+        );
+        rollouts.begin("v3", "STATION", &version, 1, now);
+        assert!(rollouts.poll(&settings, now).is_empty());
+
+        // canary reconnects with new version and stays healthy for soak period
+        assert!(
+            rollouts
+                .on_connect(&settings, &esp_info(1, "v3.1.0"), now)
+                .is_empty()
+        );
+        assert!(rollouts.poll(&settings, now + 30_000).is_empty());
+
+        // state survives restart
+        rollouts.flush().await.unwrap();
+        let mut rollouts = Rollouts::load(path.clone()).await;
+
+        let now = now + 61_000;
+        assert_eq!(
+            rollouts.poll(&settings, now),
+            vec![RolloutEvent::Advanced {
+                key: "v3/STATION/3.1.0".to_string(),
+                stage: 1
+            }]
+        );
+
+        assert!(!rollouts.allow(&settings, "v3", "STATION", &version, last_device, now));
+        assert!(rollouts.allow(&settings, "v3", "STATION", &version, wave_device, now));
+        rollouts.begin("v3", "STATION", &version, wave_device, now);
+
+        // failed update halts rollout
+        let events = rollouts.on_connect(&settings, &esp_info(wave_device, "v3.0.0"), now);
+        assert!(matches!(events[..], [RolloutEvent::Halted { .. }]));
+        assert!(!rollouts.allow(&settings, "v3", "STATION", &version, wave_device, now));
+
+        // disabled without canaries
+        let no_canaries = RolloutSettings {
+            canaries: Vec::new(),
+            ..settings.clone()
+        };
+        assert!(rollouts.allow(&no_canaries, "v3", "STATION", &version, last_device, now));
+
+        // canaries already running new build are confirmed, every canary is required
+        let settings = RolloutSettings {
+            canaries: vec![1, 0xC2, 0xC3],
+            ..settings
+        };
+        let mut other = esp_info(0xC3, "v2.9.0");
+        other.hw = "v2".to_string();
+        assert!(rollouts.on_connect(&settings, &other, now).is_empty());
+        assert!(
+            rollouts
+                .on_connect(&settings, &esp_info(1, "v3.2.0"), now)
+                .is_empty()
+        );
+
+        let version = Version::from_str("v3.2.0");
+        assert!(!rollouts.allow(&settings, "v3", "STATION", &version, wave_device, now));
+        assert!(rollouts.poll(&settings, now + 61_000).is_empty());
+
+        assert!(
+            rollouts
+                .on_connect(&settings, &esp_info(0xC2, "v3.2.0"), now)
+                .is_empty()
+        );
+        assert_eq!(
+            rollouts.poll(&settings, now + 61_000),
+            vec![RolloutEvent::Advanced {
+                key: "v3/STATION/3.2.0".to_string(),
+                stage: 1
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn canary_gating() {
+        let settings = RolloutSettings {
+            canaries: vec![1],
+            waves: vec![100],
+            soak: Duration::from_secs(60),
+        };
+        let dir = TempDir::new("canaries");
+        let mut rollouts = Rollouts::load(dir.join("rollouts.json")).await;
+        let now = 1_000_000;
+
+        // canary 1 is known to be STATION, DISPLAY rollout isn't gated by it
+        rollouts.on_connect(&settings, &esp_info(1, "v3.0.0"), now);
+        let version = Version::from_str("v3.1.0");
+        assert!(!rollouts.allow(&settings, "v3", "DISPLAY", &version, 2, now));
+        assert_eq!(
+            rollouts.poll(&settings, now),
+            vec![RolloutEvent::Advanced {
+                key: "v3/DISPLAY/3.1.0".to_string(),
+                stage: 1
+            }]
+        );
+        assert!(rollouts.allow(&settings, "v3", "DISPLAY", &version, 2, now));
+
+        // canary that isn't updated halts rollout (with reason), until backend resumes it
+        assert!(!rollouts.allow(&settings, "v3", "STATION", &version, 2, now));
+        assert!(rollouts.poll(&settings, now + 30 * 60_000).is_empty());
+        let now = now + 61 * 60_000;
+        assert_eq!(
+            rollouts.poll(&settings, now),
+            vec![RolloutEvent::Halted {
+                key: "v3/STATION/3.1.0".to_string(),
+                reason: "canary 1 wasn't updated within 60 minutes".to_string()
+            }]
+        );
+        assert!(!rollouts.allow(&settings, "v3", "STATION", &version, 1, now));
+
+        rollouts.resume(now);
+        assert!(rollouts.allow(&settings, "v3", "STATION", &version, 1, now));
+        assert!(rollouts.poll(&settings, now + 30 * 60_000).is_empty());
+    }
+}
@@ -0,0 +1,68 @@
+use crate::structs::SharedAppState;
+use anyhow::{Result, anyhow};
+use std::{collections::HashMap, path::Path};
+
+/// Parse local rooms mapping (TOML, room name -> list of esp ids)
+///
+/// ```toml
+/// "Main hall" = [0x1A2B3C4D, 0x11223344]
+/// ```
+pub fn parse_rooms(data: &str) -> Result<HashMap<u32, String>> {
+    let rooms: HashMap<String, Vec<u32>> = toml::from_str(data)?;
+
+    let mut devices = HashMap::new();
+    for (room, esp_ids) in rooms {
+        for esp_id in esp_ids {
+            if let Some(other) = devices.insert(esp_id, room.clone()) {
+                return Err(anyhow!(
+                    "Device {esp_id:X} assigned to multiple rooms (\"{other}\", \"{room}\")"
+                ));
+            }
+        }
+    }
+
+    Ok(devices)
+}
+
+pub async fn load_rooms(path: &Path) -> Result<HashMap<u32, String>> {
+    let data = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| anyhow!("Cannot read rooms file \"{}\": {e}", path.display()))?;
+
+    parse_rooms(&data).map_err(|e| anyhow!("Rooms file \"{}\" error: {e}", path.display()))
+}
+
+/// (Re)load local rooms mapping from `rooms_file` set in config
+pub async fn reload_rooms(state: &SharedAppState) -> Result<()> {
+    let rooms_file = state.config.read().await.rooms_file.clone();
+    let local_rooms = match rooms_file {
+        Some(path) => load_rooms(&path).await?,
+        None => HashMap::new(),
+    };
+
+    state.inner.write().await.local_rooms = local_rooms;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rooms_mapping() {
+        let rooms = parse_rooms(
+            r#"
+            "Main hall" = [0x1A2B3C4D, 2]
+            "Side room" = [3]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            rooms.get(&0x1A2B3C4D).map(|r| r.as_str()),
+            Some("Main hall")
+        );
+        assert_eq!(rooms.get(&3).map(|r| r.as_str()), Some("Side room"));
+        assert!(parse_rooms("a = [1]\nb = [1]").is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use crate::{mdns::MdnsHandle, structs::SharedAppState};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+const UPDATES_DRAIN_TIMEOUT: Duration = Duration::from_secs(20);
+const CONNECTIONS_DRAIN_TIMEOUT: Duration = Duration::from_secs(3);
+const UNIX_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Close frame reason sent to devices on shutdown
+pub const SHUTDOWN_REASON: &str = "server restarting";
+
+/// Increments counter for its lifetime (active connections, updates)
+#[derive(Debug)]
+pub struct ActiveGuard(Arc<AtomicUsize>);
+
+impl ActiveGuard {
+    pub fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub async fn graceful_shutdown(state: &SharedAppState, mdns: Option<MdnsHandle>) {
+    state.begin_shutdown();
+
+    if let Some(mdns) = mdns {
+        mdns.unregister();
+    }
+
+    _ = state.shutdown_broadcast().await;
+
+    let drained = wait_for_zero(&state.active_updates, UPDATES_DRAIN_TIMEOUT).await;
+    if !drained {
+        tracing::warn!("Firmware updates still in progress, aborting them!");
+        state.abort_updates();
+    }
+
+    if !wait_for_zero(&state.connections, CONNECTIONS_DRAIN_TIMEOUT).await {
+        tracing::warn!(
+            "{} devices still connected after shutdown timeout",
+            state.connections.load(Ordering::SeqCst)
+        );
+    }
+
+    if let Err(e) = state.battery_history.lock().await.flush().await {
+        tracing::error!("Battery history save error: {e:?}");
+    }
+
+    match crate::UNIX_SOCKET.flush(UNIX_FLUSH_TIMEOUT).await {
+        Ok(true) => {}
+        Ok(false) => tracing::warn!("Unix requests not flushed before shutdown timeout"),
+        Err(e) => tracing::error!("Unix flush error: {e:?}"),
+    }
+
+    tracing::info!("Shutdown completed!");
+}
+
+async fn wait_for_zero(counter: &AtomicUsize, timeout: Duration) -> bool {
+    let res = tokio::time::timeout(timeout, async {
+        while counter.load(Ordering::SeqCst) > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await;
+
+    res.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_counters() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        assert!(wait_for_zero(&counter, Duration::from_millis(10)).await);
+
+        let first = ActiveGuard::new(&counter);
+        let second = ActiveGuard::new(&counter);
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+
+        drop(first);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        assert!(!wait_for_zero(&counter, Duration::from_millis(250)).await);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            drop(second);
+        });
+        assert!(wait_for_zero(&counter, Duration::from_secs(2)).await);
+        assert_eq!(counter.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use std::path::{Path, PathBuf};
+
+/// Ed25519 signature size
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// Embedded signature layout: `image | signature (64 bytes) | magic`
+pub const SIGNATURE_MAGIC: &[u8; 8] = b"FKMSIGv1";
+
+/// Detached signature file extension (`firmware.bin` -> `firmware.bin.sig`)
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+/// Split image with embedded signature into image and signature
+pub fn split_embedded(data: &[u8]) -> (&[u8], Option<&[u8]>) {
+    let Some(rest) = data.strip_suffix(SIGNATURE_MAGIC) else {
+        return (data, None);
+    };
+
+    match rest.len().checked_sub(SIGNATURE_SIZE) {
+        Some(image_len) => (&rest[..image_len], Some(&rest[image_len..])),
+        None => (data, None),
+    }
+}
+
+/// Detached signature: raw 64 bytes or base64
+pub fn parse_detached(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() == SIGNATURE_SIZE {
+        return Ok(data.to_vec());
+    }
+
+    let text = core::str::from_utf8(data).map_err(|_| anyhow!("Invalid signature file"))?;
+    let signature = base64::prelude::BASE64_STANDARD.decode(text.trim())?;
+    if signature.len() != SIGNATURE_SIZE {
+        return Err(anyhow!("Invalid signature size: {}", signature.len()));
+    }
+
+    Ok(signature)
+}
+
+/// Public key (base64 of 32 raw ed25519 key bytes)
+pub fn parse_public_key(key: &str) -> Result<Vec<u8>> {
+    let key = base64::prelude::BASE64_STANDARD.decode(key.trim())?;
+    if key.len() != 32 {
+        return Err(anyhow!("Invalid public key size: {}", key.len()));
+    }
+
+    Ok(key)
+}
+
+pub fn detached_path(image: &Path) -> PathBuf {
+    let mut path = image.as_os_str().to_owned();
+    path.push(".");
+    path.push(SIGNATURE_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Read firmware image (without embedded signature) and its signature (embedded or detached)
+pub async fn load_image(path: &Path) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+    let mut data = tokio::fs::read(path).await?;
+    let (image, embedded) = split_embedded(&data);
+    let (image_len, embedded) = (image.len(), embedded.map(|s| s.to_vec()));
+    data.truncate(image_len);
+
+    if embedded.is_some() {
+        return Ok((data, embedded));
+    }
+
+    let detached = match tokio::fs::read(detached_path(path)).await {
+        Ok(signature) => Some(parse_detached(&signature)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok((data, detached))
+}
+
+/// Image has embedded or detached signature (without reading the image)
+pub async fn is_signed(path: &Path) -> Result<bool> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    if tokio::fs::try_exists(detached_path(path)).await? {
+        return Ok(true);
+    }
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    let tail_len = (SIGNATURE_SIZE + SIGNATURE_MAGIC.len()) as u64;
+    if len < tail_len {
+        return Ok(false);
+    }
+
+    let mut magic = [0; SIGNATURE_MAGIC.len()];
+    file.seek(std::io::SeekFrom::End(-(magic.len() as i64)))
+        .await?;
+    file.read_exact(&mut magic).await?;
+    Ok(magic == *SIGNATURE_MAGIC)
+}
+
+/// Verify image signature against configured public keys.
+/// Unsigned images (or no configured keys) are allowed only if `allow_unsigned` (dev mode).
+pub fn verify(
+    image: &[u8],
+    signature: Option<&[u8]>,
+    public_keys: &[String],
+    allow_unsigned: bool,
+) -> Result<()> {
+    let Some(signature) = signature else {
+        if allow_unsigned {
+            return Ok(());
+        }
+
+        return Err(anyhow!("Firmware image is not signed"));
+    };
+
+    if public_keys.is_empty() {
+        if allow_unsigned {
+            return Ok(());
+        }
+
+        return Err(anyhow!("No firmware public keys configured"));
+    }
+
+    let verified = public_keys
+        .iter()
+        .filter_map(|key| parse_public_key(key).ok())
+        .any(|key| {
+            ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, key)
+                .verify(image, signature)
+                .is_ok()
+        });
+
+    if !verified {
+        return Err(anyhow!("Invalid firmware signature"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn verify_signatures() {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let public_key = base64::prelude::BASE64_STANDARD.encode(key_pair.public_key());
+        let keys = vec![public_key];
+
+        let image = b"firmware image".to_vec();
+        let signature = key_pair.sign(&image);
+
+        let mut embedded = image.clone();
+        embedded.extend_from_slice(signature.as_ref());
+        embedded.extend_from_slice(SIGNATURE_MAGIC);
+        let (split_image, split_signature) = split_embedded(&embedded);
+        assert_eq!(split_image, &image[..]);
+        assert!(verify(split_image, split_signature, &keys, false).is_ok());
+
+        let detached = base64::prelude::BASE64_STANDARD.encode(signature.as_ref());
+        let detached = parse_detached(detached.as_bytes()).unwrap();
+        assert!(verify(&image, Some(&detached), &keys, false).is_ok());
+
+        assert!(verify(b"other image", Some(&detached), &keys, false).is_err());
+        assert!(verify(b"other image", Some(&detached), &keys, true).is_err());
+        assert!(verify(&image, None, &keys, false).is_err());
+        assert!(verify(&image, None, &keys, true).is_ok());
+        assert!(verify(&image, Some(&detached), &[], false).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::Result;
 use unix_utils::{
     SnapshotData, UnixError,
-    request::UnixRequestData,
+    request::{UnixRequestData, UpdateFailureReason},
     response::{PossibleGroup, UnixResponseData},
 };
 
@@ -60,15 +60,43 @@ pub async fn get_competitor_info(
     })
 }
 
-// For now, dont parse response (but its there)
-pub async fn mark_attendance(esp_id: u32, card_id: u64) -> Result<(), UnixError> {
-    crate::UNIX_SOCKET
+#[derive(Debug)]
+pub struct AttendanceInfo {
+    pub name: Option<String>,
+    pub message: Option<String>,
+}
+
+pub async fn mark_attendance(esp_id: u32, card_id: u64) -> Result<AttendanceInfo, UnixError> {
+    let res = crate::UNIX_SOCKET
         .send_tagged_request(UnixRequestData::CreateAttendance {
             card_id: card_id.to_string(),
             esp_id,
         })
-        .await
-        .map(|_| ())
+        .await?;
+
+    attendance_info(res)
+}
+
+/// Map backend response to `CreateAttendance`
+pub fn attendance_info(res: UnixResponseData) -> Result<AttendanceInfo, UnixError> {
+    match res {
+        UnixResponseData::CreateAttendanceResp { name, message } => Ok(AttendanceInfo {
+            name: Some(name),
+            message,
+        }),
+        UnixResponseData::Success { message } => Ok(AttendanceInfo {
+            name: None,
+            message: Some(message),
+        }),
+        UnixResponseData::Empty => Ok(AttendanceInfo {
+            name: None,
+            message: None,
+        }),
+        _ => Err(UnixError {
+            message: "Operation failed!".to_string(),
+            should_reset_time: false,
+        }),
+    }
 }
 
 pub async fn send_test_ack(esp_id: u32, snapshot: SnapshotData) -> Result<(), UnixError> {
@@ -80,7 +108,7 @@ pub async fn send_test_ack(esp_id: u32, snapshot: SnapshotData) -> Result<(), Un
 pub async fn send_solve_entry(
     time: u64,
     penalty: i64,
-    solved_at: u64,
+    solved_at_ms: i64,
     esp_id: u32,
     judge_id: u64,
     competitor_id: u64,
@@ -89,7 +117,7 @@ pub async fn send_solve_entry(
     inspection_time: i64,
     group_id: &str,
 ) -> Result<UnixResponseData, UnixError> {
-    let solved_at = chrono::DateTime::from_timestamp_millis(solved_at as i64 * 1000)
+    let solved_at = chrono::DateTime::from_timestamp_millis(solved_at_ms)
         .ok_or_else(|| UnixError {
             message: "Error parsing timestamp".to_string(),
             should_reset_time: false,
@@ -129,6 +157,106 @@ pub async fn send_battery_status(esp_id: u32, battery: Option<f64>) -> Result<()
         .map(|_| ())
 }
 
+pub async fn send_battery_alert(
+    esp_id: u32,
+    alert: &crate::battery::BatteryAlert,
+) -> Result<(), UnixError> {
+    let data = UnixRequestData::BatteryAlert {
+        esp_id,
+        battery_percentage: alert.level.round() as u8,
+        voltage: alert.voltage,
+        minutes_left: alert.minutes_left,
+    };
+
+    crate::UNIX_SOCKET.send_async_request(data).await
+}
+
+pub async fn send_clock_offset(
+    esp_id: u32,
+    offset: &crate::clock_sync::ClockOffset,
+) -> Result<(), UnixError> {
+    let data = UnixRequestData::ClockOffset {
+        esp_id,
+        offset_ms: offset.offset_ms,
+        delay_ms: offset.delay_ms,
+    };
+
+    crate::UNIX_SOCKET.send_async_request(data).await
+}
+
+pub async fn send_update_started(
+    esp_id: u32,
+    from_version: &str,
+    to_version: &str,
+) -> Result<(), UnixError> {
+    let data = UnixRequestData::UpdateStarted {
+        esp_id,
+        from_version: from_version.to_string(),
+        to_version: to_version.to_string(),
+    };
+
+    crate::UNIX_SOCKET.send_async_request(data).await
+}
+
+pub async fn send_update_progress(esp_id: u32, percentage: u8) -> Result<(), UnixError> {
+    let data = UnixRequestData::UpdateProgress { esp_id, percentage };
+    crate::UNIX_SOCKET.send_async_request(data).await
+}
+
+pub async fn send_update_completed(esp_id: u32, version: &str) -> Result<(), UnixError> {
+    let data = UnixRequestData::UpdateCompleted {
+        esp_id,
+        version: version.to_string(),
+    };
+
+    crate::UNIX_SOCKET.send_async_request(data).await
+}
+
+pub async fn send_update_verified(esp_id: u32, version: &str) -> Result<(), UnixError> {
+    let data = UnixRequestData::UpdateVerified {
+        esp_id,
+        version: version.to_string(),
+    };
+
+    crate::UNIX_SOCKET.send_async_request(data).await
+}
+
+pub async fn send_update_failed(
+    esp_id: u32,
+    version: &str,
+    reason: UpdateFailureReason,
+    reported_version: Option<String>,
+) -> Result<(), UnixError> {
+    let data = UnixRequestData::UpdateFailed {
+        esp_id,
+        version: version.to_string(),
+        reason,
+        reported_version,
+    };
+
+    crate::UNIX_SOCKET.send_async_request(data).await
+}
+
+pub async fn send_command_failed(command: &str, error: &str) -> Result<(), UnixError> {
+    let data = UnixRequestData::CommandFailed {
+        command: command.to_string(),
+        error: error.to_string(),
+    };
+
+    crate::UNIX_SOCKET.send_async_request(data).await
+}
+
+pub async fn send_update_queue(
+    queue: &crate::update_scheduler::UpdateQueueState,
+) -> Result<(), UnixError> {
+    let data = UnixRequestData::UpdateQueue {
+        active: queue.active.clone(),
+        waiting: queue.waiting.clone(),
+    };
+
+    crate::UNIX_SOCKET.send_async_request(data).await
+}
+
 pub async fn send_current_state(
     esp_id: u32,
     time: Option<u64>,
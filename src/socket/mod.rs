@@ -2,9 +2,16 @@ use crate::{
     structs::{SharedAppState, TimerPacket, TimerPacketInner},
     updater::FirmwareMetadata,
 };
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use base64::Engine;
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::UnixStream,
@@ -13,7 +20,7 @@ use tokio::{
 use unix_utils::{
     UnixError,
     request::{UnixRequest, UnixRequestData},
-    response::{TranslationLocale, TranslationRecord, UnixResponse, UnixResponseData},
+    response::{UnixResponse, UnixResponseData},
 };
 
 pub mod api;
@@ -31,6 +38,7 @@ pub struct SocketInner {
     //stream: UnixStream,
     state: SharedAppState,
     socket_channel: tokio::sync::mpsc::UnboundedSender<UnixRequest>,
+    pending_requests: Arc<AtomicUsize>,
     tag_channels: HashMap<u32, tokio::sync::oneshot::Sender<Option<UnixResponseData>>>,
 }
 
@@ -43,15 +51,17 @@ impl Socket {
 
     pub async fn init(&self, socket_path: &str, state: SharedAppState) -> Result<()> {
         let (socket_channel, rx) = tokio::sync::mpsc::unbounded_channel();
+        let pending_requests = Arc::new(AtomicUsize::new(0));
 
         let inner = Arc::new(RwLock::new(SocketInner {
             state: state.clone(),
             socket_channel,
+            pending_requests: pending_requests.clone(),
             tag_channels: HashMap::new(),
         }));
         self.inner.set(inner)?;
 
-        socket_task(socket_path.to_string(), rx, state).await;
+        socket_task(socket_path.to_string(), rx, pending_requests, state).await;
         Ok(())
     }
 
@@ -124,18 +134,55 @@ impl Socket {
                 inner.tag_channels.insert(tag, resp_tx);
             }
 
-            inner.socket_channel.send(req)?;
+            // counted before send (socket task decrements it after write),
+            // request that wasn't queued isn't pending
+            inner.pending_requests.fetch_add(1, Ordering::SeqCst);
+            if let Err(e) = inner.socket_channel.send(req) {
+                inner.pending_requests.fetch_sub(1, Ordering::SeqCst);
+                if let Some(tag) = tag {
+                    inner.tag_channels.remove(&tag);
+                }
+
+                return Err(e.into());
+            }
         }
 
-        if tag.is_some() {
+        if let Some(tag) = tag {
             // TODO: add better errors (for timeout, and recv error)
-            let resp = tokio::time::timeout(UNIX_TIMEOUT, resp_rx).await??;
-            return Ok(resp);
+            let resp = tokio::time::timeout(UNIX_TIMEOUT, resp_rx).await;
+            if resp.is_err() {
+                inner.write().await.tag_channels.remove(&tag);
+            }
+
+            return Ok(resp??);
         }
 
         Ok(None)
     }
 
+    /// Wait until queued requests are written and tagged requests answered.
+    /// Returns false if timeout was reached first.
+    pub async fn flush(&self, timeout: Duration) -> Result<bool> {
+        let inner = self.get_inner().await?;
+        let res = tokio::time::timeout(timeout, async {
+            loop {
+                {
+                    let inner = inner.read().await;
+                    if inner.pending_requests.load(Ordering::SeqCst) == 0
+                        && inner.tag_channels.is_empty()
+                    {
+                        return;
+                    }
+                }
+
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+
+        Ok(res.is_ok())
+    }
+
     pub async fn send_resp_to_channel(
         &self,
         tag: u32,
@@ -155,11 +202,12 @@ impl Socket {
 async fn socket_task(
     socket_path: String,
     mut rx: UnboundedReceiver<UnixRequest>,
+    pending_requests: Arc<AtomicUsize>,
     state: SharedAppState,
 ) {
     tokio::task::spawn(async move {
         loop {
-            let res = inner_socket_task(&socket_path, &mut rx, &state).await;
+            let res = inner_socket_task(&socket_path, &mut rx, &pending_requests, &state).await;
             if let Err(e) = res {
                 tracing::error!("Socket task err: {e:?}");
                 _ = tokio::time::sleep(Duration::from_millis(500)).await;
@@ -171,6 +219,7 @@ async fn socket_task(
 async fn inner_socket_task(
     socket_path: &str,
     rx: &mut UnboundedReceiver<UnixRequest>,
+    pending_requests: &AtomicUsize,
     state: &SharedAppState,
 ) -> Result<()> {
     let mut stream = UnixStream::connect(socket_path).await?;
@@ -192,19 +241,28 @@ async fn inner_socket_task(
                 }
             }
             Some(recv) = rx.recv() => {
-                let bytes = serde_json::to_vec(&recv)?;
-
-                stream.write_all(&bytes).await?;
-                stream.write_u8(0x00).await?; // null byte separator
+                let res = write_request(&mut stream, &recv).await;
+                pending_requests.fetch_sub(1, Ordering::SeqCst);
+                res?;
             }
         }
     }
 }
 
+async fn write_request(stream: &mut UnixStream, req: &UnixRequest) -> Result<()> {
+    let bytes = serde_json::to_vec(req)?;
+
+    stream.write_all(&bytes).await?;
+    stream.write_u8(0x00).await?; // null byte separator
+    Ok(())
+}
+
 async fn process_untagged_response(data: UnixResponseData, state: &SharedAppState) -> Result<()> {
     match data {
         UnixResponseData::CustomMessage {
             esp_id,
+            rooms,
+            all,
             line1,
             line2,
         } => {
@@ -217,29 +275,18 @@ async fn process_untagged_response(data: UnixResponseData, state: &SharedAppStat
             let inner = inner.read().await;
             let state = &inner.state;
 
-            state.send_timer_packet(esp_id, packet).await?;
+            let devices: Vec<u32> = esp_id.into_iter().collect();
+            state
+                .send_timer_packet_to(&devices, &rooms, all, packet)
+                .await?;
         }
         UnixResponseData::ServerStatus(status) => {
             let inner = crate::UNIX_SOCKET.get_inner().await?;
             let inner = inner.read().await;
             let mut inner_state = inner.state.inner.write().await;
 
-            // remove all unicode weirdness
-            let translations: Vec<TranslationLocale> = status
-                .translations
-                .into_iter()
-                .map(|l| TranslationLocale {
-                    locale: l.locale,
-                    translations: l
-                        .translations
-                        .into_iter()
-                        .map(|t| TranslationRecord {
-                            key: t.key,
-                            translation: unidecode::unidecode(&t.translation),
-                        })
-                        .collect(),
-                })
-                .collect();
+            // translations are rendered (transliterated) per device, see `display`
+            let translations = status.translations;
 
             let mut changed = inner_state.should_update != status.should_update
                 || inner_state.locales != translations
@@ -250,22 +297,33 @@ async fn process_untagged_response(data: UnixResponseData, state: &SharedAppStat
                 || inner_state.sound_enabled != status.sound_enabled;
 
             inner_state.should_update = status.should_update;
-            inner_state.locales = translations;
+            if inner_state.locales != translations {
+                let old = std::mem::replace(&mut inner_state.locales, translations);
+                let inner_state = &mut *inner_state;
+                inner_state
+                    .translation_cache
+                    .update(old, &inner_state.locales);
+            }
             inner_state.default_locale = status.default_locale;
             inner_state.auto_setup = status.auto_setup;
             inner_state.fkm_token = status.fkm_token;
             inner_state.secure_rfid = status.secure_rfid;
             inner_state.sound_enabled = status.sound_enabled;
 
+            let mut added = Vec::new();
             for device in &status.devices {
                 let device_settings = crate::structs::DeviceSettings {
                     sign_key: device.sign_key,
+                    room: device.room.clone(),
                 };
 
                 let old = inner_state
                     .devices_settings
                     .insert(device.esp_id, device_settings.clone());
 
+                if old.is_none() {
+                    added.push(device.esp_id);
+                }
                 if old != Some(device_settings) {
                     changed = true;
                 }
@@ -279,6 +337,15 @@ async fn process_untagged_response(data: UnixResponseData, state: &SharedAppStat
             }
 
             drop(inner_state);
+
+            // adding device confirms HMAC key it sent in `Add`
+            for esp_id in added {
+                let res = inner.state.device_keys.lock().await.confirm(esp_id).await;
+                if let Err(e) = res {
+                    tracing::error!("HMAC key confirm failed [{esp_id:X}]: {e:?}");
+                }
+            }
+
             if changed {
                 _ = inner.state.device_settings_broadcast().await;
             }
@@ -321,13 +388,40 @@ async fn process_untagged_response(data: UnixResponseData, state: &SharedAppStat
         UnixResponseData::UploadFirmware {
             file_name,
             file_data,
+            signature,
         } => {
             let data = base64::prelude::BASE64_STANDARD.decode(file_data);
-            let Ok(data) = data else {
+            let Ok(mut data) = data else {
                 tracing::error!("ForceUpdate Base64 parse error!");
                 return Ok(());
             };
 
+            let (image, embedded) = crate::signature::split_embedded(&data);
+            let (image_len, embedded) = (image.len(), embedded.map(|s| s.to_vec()));
+            data.truncate(image_len);
+
+            let signature = match (embedded, signature) {
+                (Some(embedded), _) => Some(embedded),
+                (None, Some(detached)) => {
+                    match crate::signature::parse_detached(detached.as_bytes()) {
+                        Ok(detached) => Some(detached),
+                        Err(e) => {
+                            tracing::error!("ForceUpdate signature parse error: {e}");
+                            return Ok(());
+                        }
+                    }
+                }
+                (None, None) => None,
+            };
+
+            let public_keys = state.config.read().await.firmware_public_keys.clone();
+            if let Err(e) =
+                crate::signature::verify(&data, signature.as_deref(), &public_keys, state.dev_mode)
+            {
+                tracing::error!("ForceUpdate firmware rejected: {e}");
+                return Ok(());
+            }
+
             let metadata = FirmwareMetadata::from_file(&file_name, &data).await;
             let Ok(metadata) = metadata else {
                 tracing::error!(
@@ -351,7 +445,7 @@ async fn process_untagged_response(data: UnixResponseData, state: &SharedAppStat
                 .force_update(
                     hardware.to_string(),
                     crate::updater::Firmware {
-                        data,
+                        image: crate::updater::FirmwareImage::Loaded { data, signature },
                         version,
                         build_time: metadata.build_time,
                         firmware: firmware.to_string(),
@@ -359,18 +453,50 @@ async fn process_untagged_response(data: UnixResponseData, state: &SharedAppStat
                 )
                 .await?;
         }
-        UnixResponseData::SetDeviceSettings { devices, volume } => {
-            for esp_id in devices {
-                state
-                    .send_timer_packet(
-                        esp_id,
-                        TimerPacket {
-                            tag: None,
-                            data: TimerPacketInner::SetDeviceSettings { volume },
-                        },
-                    )
-                    .await?;
+        UnixResponseData::PinFirmware {
+            esp_id,
+            hw,
+            version,
+        } => {
+            let mut pins = state.firmware_pins.lock().await;
+            let res = match (esp_id, &hw) {
+                (Some(esp_id), None) => pins.set_device(esp_id, version.clone()).await,
+                (None, Some(hw)) => pins.set_hardware(hw.clone(), version.clone()).await,
+                (Some(_), Some(_)) | (None, None) => {
+                    Err(anyhow!("PinFirmware needs exactly one of esp_id or hw"))
+                }
+            };
+            drop(pins);
+
+            // backend socket stays connected, backend gets the error instead
+            if let Err(e) = res {
+                tracing::error!("PinFirmware failed: {e:?}");
+                _ = api::send_command_failed("PinFirmware", &e.to_string()).await;
+                return Ok(());
             }
+
+            tracing::info!("Firmware pin set: {esp_id:X?} {hw:?} {version:?}");
+            state.build_broadcast().await?;
+        }
+        UnixResponseData::ResumeRollouts => {
+            state.rollouts.lock().await.resume(crate::rollout::now_ms());
+            crate::rollout::flush(state).await;
+            state.build_broadcast().await?;
+        }
+        UnixResponseData::SetDeviceSettings {
+            devices,
+            rooms,
+            all,
+            volume,
+        } => {
+            let packet = TimerPacket {
+                tag: None,
+                data: TimerPacketInner::SetDeviceSettings { volume },
+            };
+
+            state
+                .send_timer_packet_to(&devices, &rooms, all, packet)
+                .await?;
         }
         _ => {}
     }
@@ -391,3 +517,80 @@ async fn read_until_null(stream: &mut UnixStream, buf: &mut Vec<u8>) -> Result<V
         buf.push(byte);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_send_not_pending() {
+        let socket = Socket::const_new();
+        let data_dir = crate::test_util::TempDir::new("failed_send");
+        let config = crate::config::Config {
+            data_dir: data_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let state = SharedAppState::new(config, None).await;
+
+        // socket task is gone (receiver dropped)
+        let (socket_channel, _) = tokio::sync::mpsc::unbounded_channel();
+        let pending_requests = Arc::new(AtomicUsize::new(0));
+        let inner = Arc::new(RwLock::new(SocketInner {
+            state,
+            socket_channel,
+            pending_requests: pending_requests.clone(),
+            tag_channels: HashMap::new(),
+        }));
+        socket.inner.set(inner.clone()).unwrap();
+
+        let data = UnixRequestData::UpdateProgress {
+            esp_id: 1,
+            percentage: 10,
+        };
+        assert!(socket.send_async_request(data).await.is_err());
+        assert_eq!(pending_requests.load(Ordering::SeqCst), 0);
+        assert!(socket.flush(Duration::from_millis(10)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn flush_waits_for_requests() {
+        let socket = Socket::const_new();
+        assert!(socket.flush(Duration::from_millis(10)).await.is_err());
+
+        let data_dir = crate::test_util::TempDir::new("flush");
+        let config = crate::config::Config {
+            data_dir: data_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let state = SharedAppState::new(config, None).await;
+
+        let (socket_channel, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let pending_requests = Arc::new(AtomicUsize::new(0));
+        let inner = Arc::new(RwLock::new(SocketInner {
+            state,
+            socket_channel,
+            pending_requests: pending_requests.clone(),
+            tag_channels: HashMap::new(),
+        }));
+        socket.inner.set(inner.clone()).unwrap();
+        assert!(socket.flush(Duration::from_millis(10)).await.unwrap());
+
+        // queued request (not written yet)
+        pending_requests.fetch_add(1, Ordering::SeqCst);
+        assert!(!socket.flush(Duration::from_millis(200)).await.unwrap());
+        pending_requests.fetch_sub(1, Ordering::SeqCst);
+
+        // tagged request waiting for response
+        let (tx, _resp_rx) = tokio::sync::oneshot::channel();
+        inner.write().await.tag_channels.insert(1, tx);
+        assert!(!socket.flush(Duration::from_millis(200)).await.unwrap());
+
+        let flush = tokio::spawn({
+            let socket = socket.clone();
+            async move { socket.flush(Duration::from_secs(2)).await.unwrap() }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        socket.send_resp_to_channel(1, None).await.unwrap();
+        assert!(flush.await.unwrap());
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::Result;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+
+/// Backend answer remembered for a single solve (by session_id)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SolveLedgerEntry {
+    pub competitor_id: u64,
+    pub delegate: bool,
+    pub message: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SolveLedgerFile {
+    day: Option<NaiveDate>,
+    devices: HashMap<u32, HashMap<String, SolveLedgerEntry>>,
+}
+
+/// Per-device record of solves already accepted by the backend.
+/// Entries are kept for the current (local) day only and persisted to disk,
+/// so resent solves after reconnects or connector restarts aren't entered twice.
+#[derive(Debug)]
+pub struct SolveLedger {
+    path: PathBuf,
+    inner: SolveLedgerFile,
+}
+
+impl SolveLedger {
+    pub async fn load(path: PathBuf) -> Self {
+        let inner = match tokio::fs::read(&path).await {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                tracing::error!("Solve ledger parse error (starting empty): {e:?}");
+                SolveLedgerFile::default()
+            }),
+            Err(_) => SolveLedgerFile::default(),
+        };
+
+        let mut ledger = Self { path, inner };
+        ledger.rollover(Self::today());
+        ledger
+    }
+
+    pub fn get(&mut self, esp_id: u32, session_id: &str) -> Option<SolveLedgerEntry> {
+        self.rollover(Self::today());
+        self.inner.devices.get(&esp_id)?.get(session_id).cloned()
+    }
+
+    pub async fn insert(
+        &mut self,
+        esp_id: u32,
+        session_id: &str,
+        entry: SolveLedgerEntry,
+    ) -> Result<()> {
+        self.rollover(Self::today());
+        self.inner
+            .devices
+            .entry(esp_id)
+            .or_default()
+            .insert(session_id.to_string(), entry);
+
+        self.save().await
+    }
+
+    /// Clear ledger if competition day changed
+    fn rollover(&mut self, today: NaiveDate) {
+        if self.inner.day != Some(today) {
+            self.inner.devices.clear();
+            self.inner.day = Some(today);
+        }
+    }
+
+    async fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(&self.inner)?).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+
+    fn today() -> NaiveDate {
+        chrono::Local::now().date_naive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[tokio::test]
+    async fn persists_and_rolls_over() {
+        let dir = TempDir::new("solve_ledger");
+        let path = dir.join("solve_ledger.json");
+        let entry = SolveLedgerEntry {
+            competitor_id: 1,
+            delegate: false,
+            message: "ok".to_string(),
+        };
+
+        let mut ledger = SolveLedger::load(path.clone()).await;
+        ledger.insert(0xAB, "session", entry.clone()).await.unwrap();
+
+        let mut ledger = SolveLedger::load(path.clone()).await;
+        assert_eq!(ledger.get(0xAB, "session"), Some(entry));
+        assert_eq!(ledger.get(0xAC, "session"), None);
+
+        ledger.rollover(SolveLedger::today().succ_opt().unwrap());
+        assert!(ledger.inner.devices.is_empty());
+    }
+}
@@ -1,11 +1,28 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 use unix_utils::{
     SnapshotData, TestPacketData,
     response::{PossibleGroup, TranslationLocale},
 };
 
-use crate::updater::Firmware;
+use crate::{
+    attendance::AttendanceMarks,
+    auth::DeviceKeys,
+    battery::BatteryHistory,
+    config::Config,
+    connected_devices::{CONNECTED_DEVICES_FILE, ConnectedDevices},
+    firmware_catalogue::FirmwareCatalogue,
+    legacy::CurrentProtocolDevices,
+    pins::FirmwarePins,
+    rollout::Rollouts,
+    solve_ledger::SolveLedger,
+    translations::{RemovedTranslation, TranslationCache},
+    update_scheduler::UpdateScheduler,
+    updater::{Firmware, SentUpdate, TransferProgressMap},
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TimerPacket {
@@ -23,6 +40,30 @@ pub enum TimerPacketInner {
         size: u32,
         crc: u32,
         firmware: String,
+
+        /// Device should reply with `UpdateResume` (bytes of this image it already has)
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        resume: bool,
+
+        /// Negotiated windowed transfer (only for devices with `ota_window` capability)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chunk_size: Option<u32>,
+
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        window: Option<u32>,
+    },
+    UpdateAck {
+        /// All chunks up to this sequence number were received
+        seq: u32,
+    },
+    UpdateResume {
+        offset: u32,
+
+        /// CRC32 of first `offset` bytes
+        crc: u32,
+    },
+    UpdateResumeAck {
+        offset: u32,
     },
     Solve {
         solve_time: u64,
@@ -34,7 +75,15 @@ pub enum TimerPacketInner {
         delegate: bool,
         inspection_time: i64,
         group_id: String,
+
+        #[serde(default)]
         sign_key: u32,
+
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mac: Option<String>,
+
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<u64>,
     },
     SolveConfirm {
         competitor_id: u64,
@@ -65,7 +114,14 @@ pub enum TimerPacketInner {
         #[serde(skip_serializing_if = "Option::is_none")]
         attendance_device: Option<bool>,
 
+        #[serde(default)]
         sign_key: u32,
+
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mac: Option<String>,
+
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<u64>,
     },
     CardInfoResponse {
         card_id: u64,
@@ -75,6 +131,18 @@ pub enum TimerPacketInner {
         possible_groups: Vec<PossibleGroup>,
     },
     AttendanceMarked,
+
+    /// Sent instead of `AttendanceMarked` to devices with `attendance_result` capability
+    AttendanceResult {
+        success: bool,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+
+        /// Failure reason (or info, like already marked)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
     DeviceSettings {
         added: bool,
         locales: Vec<TranslationLocale>,
@@ -83,6 +151,20 @@ pub enum TimerPacketInner {
         secure_rfid: bool,
         auto_setup: bool,
         sound_enabled: bool,
+
+        /// Hash of full translation set (only for devices with delta capability)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        translations_hash: Option<String>,
+
+        /// If true, `locales` contains only changed records
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        translations_delta: bool,
+
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        removed_translations: Vec<RemovedTranslation>,
+    },
+    TranslationsAck {
+        hash: String,
     },
     Battery {
         level: Option<f64>,
@@ -91,13 +173,37 @@ pub enum TimerPacketInner {
     Add {
         firmware: String,
         sign_key: u32,
+
+        /// HMAC key generated by device (base64, 32 bytes), devices with `hmac` capability
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hmac_key: Option<String>,
     },
     EpochTime {
         current_epoch: u64,
     },
+    TimeSyncRequest {
+        seq: u32,
+        server_time: u64,
+    },
+    TimeSyncResponse {
+        seq: u32,
+        server_time: u64,
+        device_time: u64,
+    },
     SetDeviceSettings {
         volume: Option<u8>,
     },
+    DisplayInfo {
+        #[serde(default)]
+        columns: Option<usize>,
+
+        /// Non-ASCII glyphs supported by display
+        #[serde(default)]
+        glyphs: String,
+
+        #[serde(default)]
+        locale: Option<String>,
+    },
     DumpCrashLog,
 
     // packet for end to end testing
@@ -109,14 +215,55 @@ pub enum TimerPacketInner {
 pub enum BroadcastPacket {
     Build,
     Resp((u32, TimerPacket)),
+    RespAll(TimerPacket),
     UpdateDeviceSettings,
     ForceUpdate((String, Firmware)),
+    Shutdown,
 }
 
 #[derive(Debug, Clone)]
 pub struct SharedAppState {
     pub inner: std::sync::Arc<tokio::sync::RwLock<AppState>>,
+    pub config: std::sync::Arc<tokio::sync::RwLock<Config>>,
+
+    /// Dev mode (from config, can't be changed at runtime)
     pub dev_mode: bool,
+
+    /// SHA-256 fingerprint of server TLS certificate (None if TLS is disabled)
+    pub tls_fingerprint: Option<String>,
+    pub solve_ledger: std::sync::Arc<tokio::sync::Mutex<SolveLedger>>,
+
+    /// HMAC keys and last packet nonces of devices
+    pub device_keys: std::sync::Arc<tokio::sync::Mutex<DeviceKeys>>,
+
+    /// Devices that authenticated with current protocol (legacy packets are rejected)
+    pub current_protocol_devices: std::sync::Arc<tokio::sync::Mutex<CurrentProtocolDevices>>,
+    pub battery_history: std::sync::Arc<tokio::sync::Mutex<BatteryHistory>>,
+
+    /// Indexed firmware images from `firmware_dir`
+    pub firmware_catalogue: std::sync::Arc<tokio::sync::RwLock<FirmwareCatalogue>>,
+
+    /// Firmware versions pinned by backend or admin (`pins.json`)
+    pub firmware_pins: std::sync::Arc<tokio::sync::Mutex<FirmwarePins>>,
+
+    /// Staged firmware rollouts (canaries and waves)
+    pub rollouts: std::sync::Arc<tokio::sync::Mutex<Rollouts>>,
+
+    /// Limits simultaneous firmware transfers
+    pub update_scheduler: std::sync::Arc<UpdateScheduler>,
+
+    /// Progress of interrupted updates (for resume)
+    pub update_progress: std::sync::Arc<tokio::sync::Mutex<TransferProgressMap>>,
+    pub connections: std::sync::Arc<AtomicUsize>,
+
+    /// Connected devices (mirrored to `connected_devices.json`)
+    pub connected_devices: std::sync::Arc<tokio::sync::Mutex<ConnectedDevices>>,
+
+    /// Limits concurrent websocket connections (`max_connections`)
+    pub connection_slots: std::sync::Arc<tokio::sync::Semaphore>,
+    pub active_updates: std::sync::Arc<AtomicUsize>,
+    shutting_down: std::sync::Arc<AtomicBool>,
+    updates_aborted: std::sync::Arc<AtomicBool>,
     bc: tokio::sync::broadcast::Sender<BroadcastPacket>,
 }
 
@@ -124,6 +271,18 @@ pub struct SharedAppState {
 pub struct AppState {
     pub should_update: bool,
     pub devices_settings: HashMap<u32, DeviceSettings>,
+
+    /// Hash of current translations (and previous sets for delta updates)
+    pub translation_cache: TranslationCache,
+
+    /// Rooms from local mapping file (`rooms_file`), take precedence over backend rooms
+    pub local_rooms: HashMap<u32, String>,
+
+    /// Updates transferred to devices, verified when device reconnects
+    pub sent_updates: HashMap<u32, SentUpdate>,
+
+    /// Recently marked attendance (duplicate scans suppression)
+    pub attendance_marks: AttendanceMarks,
     pub locales: Vec<TranslationLocale>,
     pub default_locale: String,
     pub fkm_token: i32,
@@ -132,20 +291,83 @@ pub struct AppState {
     pub sound_enabled: bool,
 }
 
+impl AppState {
+    pub fn device_room(&self, esp_id: u32) -> Option<&str> {
+        self.local_rooms
+            .get(&esp_id)
+            .or_else(|| self.devices_settings.get(&esp_id)?.room.as_ref())
+            .map(|r| r.as_str())
+    }
+
+    pub fn devices_in_rooms(&self, rooms: &[String]) -> Vec<u32> {
+        self.local_rooms
+            .keys()
+            .chain(self.devices_settings.keys())
+            .copied()
+            .filter(|esp_id| {
+                self.device_room(*esp_id)
+                    .is_some_and(|room| rooms.iter().any(|r| r == room))
+            })
+            .collect()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct DeviceSettings {
     pub sign_key: Option<u32>,
+
+    /// Room assigned by backend
+    pub room: Option<String>,
 }
 
 impl SharedAppState {
-    pub async fn new(dev_mode: bool) -> Self {
+    pub async fn new(config: Config, tls_fingerprint: Option<String>) -> Self {
         let (bc, _) = tokio::sync::broadcast::channel(1024);
+        let solve_ledger = SolveLedger::load(config.data_dir.join("solve_ledger.json")).await;
+        let battery_history =
+            BatteryHistory::load(config.data_dir.join("battery_history.json")).await;
+        let device_keys = DeviceKeys::load(config.data_dir.join("device_keys.json")).await;
+        let current_protocol_devices =
+            CurrentProtocolDevices::load(config.data_dir.join("current_protocol_devices.json"))
+                .await;
+        let firmware_pins = FirmwarePins::load(config.data_dir.join("pins.json")).await;
+        let rollouts = Rollouts::load(config.data_dir.join("rollouts.json")).await;
+        let update_scheduler = UpdateScheduler::new(config.max_concurrent_updates);
+        let connected_devices =
+            ConnectedDevices::new(config.data_dir.join(CONNECTED_DEVICES_FILE)).await;
+        let connection_slots =
+            std::sync::Arc::new(tokio::sync::Semaphore::new(config.max_connections));
 
         Self {
-            dev_mode,
+            dev_mode: config.dev,
+            config: std::sync::Arc::new(tokio::sync::RwLock::new(config)),
+            tls_fingerprint,
+            solve_ledger: std::sync::Arc::new(tokio::sync::Mutex::new(solve_ledger)),
+            device_keys: std::sync::Arc::new(tokio::sync::Mutex::new(device_keys)),
+            current_protocol_devices: std::sync::Arc::new(tokio::sync::Mutex::new(
+                current_protocol_devices,
+            )),
+            battery_history: std::sync::Arc::new(tokio::sync::Mutex::new(battery_history)),
+            firmware_catalogue: std::sync::Arc::new(tokio::sync::RwLock::new(
+                FirmwareCatalogue::default(),
+            )),
+            firmware_pins: std::sync::Arc::new(tokio::sync::Mutex::new(firmware_pins)),
+            rollouts: std::sync::Arc::new(tokio::sync::Mutex::new(rollouts)),
+            update_scheduler,
+            update_progress: std::sync::Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+            connections: std::sync::Arc::new(AtomicUsize::new(0)),
+            connected_devices: std::sync::Arc::new(tokio::sync::Mutex::new(connected_devices)),
+            connection_slots,
+            active_updates: std::sync::Arc::new(AtomicUsize::new(0)),
+            shutting_down: std::sync::Arc::new(AtomicBool::new(false)),
+            updates_aborted: std::sync::Arc::new(AtomicBool::new(false)),
             inner: std::sync::Arc::new(tokio::sync::RwLock::new(AppState {
                 should_update: false,
                 devices_settings: HashMap::new(),
+                attendance_marks: AttendanceMarks::default(),
+                sent_updates: HashMap::new(),
+                local_rooms: HashMap::new(),
+                translation_cache: TranslationCache::new(&[]),
                 locales: Vec::new(),
                 default_locale: "en".to_string(),
                 fkm_token: 0,
@@ -178,6 +400,53 @@ impl SharedAppState {
         Ok(())
     }
 
+    /// Send packet to listed devices and devices in rooms (or to all devices)
+    pub async fn send_timer_packet_to(
+        &self,
+        devices: &[u32],
+        rooms: &[String],
+        all: bool,
+        packet: TimerPacket,
+    ) -> anyhow::Result<()> {
+        if all {
+            self.bc.send(BroadcastPacket::RespAll(packet))?;
+            return Ok(());
+        }
+
+        let mut esp_ids: Vec<u32> = devices.to_vec();
+        esp_ids.extend(self.inner.read().await.devices_in_rooms(rooms));
+        esp_ids.sort_unstable();
+        esp_ids.dedup();
+
+        for esp_id in esp_ids {
+            self.bc
+                .send(BroadcastPacket::Resp((esp_id, packet.clone())))?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn shutdown_broadcast(&self) -> anyhow::Result<()> {
+        self.bc.send(BroadcastPacket::Shutdown)?;
+        Ok(())
+    }
+
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub fn abort_updates(&self) {
+        self.updates_aborted.store(true, Ordering::SeqCst);
+    }
+
+    pub fn updates_aborted(&self) -> bool {
+        self.updates_aborted.load(Ordering::SeqCst)
+    }
+
     pub async fn get_bc(&self) -> tokio::sync::broadcast::Receiver<BroadcastPacket> {
         self.bc.subscribe()
     }
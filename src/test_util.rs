@@ -0,0 +1,48 @@
+use crate::{http::EspConnectInfo, updater::FirmwareMetadata};
+use std::path::{Path, PathBuf};
+
+/// Temporary directory, removed when dropped (even if test panics)
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("fkm_{name}_{}", rand::random::<u32>()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Firmware image with trailer (unsigned)
+pub fn image(hw: &str, firmware: &str, version: &str) -> Vec<u8> {
+    let mut data = vec![0xAB; 256];
+    let metadata = FirmwareMetadata::new(hw, firmware, version, 0).unwrap();
+    data.extend_from_slice(&metadata.to_bytes());
+    data
+}
+
+/// `STATION` device on `v3` hardware without capabilities
+pub fn esp_info(esp_id: u32, version: &str) -> EspConnectInfo {
+    EspConnectInfo {
+        id: esp_id,
+        version: version.to_string(),
+        firmware: "STATION".to_string(),
+        hw: "v3".to_string(),
+        random: 0,
+        caps: String::new(),
+        tr_hash: None,
+    }
+}
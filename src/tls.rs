@@ -0,0 +1,132 @@
+use anyhow::{Result, anyhow};
+use rcgen::CertifiedKey;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::path::Path;
+
+const CERT_SUBJECT_NAME: &str = "micro-connector.local";
+
+/// Server certificate (loaded from disk or generated once and persisted)
+#[derive(Debug)]
+pub struct TlsIdentity {
+    pub certs: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+
+    /// SHA-256 of leaf certificate (DER), lowercase hex - used for pinning by firmware
+    pub fingerprint: String,
+}
+
+impl TlsIdentity {
+    pub async fn load_or_generate(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let (cert_exists, key_exists) = (
+            tokio::fs::try_exists(cert_path).await?,
+            tokio::fs::try_exists(key_path).await?,
+        );
+
+        match (cert_exists, key_exists) {
+            (true, true) => {}
+
+            // cert is written last, so key without cert is leftover of interrupted generation
+            (false, _) => {
+                tracing::info!(
+                    "Generating new TLS certificate ({})",
+                    cert_path.to_string_lossy()
+                );
+                Self::generate(cert_path, key_path).await?;
+            }
+            (true, false) => {
+                return Err(anyhow!(
+                    "Only one of TLS cert ({}) and key ({}) exists!",
+                    cert_path.to_string_lossy(),
+                    key_path.to_string_lossy()
+                ));
+            }
+        }
+
+        let certs = cert_from_str(&tokio::fs::read_to_string(cert_path).await?)?;
+        let key = key_from_str(&tokio::fs::read_to_string(key_path).await?)?;
+        let leaf = certs
+            .first()
+            .ok_or_else(|| anyhow!("No certificate found in {}", cert_path.to_string_lossy()))?;
+
+        Ok(Self {
+            fingerprint: fingerprint(leaf),
+            certs,
+            key,
+        })
+    }
+
+    async fn generate(cert_path: &Path, key_path: &Path) -> Result<()> {
+        let CertifiedKey { cert, signing_key } =
+            rcgen::generate_simple_self_signed(vec![CERT_SUBJECT_NAME.to_string()])?;
+
+        for path in [cert_path, key_path] {
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+        }
+
+        crate::fs_util::write_atomic(key_path, signing_key.serialize_pem().as_bytes(), true)
+            .await?;
+        crate::fs_util::write_atomic(cert_path, cert.pem().as_bytes(), false).await?;
+        Ok(())
+    }
+}
+
+pub fn fingerprint(cert: &CertificateDer) -> String {
+    ring::digest::digest(&ring::digest::SHA256, cert.as_ref())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn cert_from_str(cert: &str) -> Result<Vec<CertificateDer<'static>>> {
+    rustls_pemfile::certs(&mut cert.as_bytes())
+        .collect::<std::io::Result<_>>()
+        .map_err(anyhow::Error::from)
+}
+
+fn key_from_str(key: &str) -> Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut key.as_bytes())?
+        .ok_or_else(|| anyhow::anyhow!("Private ket returned None"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn generate_and_reload() {
+        let tmp = TempDir::new("tls");
+        let dir = tmp.join("tls");
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+
+        let generated = TlsIdentity::load_or_generate(&cert_path, &key_path)
+            .await
+            .unwrap();
+        let mode = std::fs::metadata(&key_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let loaded = TlsIdentity::load_or_generate(&cert_path, &key_path)
+            .await
+            .unwrap();
+        assert_eq!(generated.fingerprint, loaded.fingerprint);
+        assert_eq!(loaded.fingerprint.len(), 64);
+
+        // interrupted generation (key without cert) is generated again
+        std::fs::remove_file(&cert_path).unwrap();
+        let regenerated = TlsIdentity::load_or_generate(&cert_path, &key_path)
+            .await
+            .unwrap();
+        assert_ne!(regenerated.fingerprint, generated.fingerprint);
+
+        std::fs::remove_file(&key_path).unwrap();
+        assert!(
+            TlsIdentity::load_or_generate(&cert_path, &key_path)
+                .await
+                .is_err()
+        );
+    }
+}
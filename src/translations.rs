@@ -0,0 +1,222 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use unix_utils::response::{TranslationLocale, TranslationRecord};
+
+/// Firmware capability: device reports translations hash and accepts delta updates
+pub const TRANSLATIONS_DELTA_CAPABILITY: &str = "tr_delta";
+
+/// How many previous translation sets are kept to build deltas from
+const HISTORY_SIZE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RemovedTranslation {
+    pub locale: String,
+    pub key: String,
+}
+
+/// Translations that have to be sent to device
+#[derive(Debug, PartialEq)]
+pub enum TranslationsUpdate {
+    /// Device doesn't hold any known set
+    Full,
+
+    /// Device already holds current set
+    Unchanged,
+
+    /// Only changed (or added) records and removed keys
+    Delta {
+        changed: Vec<TranslationLocale>,
+        removed: Vec<RemovedTranslation>,
+    },
+}
+
+/// SHA-256 (lowercase hex) of translation set, independent of records order
+pub fn hash_translations(locales: &[TranslationLocale]) -> String {
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+    for (locale, records) in sorted(locales) {
+        ctx.update(locale.as_bytes());
+        ctx.update(&[0x01]);
+        for (key, translation) in records {
+            ctx.update(key.as_bytes());
+            ctx.update(&[0x00]);
+            ctx.update(translation.as_bytes());
+            ctx.update(&[0x00]);
+        }
+        ctx.update(&[0x02]);
+    }
+
+    ctx.finish()
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn sorted(locales: &[TranslationLocale]) -> BTreeMap<&str, BTreeMap<&str, &str>> {
+    locales
+        .iter()
+        .map(|l| {
+            let records = l
+                .translations
+                .iter()
+                .map(|t| (t.key.as_str(), t.translation.as_str()))
+                .collect();
+
+            (l.locale.as_str(), records)
+        })
+        .collect()
+}
+
+/// Records changed between translation sets (and keys removed from the old one)
+pub fn diff(
+    old: &[TranslationLocale],
+    new: &[TranslationLocale],
+) -> (Vec<TranslationLocale>, Vec<RemovedTranslation>) {
+    let (old, new) = (sorted(old), sorted(new));
+
+    let changed = new
+        .iter()
+        .filter_map(|(locale, records)| {
+            let old_records = old.get(locale);
+            let translations: Vec<TranslationRecord> = records
+                .iter()
+                .filter(|(key, translation)| {
+                    old_records.and_then(|o| o.get(*key)) != Some(*translation)
+                })
+                .map(|(key, translation)| TranslationRecord {
+                    key: key.to_string(),
+                    translation: translation.to_string(),
+                })
+                .collect();
+
+            (!translations.is_empty()).then(|| TranslationLocale {
+                locale: locale.to_string(),
+                translations,
+            })
+        })
+        .collect();
+
+    let removed = old
+        .iter()
+        .flat_map(|(locale, records)| {
+            records
+                .keys()
+                .filter(|key| new.get(locale).is_none_or(|n| !n.contains_key(*key)))
+                .map(|key| RemovedTranslation {
+                    locale: locale.to_string(),
+                    key: key.to_string(),
+                })
+        })
+        .collect();
+
+    (changed, removed)
+}
+
+/// Hash of current translation set and few previous sets (for deltas)
+#[derive(Debug, Clone)]
+pub struct TranslationCache {
+    pub hash: String,
+    history: VecDeque<(String, Vec<TranslationLocale>)>,
+}
+
+impl TranslationCache {
+    pub fn new(current: &[TranslationLocale]) -> Self {
+        Self {
+            hash: hash_translations(current),
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Called when translation set changes (with the old set)
+    pub fn update(&mut self, old: Vec<TranslationLocale>, new: &[TranslationLocale]) {
+        let old_hash = std::mem::replace(&mut self.hash, hash_translations(new));
+        if self.history.len() >= HISTORY_SIZE {
+            self.history.pop_front();
+        }
+
+        self.history.retain(|(hash, _)| *hash != old_hash);
+        self.history.push_back((old_hash, old));
+    }
+
+    /// What to send to device holding translations with given hash
+    pub fn update_for(
+        &self,
+        device_hash: Option<&str>,
+        current: &[TranslationLocale],
+    ) -> TranslationsUpdate {
+        let Some(device_hash) = device_hash else {
+            return TranslationsUpdate::Full;
+        };
+
+        if device_hash == self.hash {
+            return TranslationsUpdate::Unchanged;
+        }
+
+        match self.history.iter().find(|(hash, _)| hash == device_hash) {
+            Some((_, old)) => {
+                let (changed, removed) = diff(old, current);
+                TranslationsUpdate::Delta { changed, removed }
+            }
+            None => TranslationsUpdate::Full,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locale(locale: &str, records: &[(&str, &str)]) -> TranslationLocale {
+        TranslationLocale {
+            locale: locale.to_string(),
+            translations: records
+                .iter()
+                .map(|(key, translation)| TranslationRecord {
+                    key: key.to_string(),
+                    translation: translation.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn hash_and_delta() {
+        let v1 = vec![
+            locale("en", &[("a", "A"), ("b", "B")]),
+            locale("pl", &[("a", "A-pl")]),
+        ];
+        let v2 = vec![
+            locale("pl", &[("a", "A-pl")]),
+            locale("en", &[("b", "B2"), ("c", "C")]),
+        ];
+
+        let reordered = vec![
+            locale("pl", &[("a", "A-pl")]),
+            locale("en", &[("b", "B"), ("a", "A")]),
+        ];
+        assert_eq!(hash_translations(&v1), hash_translations(&reordered));
+        assert_ne!(hash_translations(&v1), hash_translations(&v2));
+
+        let mut cache = TranslationCache::new(&[]);
+        cache.update(Vec::new(), &v1);
+        let v1_hash = cache.hash.clone();
+        cache.update(v1, &v2);
+
+        assert_eq!(
+            cache.update_for(Some(&cache.hash), &v2),
+            TranslationsUpdate::Unchanged
+        );
+        assert_eq!(cache.update_for(None, &v2), TranslationsUpdate::Full);
+        assert_eq!(cache.update_for(Some("abc"), &v2), TranslationsUpdate::Full);
+        assert_eq!(
+            cache.update_for(Some(&v1_hash), &v2),
+            TranslationsUpdate::Delta {
+                changed: vec![locale("en", &[("b", "B2"), ("c", "C")])],
+                removed: vec![RemovedTranslation {
+                    locale: "en".to_string(),
+                    key: "a".to_string(),
+                }],
+            }
+        );
+    }
+}
@@ -0,0 +1,289 @@
+use crate::updater::Version;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::sync::{oneshot, watch};
+
+/// Devices currently updating and waiting for update slot (in order)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UpdateQueueState {
+    pub active: Vec<u32>,
+    pub waiting: Vec<u32>,
+}
+
+#[derive(Debug)]
+struct Waiting {
+    esp_id: u32,
+    since: Instant,
+    tx: oneshot::Sender<UpdateSlot>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    max_active: usize,
+    active: HashMap<u32, Instant>,
+    queue: VecDeque<Waiting>,
+
+    /// Failed (not completed) update attempts per device
+    attempts: HashMap<u32, u32>,
+
+    /// Version of last failed update (attempts are forgotten once device runs it)
+    targets: HashMap<u32, Version>,
+}
+
+/// How update slot was released
+#[derive(Debug, Clone, PartialEq)]
+enum SlotEnd {
+    Completed,
+    Failed(Option<Version>),
+
+    /// Nothing was sent (device doesn't need update anymore), attempt isn't counted
+    Unused,
+}
+
+/// Limits number of simultaneous firmware transfers.
+///
+/// Waiting devices are served in FIFO order, but devices with fewer failed
+/// attempts go first, so a device with bad connection can't starve others.
+#[derive(Debug)]
+pub struct UpdateScheduler {
+    inner: Mutex<Inner>,
+    queue_state: watch::Sender<UpdateQueueState>,
+}
+
+/// Permission to send firmware, releases slot (and starts next update) on drop
+#[derive(Debug)]
+pub struct UpdateSlot {
+    scheduler: Option<Arc<UpdateScheduler>>,
+    esp_id: u32,
+    end: SlotEnd,
+}
+
+impl UpdateSlot {
+    /// Update was sent successfully (device isn't deprioritized next time)
+    pub fn complete(mut self) {
+        self.end = SlotEnd::Completed;
+    }
+
+    /// Update to `target` failed (attempt counts until device reconnects running it)
+    pub fn fail(mut self, target: Version) {
+        self.end = SlotEnd::Failed(Some(target));
+    }
+
+    /// Slot isn't needed anymore, release it without counting attempt
+    pub fn cancel(mut self) {
+        self.end = SlotEnd::Unused;
+    }
+}
+
+impl Drop for UpdateSlot {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            let end = std::mem::replace(&mut self.end, SlotEnd::Unused);
+            scheduler.release(self.esp_id, end);
+        }
+    }
+}
+
+impl Inner {
+    /// Forget attempt counted when slot was granted
+    fn uncount_attempt(&mut self, esp_id: u32) {
+        if let Some(attempts) = self.attempts.get_mut(&esp_id) {
+            *attempts -= 1;
+            if *attempts == 0 {
+                self.attempts.remove(&esp_id);
+            }
+        }
+    }
+}
+
+impl UpdateScheduler {
+    pub fn new(max_active: usize) -> Arc<Self> {
+        Arc::new(Self {
+            inner: Mutex::new(Inner {
+                max_active,
+                active: HashMap::new(),
+                queue: VecDeque::new(),
+                attempts: HashMap::new(),
+                targets: HashMap::new(),
+            }),
+            queue_state: watch::Sender::new(UpdateQueueState::default()),
+        })
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<UpdateQueueState> {
+        self.queue_state.subscribe()
+    }
+
+    pub fn set_max_active(self: &Arc<Self>, max_active: usize) {
+        self.inner
+            .lock()
+            .expect("Scheduler lock poisoned")
+            .max_active = max_active;
+        self.dispatch();
+    }
+
+    /// Enqueue device, receiver gets slot when it's device's turn
+    /// (immediately if there is free slot)
+    pub fn request(self: &Arc<Self>, esp_id: u32) -> oneshot::Receiver<UpdateSlot> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut inner = self.inner.lock().expect("Scheduler lock poisoned");
+            match inner.queue.iter_mut().find(|w| w.esp_id == esp_id) {
+                // device reconnected, it keeps its place in queue
+                Some(waiting) => waiting.tx = tx,
+                None => inner.queue.push_back(Waiting {
+                    esp_id,
+                    since: Instant::now(),
+                    tx,
+                }),
+            }
+        }
+
+        self.dispatch();
+        rx
+    }
+
+    /// Device connected, failed attempts are forgotten if it runs version it failed to get
+    pub fn reconnected(&self, esp_id: u32, version: &Version) {
+        let mut inner = self.inner.lock().expect("Scheduler lock poisoned");
+        if inner.targets.get(&esp_id) == Some(version) {
+            inner.targets.remove(&esp_id);
+            inner.attempts.remove(&esp_id);
+        }
+    }
+
+    fn release(self: &Arc<Self>, esp_id: u32, end: SlotEnd) {
+        {
+            let mut inner = self.inner.lock().expect("Scheduler lock poisoned");
+            inner.active.remove(&esp_id);
+            match end {
+                SlotEnd::Completed => {
+                    inner.attempts.remove(&esp_id);
+                    inner.targets.remove(&esp_id);
+                }
+                SlotEnd::Failed(Some(target)) => {
+                    inner.targets.insert(esp_id, target);
+                }
+                SlotEnd::Failed(None) => {}
+                SlotEnd::Unused => inner.uncount_attempt(esp_id),
+            }
+        }
+
+        self.dispatch();
+    }
+
+    /// Grant free slots to waiting devices
+    fn dispatch(self: &Arc<Self>) {
+        loop {
+            let mut inner = self.inner.lock().expect("Scheduler lock poisoned");
+            inner.queue.retain(|w| !w.tx.is_closed());
+            if inner.active.len() >= inner.max_active {
+                break;
+            }
+
+            let next = inner
+                .queue
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, w)| (inner.attempts.get(&w.esp_id).copied(), w.since))
+                .map(|(i, _)| i);
+            let Some(waiting) = next.and_then(|i| inner.queue.remove(i)) else {
+                break;
+            };
+
+            inner.active.insert(waiting.esp_id, Instant::now());
+            *inner.attempts.entry(waiting.esp_id).or_default() += 1;
+            drop(inner);
+
+            let slot = UpdateSlot {
+                scheduler: Some(self.clone()),
+                esp_id: waiting.esp_id,
+                end: SlotEnd::Failed(None),
+            };
+
+            if let Err(mut slot) = waiting.tx.send(slot) {
+                // device disconnected in the meantime
+                slot.scheduler = None;
+                let mut inner = self.inner.lock().expect("Scheduler lock poisoned");
+                inner.active.remove(&waiting.esp_id);
+                inner.uncount_attempt(waiting.esp_id);
+            }
+        }
+
+        self.publish();
+    }
+
+    fn publish(&self) {
+        let inner = self.inner.lock().expect("Scheduler lock poisoned");
+        let mut active: Vec<u32> = inner.active.keys().copied().collect();
+        active.sort_unstable();
+
+        let mut waiting: Vec<&Waiting> = inner.queue.iter().collect();
+        waiting.sort_by_key(|w| (inner.attempts.get(&w.esp_id).copied(), w.since));
+        let state = UpdateQueueState {
+            active,
+            waiting: waiting.iter().map(|w| w.esp_id).collect(),
+        };
+
+        self.queue_state.send_if_modified(|current| {
+            let modified = *current != state;
+            *current = state;
+            modified
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedule_updates() {
+        let scheduler = UpdateScheduler::new(1);
+        let queue = scheduler.subscribe();
+
+        let mut first = scheduler.request(1);
+        let mut second = scheduler.request(2);
+        let mut third = scheduler.request(3);
+        let slot = first.try_recv().unwrap();
+        assert!(second.try_recv().is_err());
+        assert_eq!(
+            *queue.borrow(),
+            UpdateQueueState {
+                active: vec![1],
+                waiting: vec![2, 3],
+            }
+        );
+
+        // failed update, device 1 is requeued behind devices without failed attempts
+        drop(slot);
+        let slot = second.try_recv().unwrap();
+        let mut first = scheduler.request(1);
+        slot.complete();
+        let slot = third.try_recv().unwrap();
+        assert!(first.try_recv().is_err());
+
+        // disconnected device is skipped
+        let fourth = scheduler.request(4);
+        drop(fourth);
+        drop(slot);
+        let slot = first.try_recv().unwrap();
+        assert_eq!(queue.borrow().waiting, Vec::<u32>::new());
+
+        // unused slot doesn't count as attempt (device 1 still has one failed attempt)
+        slot.cancel();
+        assert_eq!(scheduler.inner.lock().unwrap().attempts.get(&1), Some(&1));
+
+        // device running version it failed to get isn't deprioritized anymore
+        let target = Version::from_str("v3.1.0");
+        let slot = scheduler.request(7).try_recv().unwrap();
+        slot.fail(target.clone());
+        scheduler.reconnected(7, &Version::from_str("v3.0.0"));
+        assert_eq!(scheduler.inner.lock().unwrap().attempts.get(&7), Some(&1));
+        scheduler.reconnected(7, &target);
+        assert!(!scheduler.inner.lock().unwrap().attempts.contains_key(&7));
+    }
+}
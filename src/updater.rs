@@ -1,22 +1,192 @@
 use crate::{
+    firmware_catalogue::Channel,
+    handler::send_packet,
     http::EspConnectInfo,
+    legacy,
+    shutdown::ActiveGuard,
+    signature,
+    socket::api,
     structs::{SharedAppState, TimerPacket, TimerPacketInner},
 };
 use anyhow::{Result, anyhow};
-use axum::extract::ws::{Message, WebSocket};
-use std::{fs::DirEntry, path::PathBuf};
+use axum::extract::ws::{CloseFrame, Message, WebSocket, close_code};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tracing::{debug, error, info};
+use unix_utils::request::UpdateFailureReason;
 
 const UPDATE_CHUNK_SIZE: usize = 1024 * 4;
 
+/// Max time without transfer progress (ack)
+const UPDATE_ACK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Time given to old firmware to process last chunk (it doesn't ack it)
+const SEQUENTIAL_FINISH_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Transferred update not verified (device didn't reconnect) within this time fails with `Timeout`
+const VERIFY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// Firmware capability: device can resume interrupted update (`UpdateResume`)
+pub const OTA_RESUME_CAPABILITY: &str = "ota_resume";
+
+/// Firmware capability: sequence numbered chunks acked with `UpdateAck`,
+/// optionally with preferred window (`ota_window=8`) and chunk size (`ota_chunk=8192`)
+pub const OTA_WINDOW_CAPABILITY: &str = "ota_window";
+pub const OTA_CHUNK_CAPABILITY: &str = "ota_chunk";
+
+const DEFAULT_WINDOW: usize = 4;
+const MAX_WINDOW: usize = 16;
+const MIN_CHUNK_SIZE: usize = 512;
+const MAX_CHUNK_SIZE: usize = 1024 * 16;
+
+/// Negotiated sliding window transfer parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransferWindow {
+    pub chunk_size: usize,
+
+    /// Max chunks sent but not acked yet
+    pub window: usize,
+}
+
+impl TransferWindow {
+    /// None if device doesn't support windowed transfer (acks every chunk with any frame)
+    pub fn negotiate(esp_connect_info: &EspConnectInfo) -> Option<Self> {
+        let window = esp_connect_info.cap_value(OTA_WINDOW_CAPABILITY)?;
+        let window = window
+            .parse()
+            .unwrap_or(DEFAULT_WINDOW)
+            .clamp(1, MAX_WINDOW);
+        let chunk_size = esp_connect_info
+            .cap_value(OTA_CHUNK_CAPABILITY)
+            .and_then(|c| c.parse().ok())
+            .unwrap_or(UPDATE_CHUNK_SIZE)
+            .clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE);
+
+        Some(Self { chunk_size, window })
+    }
+}
+
+/// Acknowledged part of transfer (kept for resume and reported to backend)
+struct TransferTracker {
+    esp_id: u32,
+    image_crc: u32,
+    size: usize,
+    reported_percentage: u8,
+}
+
+impl TransferTracker {
+    async fn acked(&mut self, state: &SharedAppState, acked: usize) {
+        state.update_progress.lock().await.insert(
+            self.esp_id,
+            TransferProgress {
+                image_crc: self.image_crc,
+                acked,
+            },
+        );
+
+        // reported in 10% steps
+        let percentage = (acked * 100 / self.size) as u8 / 10 * 10;
+        if percentage > self.reported_percentage {
+            self.reported_percentage = percentage;
+            _ = api::send_update_progress(self.esp_id, percentage).await;
+        }
+    }
+
+    async fn completed(&self, state: &SharedAppState) {
+        state.update_progress.lock().await.remove(&self.esp_id);
+    }
+}
+
+/// Bytes of image acknowledged by device during last (interrupted) update
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransferProgress {
+    pub image_crc: u32,
+    pub acked: usize,
+}
+
+pub type TransferProgressMap = HashMap<u32, TransferProgress>;
+
+/// Offset to continue update from: device prefix must match image (CRC)
+/// and can't be longer than the part it acknowledged before
+pub fn resume_offset(
+    data: &[u8],
+    progress: Option<&TransferProgress>,
+    offset: u32,
+    prefix_crc: u32,
+) -> usize {
+    let offset = offset as usize;
+    if offset == 0 || offset >= data.len() {
+        return 0;
+    }
+
+    let image_crc = crc32fast::hash(data);
+    if let Some(progress) = progress
+        && (progress.image_crc != image_crc || offset > progress.acked)
+    {
+        return 0;
+    }
+
+    if crc32fast::hash(&data[..offset]) != prefix_crc {
+        return 0;
+    }
+
+    offset
+}
+
 #[derive(Debug, Clone)]
 pub struct Firmware {
-    pub data: Vec<u8>,
+    pub image: FirmwareImage,
     pub version: Version,
     pub build_time: u64,
     pub firmware: String,
 }
 
+#[derive(Debug, Clone)]
+pub enum FirmwareImage {
+    /// Image in firmware dir (read when update starts)
+    File(PathBuf),
+
+    /// Uploaded image with its signature
+    Loaded {
+        data: Vec<u8>,
+        signature: Option<Vec<u8>>,
+    },
+}
+
+impl Firmware {
+    /// Image bytes and signature, image must still have the version it was indexed with
+    pub async fn load(&self) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+        let (data, image_signature) = self.image.load().await?;
+        let metadata = FirmwareMetadata::from_trailer(&data)
+            .ok_or_else(|| anyhow!("Missing firmware metadata"))?;
+        let (_, firmware, version) = metadata
+            .strings()
+            .ok_or_else(|| anyhow!("Wrong firmware metadata utf8"))?;
+
+        if Version::from_str(version) != self.version
+            || firmware != self.firmware
+            || metadata.build_time != self.build_time
+        {
+            return Err(anyhow!(
+                "Image changed since it was indexed (now {firmware} {version})"
+            ));
+        }
+
+        Ok((data, image_signature))
+    }
+}
+
+impl FirmwareImage {
+    /// Image bytes (without embedded signature) and signature
+    pub async fn load(&self) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+        match self {
+            Self::File(path) => signature::load_image(path).await,
+            Self::Loaded { data, signature } => Ok((data.clone(), signature.clone())),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct FirmwareMetadata {
@@ -25,7 +195,7 @@ pub struct FirmwareMetadata {
     pub hardware: [u8; 16],
     pub build_time: u64,
 }
-const METADATA_SIZE: usize = core::mem::size_of::<FirmwareMetadata>();
+pub const METADATA_SIZE: usize = core::mem::size_of::<FirmwareMetadata>();
 const VERSION_OFFSET: usize = 0;
 const FIRMWARE_OFFSET: usize = 32;
 const HARDWARE_OFFSET: usize = 48;
@@ -33,19 +203,79 @@ const BUILD_TIME_OFFSET: usize = 64;
 const BUILD_TIME_END: usize = BUILD_TIME_OFFSET + 8;
 
 impl FirmwareMetadata {
-    pub async fn from_dir_entry(entry: &DirEntry) -> Result<Self> {
-        let path = entry.path();
-        let file_data = tokio::fs::read(&path).await?;
+    /// Read only the end of file (trailer, possibly followed by embedded signature)
+    pub async fn read_trailer(path: &std::path::Path) -> Result<Self> {
         let file_name = path
             .file_stem()
             .ok_or_else(|| anyhow::anyhow!("file_name is none"))?
             .to_str()
             .ok_or_else(|| anyhow::anyhow!("file_name is none"))?;
 
-        Self::from_file(file_name, &file_data).await
+        let mut file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        let tail_len = len.min(
+            (METADATA_SIZE + signature::SIGNATURE_SIZE + signature::SIGNATURE_MAGIC.len()) as u64,
+        );
+
+        file.seek(std::io::SeekFrom::Start(len - tail_len)).await?;
+        let mut tail = vec![0; tail_len as usize];
+        file.read_exact(&mut tail).await?;
+
+        Self::from_file(file_name, &tail).await
     }
 
-    pub async fn from_file(file_name: &str, data: &[u8]) -> Result<Self> {
+    /// Hardware, firmware and version (without trailing zeros)
+    pub fn strings(&self) -> Option<(&str, &str, &str)> {
+        let (Ok(hw), Ok(firmware), Ok(version)) = (
+            core::str::from_utf8(&self.hardware),
+            core::str::from_utf8(&self.firmware),
+            core::str::from_utf8(&self.version),
+        ) else {
+            return None;
+        };
+
+        Some((
+            hw.trim_end_matches('\0'),
+            firmware.trim_end_matches('\0'),
+            version.trim_end_matches('\0'),
+        ))
+    }
+
+    /// New trailer (fails if strings don't fit into their fields)
+    pub fn new(hw: &str, firmware: &str, version: &str, build_time: u64) -> Result<Self> {
+        let mut metadata = FirmwareMetadata {
+            version: [0; 32],
+            firmware: [0; 16],
+            hardware: [0; 16],
+            build_time,
+        };
+
+        for (name, field, value) in [
+            ("version", &mut metadata.version[..], version),
+            ("firmware", &mut metadata.firmware[..], firmware),
+            ("hardware", &mut metadata.hardware[..], hw),
+        ] {
+            if value.len() > field.len() {
+                return Err(anyhow!("{name} is longer than {} bytes", field.len()));
+            }
+
+            field[..value.len()].copy_from_slice(value.as_bytes());
+        }
+
+        Ok(metadata)
+    }
+
+    pub fn to_bytes(&self) -> [u8; METADATA_SIZE] {
+        let mut bytes = [0; METADATA_SIZE];
+        bytes[VERSION_OFFSET..FIRMWARE_OFFSET].copy_from_slice(&self.version);
+        bytes[FIRMWARE_OFFSET..HARDWARE_OFFSET].copy_from_slice(&self.firmware);
+        bytes[HARDWARE_OFFSET..BUILD_TIME_OFFSET].copy_from_slice(&self.hardware);
+        bytes[BUILD_TIME_OFFSET..BUILD_TIME_END].copy_from_slice(&self.build_time.to_le_bytes());
+        bytes
+    }
+
+    /// Trailer at the end of image (without embedded signature), None if it isn't valid utf8
+    pub fn from_trailer(data: &[u8]) -> Option<Self> {
         if data.len() >= METADATA_SIZE {
             let metadata_bytes = &data[data.len() - METADATA_SIZE..];
 
@@ -76,10 +306,19 @@ impl FirmwareMetadata {
                 && core::str::from_utf8(&metadata.hardware).is_ok();
 
             if metadata_parse_success {
-                return Ok(metadata);
+                return Some(metadata);
             }
         }
 
+        None
+    }
+
+    pub async fn from_file(file_name: &str, data: &[u8]) -> Result<Self> {
+        let (data, _) = signature::split_embedded(data);
+        if let Some(metadata) = Self::from_trailer(data) {
+            return Ok(metadata);
+        }
+
         let name_split: Vec<&str> = file_name.split('_').collect();
         if name_split.len() != 3 {
             return Err(anyhow!("Wrong file name!"));
@@ -103,72 +342,204 @@ pub async fn should_update(
     state: &SharedAppState,
     esp_connect_info: &EspConnectInfo,
 ) -> Result<Option<Firmware>> {
-    if !state
+    let current_version = Version::from_str(&esp_connect_info.version);
+    let added = state
         .inner
         .read()
         .await
         .devices_settings
-        .contains_key(&esp_connect_info.id)
-    {
-        return Ok(None);
+        .contains_key(&esp_connect_info.id);
+    if !added {
+        // legacy devices can't be added before update, while bootstrap is enabled
+        // they only get newest image with current protocol (no pins, no staging)
+        if !legacy::is_legacy(esp_connect_info) || !state.config.read().await.legacy_bootstrap {
+            return Ok(None);
+        }
+
+        return Ok(state
+            .firmware_catalogue
+            .read()
+            .await
+            .latest(
+                &esp_connect_info.hw,
+                &esp_connect_info.firmware,
+                Channel::for_mode(state.dev_mode),
+            )
+            .filter(|entry| !legacy::is_legacy_version(&entry.version))
+            .filter(|entry| current_version.is_newer(&entry.version))
+            .map(|entry| entry.firmware()));
     }
 
-    let firmware_dir = std::env::var("FIRMWARE_DIR")?;
-    let firmware_dir = std::path::PathBuf::from(firmware_dir);
+    let pin = state
+        .firmware_pins
+        .lock()
+        .await
+        .get(esp_connect_info.id, &esp_connect_info.hw)
+        .map(Version::from_str);
 
-    let mut latest_firmware: (Option<PathBuf>, Version, String) = (
-        None,
-        Version::from_str(&esp_connect_info.version),
-        String::new(),
+    // pinned version is sent even if it's older (and isn't staged)
+    if let Some(pin) = pin {
+        if pin == current_version {
+            return Ok(None);
+        }
+
+        let firmware = state
+            .firmware_catalogue
+            .read()
+            .await
+            .find(&esp_connect_info.hw, &esp_connect_info.firmware, &pin)
+            .map(|entry| entry.firmware());
+
+        if firmware.is_none() {
+            tracing::warn!(
+                "[{:X}] Pinned firmware {pin} not found in firmware dir",
+                esp_connect_info.id
+            );
+        }
+
+        return Ok(firmware);
+    }
+
+    let latest_firmware = state
+        .firmware_catalogue
+        .read()
+        .await
+        .latest(
+            &esp_connect_info.hw,
+            &esp_connect_info.firmware,
+            Channel::for_mode(state.dev_mode),
+        )
+        .filter(|entry| current_version.is_newer(&entry.version))
+        .map(|entry| entry.firmware());
+
+    let Some(latest_firmware) = latest_firmware else {
+        return Ok(None);
+    };
+
+    let rollout = state.config.read().await.rollout();
+    let allowed = state.rollouts.lock().await.allow(
+        &rollout,
+        &esp_connect_info.hw,
+        &esp_connect_info.firmware,
+        &latest_firmware.version,
+        esp_connect_info.id,
+        crate::rollout::now_ms(),
     );
+    if !allowed {
+        return Ok(None);
+    }
 
-    for entry in firmware_dir.read_dir()? {
-        let entry = entry?;
-        let metadata = FirmwareMetadata::from_dir_entry(&entry).await;
-        let Ok(metadata) = metadata else {
-            tracing::error!("metadata error: {:?}", metadata.expect_err(""));
-            continue;
-        };
+    Ok(Some(latest_firmware))
+}
 
-        let (Ok(hw), Ok(firmware), Ok(version)) = (
-            core::str::from_utf8(&metadata.hardware).map(|x| x.trim_end_matches('\0')),
-            core::str::from_utf8(&metadata.firmware).map(|x| x.trim_end_matches('\0')),
-            core::str::from_utf8(&metadata.version).map(|x| x.trim_end_matches('\0')),
-        ) else {
-            continue;
-        };
+/// Transfer didn't finish in time (reported as `Timeout`, other errors as `Closed`)
+#[derive(Debug)]
+struct UpdateTimeout;
 
-        let version = Version::from_str(version);
-        if hw != esp_connect_info.hw || firmware != esp_connect_info.firmware {
-            continue;
-        }
+impl std::fmt::Display for UpdateTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Timeout while updating")
+    }
+}
 
-        if (state.dev_mode && version.is_stable()) || (!state.dev_mode && version.is_dev()) {
-            continue;
-        }
+impl std::error::Error for UpdateTimeout {}
 
-        if latest_firmware.1.is_newer(&version) {
-            latest_firmware = (Some(entry.path()), version, firmware.to_string());
-        }
+enum TransferEnd {
+    Completed,
+    Aborted,
+    Closed,
+}
+
+/// Failure reported to backend (None if transfer completed or was aborted on shutdown)
+fn failure_reason(res: &Result<TransferEnd>) -> Option<UpdateFailureReason> {
+    match res {
+        Ok(TransferEnd::Completed | TransferEnd::Aborted) => None,
+        Ok(TransferEnd::Closed) => Some(UpdateFailureReason::Closed),
+        Err(e) if e.is::<UpdateTimeout>() => Some(UpdateFailureReason::Timeout),
+        Err(_) => Some(UpdateFailureReason::Closed),
     }
+}
 
-    if latest_firmware.0.is_none() {
-        return Ok(None);
+/// Update transferred to device, waiting for device to reconnect
+#[derive(Debug, Clone)]
+pub struct SentUpdate {
+    pub version: Version,
+    pub sent_at: std::time::Instant,
+}
+
+#[derive(Debug, PartialEq)]
+enum UpdateCheck {
+    Verified(Version),
+    Mismatch(Version),
+}
+
+/// Check (and remove) update sent to device before, None if there is no pending update
+fn check_sent_update(
+    sent_updates: &mut HashMap<u32, SentUpdate>,
+    esp_id: u32,
+    reported: &Version,
+) -> Option<UpdateCheck> {
+    let target = sent_updates.remove(&esp_id)?.version;
+    if *reported == target {
+        Some(UpdateCheck::Verified(target))
+    } else {
+        Some(UpdateCheck::Mismatch(target))
     }
+}
 
-    Ok(Some(Firmware {
-        data: tokio::fs::read(latest_firmware.0.expect("Cant be none")).await?,
-        version: latest_firmware.1,
-        build_time: 0,
-        firmware: latest_firmware.2,
-    }))
+/// Remove updates not verified within [`VERIFY_TIMEOUT`]
+fn expire_sent_updates(
+    sent_updates: &mut HashMap<u32, SentUpdate>,
+    now: std::time::Instant,
+) -> Vec<(u32, Version)> {
+    let expired: Vec<u32> = sent_updates
+        .iter()
+        .filter(|(_, sent)| now.duration_since(sent.sent_at) > VERIFY_TIMEOUT)
+        .map(|(esp_id, _)| *esp_id)
+        .collect();
+
+    expired
+        .into_iter()
+        .filter_map(|esp_id| Some((esp_id, sent_updates.remove(&esp_id)?.version)))
+        .collect()
 }
 
+/// Returns true if connection should be closed (update sent or aborted)
 pub async fn update_client(
     socket: &mut WebSocket,
     esp_connect_info: &EspConnectInfo,
+    state: &SharedAppState,
     latest_firmware: Firmware,
 ) -> Result<bool> {
+    let (data, image_signature) = match latest_firmware.load().await {
+        Ok(image) => image,
+        Err(e) => {
+            error!(
+                "[{:X}] Cannot load firmware {}: {e}",
+                esp_connect_info.id, latest_firmware.version
+            );
+            return Ok(false);
+        }
+    };
+
+    let public_keys = state.config.read().await.firmware_public_keys.clone();
+    if let Err(e) = signature::verify(
+        &data,
+        image_signature.as_deref(),
+        &public_keys,
+        state.dev_mode,
+    ) {
+        error!(
+            "[{:X}] Refusing to send firmware {}: {e}",
+            esp_connect_info.id, latest_firmware.version
+        );
+        if let FirmwareImage::File(path) = &latest_firmware.image {
+            state.firmware_catalogue.write().await.reject(path);
+        }
+        return Ok(false);
+    }
+
+    let _update = ActiveGuard::new(&state.active_updates);
     info!(
         "[{:X}/{}] Updating client from version: {} to version {}",
         esp_connect_info.id,
@@ -177,79 +548,445 @@ pub async fn update_client(
         latest_firmware.version
     );
 
-    let crc = crc32fast::hash(&latest_firmware.data);
+    let esp_id = esp_connect_info.id;
+    let version = latest_firmware.version.clone();
+    state.rollouts.lock().await.begin(
+        &esp_connect_info.hw,
+        &esp_connect_info.firmware,
+        &version,
+        esp_id,
+        crate::rollout::now_ms(),
+    );
+    crate::rollout::flush(state).await;
+    _ = api::send_update_started(esp_id, &esp_connect_info.version, &version.inner_version()).await;
+
+    let res = send_firmware(socket, esp_connect_info, state, &latest_firmware, &data).await;
+    if let Ok(TransferEnd::Completed) = res {
+        info!("[{esp_id:X}] Update to {version} transferred");
+        state.inner.write().await.sent_updates.insert(
+            esp_id,
+            SentUpdate {
+                version: version.clone(),
+                sent_at: std::time::Instant::now(),
+            },
+        );
+
+        _ = api::send_update_completed(esp_id, &version.inner_version()).await;
+        return Ok(true);
+    }
+
+    state.rollouts.lock().await.cancel(
+        &esp_connect_info.hw,
+        &esp_connect_info.firmware,
+        &version,
+        esp_id,
+    );
+    crate::rollout::flush(state).await;
+
+    let Some(reason) = failure_reason(&res) else {
+        return Ok(true);
+    };
+
+    error!("[{esp_id:X}] Update to {version} failed: {reason:?}");
+    _ = api::send_update_failed(esp_id, &version.inner_version(), reason, None).await;
+    res.map(|_| false)
+}
+
+/// Device reconnected, verify version of update sent to it before
+pub async fn verify_update(state: &SharedAppState, esp_connect_info: &EspConnectInfo) {
+    let esp_id = esp_connect_info.id;
+    let reported = Version::from_str(&esp_connect_info.version);
+    let check = check_sent_update(
+        &mut state.inner.write().await.sent_updates,
+        esp_id,
+        &reported,
+    );
+
+    match check {
+        Some(UpdateCheck::Verified(target)) => {
+            info!("[{esp_id:X}] Update to {target} verified");
+            _ = api::send_update_verified(esp_id, &target.inner_version()).await;
+        }
+        Some(UpdateCheck::Mismatch(target)) => {
+            error!("[{esp_id:X}] Update to {target} failed, device reported {reported}");
+            _ = api::send_update_failed(
+                esp_id,
+                &target.inner_version(),
+                UpdateFailureReason::VersionMismatch,
+                Some(esp_connect_info.version.clone()),
+            )
+            .await;
+        }
+        None => {}
+    }
+}
+
+/// Report updates whose devices didn't reconnect in time (called periodically)
+pub async fn expire_updates(state: &SharedAppState) {
+    let expired = expire_sent_updates(
+        &mut state.inner.write().await.sent_updates,
+        std::time::Instant::now(),
+    );
+
+    for (esp_id, target) in expired {
+        error!("[{esp_id:X}] Update to {target} failed, device didn't reconnect");
+        _ = api::send_update_failed(
+            esp_id,
+            &target.inner_version(),
+            UpdateFailureReason::Timeout,
+            None,
+        )
+        .await;
+    }
+}
+
+async fn send_firmware(
+    socket: &mut WebSocket,
+    esp_connect_info: &EspConnectInfo,
+    state: &SharedAppState,
+    latest_firmware: &Firmware,
+    data: &[u8],
+) -> Result<TransferEnd> {
+    let esp_id = esp_connect_info.id;
+    let resume = esp_connect_info.has_cap(OTA_RESUME_CAPABILITY);
+    let window = TransferWindow::negotiate(esp_connect_info);
+    let crc = crc32fast::hash(data);
     let start_update_resp = TimerPacket {
         tag: None,
         data: TimerPacketInner::StartUpdate {
             version: latest_firmware.version.inner_version(),
             build_time: latest_firmware.build_time,
-            size: latest_firmware.data.len() as u32,
+            size: data.len() as u32,
             crc,
-            firmware: latest_firmware.firmware,
+            firmware: latest_firmware.firmware.clone(),
+            resume,
+            chunk_size: window.map(|w| w.chunk_size as u32),
+            window: window.map(|w| w.window as u32),
         },
     };
 
-    socket
-        .send(Message::Text(
-            serde_json::to_string(&start_update_resp)?.into(),
-        ))
-        .await?;
+    send_packet(socket, esp_connect_info, start_update_resp).await?;
 
     // wait for esp to respond
-    tokio::time::timeout(std::time::Duration::from_secs(10), socket.recv())
+    let frame = tokio::time::timeout(UPDATE_ACK_TIMEOUT, socket.recv())
         .await
-        .map_err(|_| {
-            error!("Timeout while updating");
-            anyhow::anyhow!("Timeout while updating")
-        })?;
+        .map_err(|_| UpdateTimeout)?;
+
+    let mut offset = 0;
+    if resume {
+        let device_progress = match frame {
+            Some(Ok(Message::Text(payload))) => serde_json::from_str::<TimerPacket>(&payload)
+                .ok()
+                .and_then(|packet| match packet.data {
+                    TimerPacketInner::UpdateResume { offset, crc } => Some((offset, crc)),
+                    _ => None,
+                }),
+            _ => None,
+        };
+
+        if let Some((device_offset, prefix_crc)) = device_progress {
+            let progress = state.update_progress.lock().await.get(&esp_id).cloned();
+            offset = resume_offset(data, progress.as_ref(), device_offset, prefix_crc);
+
+            info!(
+                "[{esp_id:X}] Device has {device_offset} bytes of update, resuming from {offset}"
+            );
+        }
+
+        let resume_ack = TimerPacket {
+            tag: None,
+            data: TimerPacketInner::UpdateResumeAck {
+                offset: offset as u32,
+            },
+        };
+        send_packet(socket, esp_connect_info, resume_ack).await?;
+    }
+
+    let mut tracker = TransferTracker {
+        esp_id,
+        image_crc: crc,
+        size: data.len(),
+        reported_percentage: 0,
+    };
+
+    let res = match window {
+        Some(window) => send_windowed(socket, state, &mut tracker, data, offset, window).await?,
+        None => send_sequential(socket, state, &mut tracker, data, offset).await?,
+    };
+
+    if let TransferEnd::Completed = res {
+        tracker.completed(state).await;
+    }
+
+    Ok(res)
+}
+
+/// Frames of firmware transfer (implemented by [`WebSocket`], replaced by fake device in tests)
+trait UpdateSocket {
+    async fn send(&mut self, msg: Message) -> Result<()>;
+    async fn recv(&mut self) -> Option<Result<Message>>;
+}
+
+impl UpdateSocket for WebSocket {
+    async fn send(&mut self, msg: Message) -> Result<()> {
+        Ok(WebSocket::send(self, msg).await?)
+    }
+
+    async fn recv(&mut self) -> Option<Result<Message>> {
+        WebSocket::recv(self)
+            .await
+            .map(|res| res.map_err(Into::into))
+    }
+}
+
+async fn abort_update(socket: &mut impl UpdateSocket, esp_id: u32) -> TransferEnd {
+    error!("[{esp_id:X}] Update aborted (shutdown)");
+    let frame = CloseFrame {
+        code: close_code::RESTART,
+        reason: crate::shutdown::SHUTDOWN_REASON.into(),
+    };
+
+    _ = socket.send(Message::Close(Some(frame))).await;
+    TransferEnd::Aborted
+}
+
+/// Chunks prefixed with sequence number (u32 LE), up to `window` chunks wait for
+/// cumulative `UpdateAck` (all chunks up to `seq` received).
+/// Sequence numbers are relative to resume `offset` (chunk `0` starts at `offset`).
+async fn send_windowed(
+    socket: &mut impl UpdateSocket,
+    state: &SharedAppState,
+    tracker: &mut TransferTracker,
+    data: &[u8],
+    offset: usize,
+    window: TransferWindow,
+) -> Result<TransferEnd> {
+    let esp_id = tracker.esp_id;
+    let chunks: Vec<&[u8]> = data[offset..].chunks(window.chunk_size).collect();
+    let (mut sent, mut acked) = (0, 0);
+    let mut deadline = tokio::time::Instant::now() + UPDATE_ACK_TIMEOUT;
+
+    while acked < chunks.len() {
+        if state.updates_aborted() {
+            return Ok(abort_update(socket, esp_id).await);
+        }
+
+        while sent < chunks.len() && sent - acked < window.window {
+            let mut frame = Vec::with_capacity(4 + chunks[sent].len());
+            frame.extend_from_slice(&(sent as u32).to_le_bytes());
+            frame.extend_from_slice(chunks[sent]);
+            socket.send(Message::Binary(frame.into())).await?;
+            sent += 1;
+        }
+
+        let frame = tokio::time::timeout_at(deadline, socket.recv())
+            .await
+            .map_err(|_| UpdateTimeout)?;
+
+        let payload = match frame.ok_or_else(|| anyhow::anyhow!("Frame option is none"))?? {
+            Message::Close(_) => return Ok(TransferEnd::Closed),
+            Message::Text(payload) => payload,
+            _ => continue,
+        };
+
+        // other packets (logs etc.) sent by device during update are ignored
+        let Ok(TimerPacket {
+            data: TimerPacketInner::UpdateAck { seq },
+            ..
+        }) = serde_json::from_str(&payload)
+        else {
+            continue;
+        };
+
+        let seq = seq as usize;
+        if seq >= sent || seq < acked {
+            debug!("[{esp_id:X}] Unexpected update ack {seq} (sent: {sent}, acked: {acked})");
+            continue;
+        }
+
+        acked = seq + 1;
+        deadline = tokio::time::Instant::now() + UPDATE_ACK_TIMEOUT;
+        tracker
+            .acked(state, (offset + acked * window.chunk_size).min(data.len()))
+            .await;
+
+        if acked.is_multiple_of(10) {
+            debug!(
+                "[{esp_id:X}] {}/{} chunks left",
+                chunks.len() - acked,
+                chunks.len()
+            );
+        }
+    }
+
+    Ok(TransferEnd::Completed)
+}
 
-    let mut firmware_chunks = latest_firmware.data.chunks(UPDATE_CHUNK_SIZE);
+/// Old protocol: any frame from device acks previous chunk.
+/// Old firmware doesn't confirm the last chunk (it verifies and flashes the image,
+/// then reboots), closing connection before that aborts its update, so it gets
+/// [`SEQUENTIAL_FINISH_DELAY`] instead. Devices with `ota_window` ack every chunk.
+async fn send_sequential(
+    socket: &mut impl UpdateSocket,
+    state: &SharedAppState,
+    tracker: &mut TransferTracker,
+    data: &[u8],
+    offset: usize,
+) -> Result<TransferEnd> {
+    let esp_id = tracker.esp_id;
+    let total_chunks = data.len().div_ceil(UPDATE_CHUNK_SIZE);
+    let mut acked = offset;
+    let mut firmware_chunks = data[offset..].chunks(UPDATE_CHUNK_SIZE);
 
     while let Some(chunk) = firmware_chunks.next() {
+        if state.updates_aborted() {
+            return Ok(abort_update(socket, esp_id).await);
+        }
+
         let msg = Message::Binary(chunk.to_vec().into());
         socket.send(msg).await?;
 
         if firmware_chunks.len().is_multiple_of(10) {
             debug!(
-                "[{:X}] {}/{} chunks left",
-                esp_connect_info.id,
+                "[{esp_id:X}] {}/{} chunks left",
                 firmware_chunks.len(),
-                latest_firmware.data.len() / UPDATE_CHUNK_SIZE
+                total_chunks
             );
         }
 
         if firmware_chunks.len() == 0 {
-            tokio::time::sleep(std::time::Duration::from_secs(5)).await; // Wait for esp to process
-            // (important)
-
+            tokio::time::sleep(SEQUENTIAL_FINISH_DELAY).await;
             break;
         }
 
-        let frame = tokio::time::timeout(std::time::Duration::from_secs(10), socket.recv())
-            .await
-            .map_err(|_| {
-                error!("Timeout while updating {:X}", esp_connect_info.id);
-                anyhow::anyhow!("Timeout while updating")
-            })?;
+        let deadline = tokio::time::Instant::now() + UPDATE_ACK_TIMEOUT;
+        loop {
+            let frame = tokio::time::timeout_at(deadline, socket.recv())
+                .await
+                .map_err(|_| UpdateTimeout)?;
 
-        let frame = frame.ok_or_else(|| anyhow::anyhow!("Frame option is none"))??;
-        if let Message::Close(_) = frame {
-            return Ok(false);
+            match frame.ok_or_else(|| anyhow::anyhow!("Frame option is none"))?? {
+                Message::Close(_) => return Ok(TransferEnd::Closed),
+                Message::Ping(_) | Message::Pong(_) => continue,
+                _ => break,
+            }
+        }
+
+        acked += chunk.len();
+        tracker.acked(state, acked).await;
+    }
+
+    Ok(TransferEnd::Completed)
+}
+
+/// Pre-release identifier (SemVer 2.0)
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum PreRelease {
+    /// Numeric identifiers have lower precedence than alphanumeric ones
+    Numeric(u64),
+    AlphaNumeric(String),
+}
+
+/// Parsed stable version (SemVer 2.0), build metadata is ignored
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SemVer {
+    core: [u64; 3],
+    pre: Vec<PreRelease>,
+}
+
+impl SemVer {
+    /// Missing minor/patch are treated as 0 (`2.4` == `2.4.0`)
+    fn parse(string: &str) -> Option<Self> {
+        let version = string.split_once('+').map_or(string, |(version, build)| {
+            // build metadata must be valid even if it's ignored
+            let valid = build.split('.').all(Self::valid_identifier);
+            if valid { version } else { "" }
+        });
+
+        let (core, pre) = match version.split_once('-') {
+            Some((core, pre)) => (core, Some(pre)),
+            None => (version, None),
+        };
+
+        let parts: Vec<&str> = core.split('.').collect();
+        if parts.is_empty() || parts.len() > 3 {
+            return None;
+        }
+
+        let mut numbers = [0u64; 3];
+        for (number, part) in numbers.iter_mut().zip(&parts) {
+            *number = Self::numeric(part)?;
         }
+
+        let pre = match pre {
+            Some(pre) => pre
+                .split('.')
+                .map(|identifier| {
+                    if !Self::valid_identifier(identifier) {
+                        return None;
+                    }
+
+                    if identifier.bytes().all(|b| b.is_ascii_digit()) {
+                        Self::numeric(identifier).map(PreRelease::Numeric)
+                    } else {
+                        Some(PreRelease::AlphaNumeric(identifier.to_string()))
+                    }
+                })
+                .collect::<Option<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+
+        Some(Self { core: numbers, pre })
     }
 
-    Ok(true)
+    /// Number without leading zeros
+    fn numeric(string: &str) -> Option<u64> {
+        let digits = !string.is_empty() && string.bytes().all(|b| b.is_ascii_digit());
+        if !digits || (string.len() > 1 && string.starts_with('0')) {
+            return None;
+        }
+
+        string.parse().ok()
+    }
+
+    fn valid_identifier(identifier: &str) -> bool {
+        !identifier.is_empty()
+            && identifier
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    }
+}
+
+impl PartialOrd for SemVer {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
-/// Inner u128 is calculated version number,
-/// if version is newer, the number should be bigger
+impl Ord for SemVer {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // release has higher precedence than its pre-releases,
+        // otherwise identifiers are compared one by one (longer set wins if all equal)
+        self.core
+            .cmp(&other.core)
+            .then_with(|| match (self.pre.is_empty(), other.pre.is_empty()) {
+                (true, true) => std::cmp::Ordering::Equal,
+                (true, false) => std::cmp::Ordering::Greater,
+                (false, true) => std::cmp::Ordering::Less,
+                (false, false) => self.pre.cmp(&other.pre),
+            })
+    }
+}
+
+/// Stable versions are ordered by SemVer 2.0 precedence,
+/// dev versions by build epoch
 /// * Important: you can't compare between Dev and Stable version!
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Version {
-    /// Like v2.1.0
+    /// Like v2.1.0 or v3.0.0-rc.1 (valid SemVer, without `v`)
     Stable(String),
 
-    /// Like DV542356675 (epoch build time)
+    /// Like D1714320292 or DV1714320292 (epoch build time)
     Dev(u128),
 
     /// Any other version string
@@ -258,11 +995,17 @@ pub enum Version {
 
 impl Version {
     pub fn from_str(string: &str) -> Self {
-        if string.starts_with("v") {
-            Self::Stable(string.trim_start_matches('v').to_string())
-        } else if string.starts_with('D') {
-            let nmb = string.trim_start_matches('D').parse().unwrap_or(0);
-            Self::Dev(nmb)
+        if let Some(version) = string.strip_prefix('v') {
+            match SemVer::parse(version) {
+                Some(_) => Self::Stable(version.to_string()),
+                None => Self::Other,
+            }
+        } else if let Some(epoch) = string.strip_prefix('D') {
+            let epoch = epoch.strip_prefix('V').unwrap_or(epoch);
+            match epoch.parse() {
+                Ok(epoch) => Self::Dev(epoch),
+                Err(_) => Self::Other,
+            }
         } else {
             Self::Other
         }
@@ -270,17 +1013,18 @@ impl Version {
 
     /// Check if version provided as input is newer than self
     pub fn is_newer(&self, ver: &Self) -> bool {
-        if ver == &Version::Other {
-            return false;
-        }
-
-        // Only compare if version variants are the same!
-        if matches!(ver, Version::Stable(..)) && matches!(self, Version::Stable(..)) {
-            Self::compare_stable(&self.get_str(), &ver.get_str())
-        } else if matches!(ver, Version::Dev(..)) && matches!(self, Version::Dev(..)) {
-            ver.get_nmb() > self.get_nmb()
-        } else {
-            true
+        match (self, ver) {
+            (_, Version::Other) => false,
+
+            // Only compare if version variants are the same!
+            (Version::Stable(current), Version::Stable(new)) => {
+                match (SemVer::parse(current), SemVer::parse(new)) {
+                    (Some(current), Some(new)) => new > current,
+                    _ => false,
+                }
+            }
+            (Version::Dev(current), Version::Dev(new)) => new > current,
+            _ => true,
         }
     }
 
@@ -300,55 +1044,6 @@ impl Version {
         false
     }
 
-    /// return if v2 is newer than v1 (for stable only)
-    fn compare_stable(v1: &str, v2: &str) -> bool {
-        let v1 = v1.split('.').collect::<Vec<_>>();
-        let v2 = v2.split('.').collect::<Vec<_>>();
-
-        let mut same_beg = true;
-        for i in 0..v1.len() {
-            if let Some(v2) = v2.get(i) {
-                if v1[i] != *v2 {
-                    same_beg = false;
-                }
-
-                let v1 = v1[i].parse().unwrap_or(-1);
-                let v2 = v2.parse().unwrap_or(-1);
-
-                if v2 > v1 {
-                    return true;
-                } else if v2 < v1 {
-                    return false;
-                }
-            } else {
-                break;
-            }
-        }
-
-        // if same beggining and the second one is longer (dot parts)
-        if same_beg && v2.len() > v1.len() {
-            return true;
-        }
-
-        false
-    }
-
-    /// Only used for dev comparison
-    fn get_nmb(&self) -> u128 {
-        match self {
-            Self::Dev(d) => *d,
-            _ => 0,
-        }
-    }
-
-    /// Only used for stable comparison
-    fn get_str(&self) -> String {
-        match self {
-            Self::Stable(s) => s.to_string(),
-            _ => "".to_string(),
-        }
-    }
-
     pub fn inner_version(&self) -> String {
         match self {
             Self::Stable(s) => s.to_string(),
@@ -358,6 +1053,22 @@ impl Version {
     }
 }
 
+/// Stable versions are equal if they have the same precedence
+/// (`v2.4` == `v2.4.0`, build metadata is ignored)
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Stable(a), Self::Stable(b)) => match (SemVer::parse(a), SemVer::parse(b)) {
+                (Some(a), Some(b)) => a == b,
+                _ => a == b,
+            },
+            (Self::Dev(a), Self::Dev(b)) => a == b,
+            (Self::Other, Self::Other) => true,
+            _ => false,
+        }
+    }
+}
+
 impl std::fmt::Display for Version {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -371,69 +1082,418 @@ impl std::fmt::Display for Version {
 #[cfg(test)]
 mod tests {
     #[test]
-    fn check() {
+    fn resume() {
+        use crate::updater::{TransferProgress, resume_offset};
+
+        let data: Vec<u8> = (0..10000u32).map(|i| i as u8).collect();
+        let prefix_crc = crc32fast::hash(&data[..4096]);
+        let progress = TransferProgress {
+            image_crc: crc32fast::hash(&data),
+            acked: 8192,
+        };
+
         assert_eq!(
-            crate::updater::Version::Other.is_newer(&crate::updater::Version::from_str("v2.1.0")),
-            true
+            resume_offset(&data, Some(&progress), 4096, prefix_crc),
+            4096
         );
+        assert_eq!(resume_offset(&data, None, 4096, prefix_crc), 4096);
+        assert_eq!(resume_offset(&data, Some(&progress), 4096, 0), 0);
+        assert_eq!(resume_offset(&data, Some(&progress), 10000, 0), 0);
 
+        let progress = TransferProgress {
+            acked: 1024,
+            ..progress
+        };
+        assert_eq!(resume_offset(&data, Some(&progress), 4096, prefix_crc), 0);
+
+        let progress = TransferProgress {
+            image_crc: 1,
+            acked: 8192,
+        };
+        assert_eq!(resume_offset(&data, Some(&progress), 4096, prefix_crc), 0);
+    }
+
+    #[test]
+    fn equality() {
+        use crate::updater::Version;
+
+        let eq = |a: &str, b: &str| Version::from_str(a) == Version::from_str(b);
+        assert!(eq("v2.4", "v2.4.0"));
+        assert!(eq("v3.0.0+build.5", "v3.0.0"));
+        assert!(eq("v3.0.0-rc.1+build.5", "v3.0.0-rc.1+build.6"));
+        assert!(!eq("v3.0.0-rc.1", "v3.0.0"));
+        assert!(!eq("v3.0.1", "v3.0.0"));
+        assert!(eq("DV1714320292", "D1714320292"));
+        assert!(!eq("D1714320292", "v3.0.0"));
+    }
+
+    #[test]
+    fn transfer_end_reason() {
+        use crate::updater::{TransferEnd, UpdateTimeout, failure_reason};
+        use unix_utils::request::UpdateFailureReason;
+
+        assert_eq!(failure_reason(&Ok(TransferEnd::Completed)), None);
+        assert_eq!(failure_reason(&Ok(TransferEnd::Aborted)), None);
         assert_eq!(
-            crate::updater::Version::Other.is_newer(&crate::updater::Version::from_str("DV1321")),
-            true
+            failure_reason(&Ok(TransferEnd::Closed)),
+            Some(UpdateFailureReason::Closed)
         );
-
         assert_eq!(
-            crate::updater::Version::from_str("DV1714320292")
-                .is_newer(&crate::updater::Version::from_str("v2.1.0")),
-            true
+            failure_reason(&Err(UpdateTimeout.into())),
+            Some(UpdateFailureReason::Timeout)
         );
-
         assert_eq!(
-            crate::updater::Version::from_str("DV1714320292")
-                .is_newer(&crate::updater::Version::from_str("DV1714320295")),
-            false
+            failure_reason(&Err(anyhow::anyhow!("Connection reset"))),
+            Some(UpdateFailureReason::Closed)
         );
+    }
+
+    #[test]
+    fn verify_sent_update() {
+        use crate::updater::{
+            SentUpdate, UpdateCheck, VERIFY_TIMEOUT, Version, check_sent_update,
+            expire_sent_updates,
+        };
+        use std::{collections::HashMap, time::Instant};
+
+        let now = Instant::now();
+        let sent = |version: &str| SentUpdate {
+            version: Version::from_str(version),
+            sent_at: now,
+        };
+        let mut sent_updates = HashMap::from([(1, sent("v3.1.0")), (2, sent("v3.1.0"))]);
 
         assert_eq!(
-            crate::updater::Version::from_str("DV1714320292")
-                .is_newer(&crate::updater::Version::from_str("DV1714320291")),
-            false
+            check_sent_update(&mut sent_updates, 1, &Version::from_str("v3.1.0")),
+            Some(UpdateCheck::Verified(Version::from_str("v3.1.0")))
         );
-
         assert_eq!(
-            crate::updater::Version::from_str("v2.1")
-                .is_newer(&crate::updater::Version::from_str("v2.1.0")),
-            true
+            check_sent_update(&mut sent_updates, 1, &Version::from_str("v3.1.0")),
+            None
         );
-
         assert_eq!(
-            crate::updater::Version::from_str("v2.1.0")
-                .is_newer(&crate::updater::Version::from_str("v2.1.12")),
-            true
+            check_sent_update(&mut sent_updates, 2, &Version::from_str("v3.0.0")),
+            Some(UpdateCheck::Mismatch(Version::from_str("v3.1.0")))
         );
-
         assert_eq!(
-            crate::updater::Version::from_str("v2.0.1")
-                .is_newer(&crate::updater::Version::from_str("v2.0.0")),
-            false
+            check_sent_update(&mut sent_updates, 3, &Version::from_str("v3.0.0")),
+            None
         );
 
+        sent_updates.insert(4, sent("v3.2.0"));
+        assert!(expire_sent_updates(&mut sent_updates, now + VERIFY_TIMEOUT).is_empty());
         assert_eq!(
-            crate::updater::Version::from_str("v2.0.0")
-                .is_newer(&crate::updater::Version::from_str("v2.0.0")),
-            false
+            expire_sent_updates(
+                &mut sent_updates,
+                now + VERIFY_TIMEOUT + std::time::Duration::from_secs(1)
+            ),
+            vec![(4, Version::from_str("v3.2.0"))]
         );
+        assert!(sent_updates.is_empty());
+    }
 
-        assert_eq!(
-            crate::updater::Version::from_str("v2.2.0")
-                .is_newer(&crate::updater::Version::from_str("v2.1.2")),
-            false
+    #[tokio::test]
+    async fn should_update_pinned() {
+        use crate::{
+            structs::{DeviceSettings, SharedAppState},
+            test_util::{TempDir, esp_info, image},
+            updater::{Version, should_update},
+        };
+
+        let dir = TempDir::new("pinned");
+        let config = crate::config::Config {
+            dev: true,
+            firmware_dir: dir.join("firmware"),
+            data_dir: dir.join("data"),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(&config.firmware_dir).unwrap();
+        for version in ["v3.0.0", "v3.1.0"] {
+            let data = image("v3", "STATION", version);
+            std::fs::write(config.firmware_dir.join(format!("{version}.bin")), data).unwrap();
+        }
+
+        let state = SharedAppState::new(config, None).await;
+        crate::firmware_catalogue::refresh(&state).await.unwrap();
+        state.inner.write().await.devices_settings.insert(
+            0x1234,
+            DeviceSettings {
+                sign_key: None,
+                room: None,
+            },
         );
+        let info = |version: &str| esp_info(0x1234, version);
 
+        // older pinned version is pushed (rollback)
+        state
+            .firmware_pins
+            .lock()
+            .await
+            .set_device(0x1234, Some("v3.0.0".to_string()))
+            .await
+            .unwrap();
+        let firmware = should_update(&state, &info("v3.1.0")).await.unwrap();
+        assert_eq!(firmware.unwrap().version, Version::from_str("v3.0.0"));
+        assert!(
+            should_update(&state, &info("v3.0.0"))
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        // pinned image not in firmware dir
+        state
+            .firmware_pins
+            .lock()
+            .await
+            .set_hardware("v3".to_string(), Some("v2.9.0".to_string()))
+            .await
+            .unwrap();
+        state
+            .firmware_pins
+            .lock()
+            .await
+            .set_device(0x1234, None)
+            .await
+            .unwrap();
+        assert!(
+            should_update(&state, &info("v3.1.0"))
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    /// Device receiving windowed transfer, replies with scripted frames
+    /// (None: doesn't reply at all)
+    struct FakeDevice {
+        chunks: Vec<(u32, Vec<u8>)>,
+        replies: std::collections::VecDeque<Option<axum::extract::ws::Message>>,
+    }
+
+    impl crate::updater::UpdateSocket for FakeDevice {
+        async fn send(&mut self, msg: axum::extract::ws::Message) -> anyhow::Result<()> {
+            if let axum::extract::ws::Message::Binary(frame) = msg {
+                let seq = u32::from_le_bytes(frame[..4].try_into().unwrap());
+                self.chunks.push((seq, frame[4..].to_vec()));
+            }
+
+            Ok(())
+        }
+
+        async fn recv(&mut self) -> Option<anyhow::Result<axum::extract::ws::Message>> {
+            match self.replies.pop_front() {
+                Some(Some(msg)) => Some(Ok(msg)),
+                _ => std::future::pending().await,
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn windowed_transfer() {
+        use crate::{
+            structs::{SharedAppState, TimerPacket, TimerPacketInner},
+            updater::{TransferEnd, TransferTracker, TransferWindow, UpdateTimeout, send_windowed},
+        };
+        use axum::extract::ws::Message;
+
+        let ack = |seq: u32| {
+            let packet = TimerPacket {
+                tag: None,
+                data: TimerPacketInner::UpdateAck { seq },
+            };
+            Some(Message::Text(
+                serde_json::to_string(&packet).unwrap().into(),
+            ))
+        };
+
+        let data_dir = crate::test_util::TempDir::new("windowed");
+        let config = crate::config::Config {
+            data_dir: data_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        let state = SharedAppState::new(config, None).await;
+        let data: Vec<u8> = (0..20).collect();
+        let window = TransferWindow {
+            chunk_size: 4,
+            window: 2,
+        };
+        let tracker = || TransferTracker {
+            esp_id: 0x1234,
+            image_crc: 1,
+            size: 20,
+            reported_percentage: 0,
+        };
+
+        // out of range, stale and other frames are ignored, acks are cumulative
+        let mut device = FakeDevice {
+            chunks: Vec::new(),
+            replies: [
+                ack(0),
+                ack(5),
+                Some(Message::Text(r#"{"data":"dump_crash_log"}"#.into())),
+                ack(2),
+                ack(1),
+                ack(4),
+            ]
+            .into(),
+        };
+        let res = send_windowed(&mut device, &state, &mut tracker(), &data, 2, window).await;
+        assert!(matches!(res, Ok(TransferEnd::Completed)));
+        let seqs: Vec<u32> = device.chunks.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(seqs, [0, 1, 2, 3, 4]);
+        let sent: Vec<u8> = device.chunks.into_iter().flat_map(|(_, c)| c).collect();
+        assert_eq!(sent, data[2..]);
+        assert_eq!(state.update_progress.lock().await[&0x1234].acked, 20);
+
+        // no ack within timeout
+        let mut device = FakeDevice {
+            chunks: Vec::new(),
+            replies: [ack(0), None].into(),
+        };
+        let res = send_windowed(&mut device, &state, &mut tracker(), &data, 0, window).await;
+        assert!(matches!(res, Err(e) if e.is::<UpdateTimeout>()));
+        assert_eq!(device.chunks.len(), 3);
+        assert_eq!(state.update_progress.lock().await[&0x1234].acked, 4);
+
+        let mut device = FakeDevice {
+            chunks: Vec::new(),
+            replies: [Some(Message::Close(None))].into(),
+        };
+        let res = send_windowed(&mut device, &state, &mut tracker(), &data, 0, window).await;
+        assert!(matches!(res, Ok(TransferEnd::Closed)));
+    }
+
+    #[test]
+    fn negotiate_window() {
+        use crate::{http::EspConnectInfo, test_util::esp_info, updater::TransferWindow};
+
+        let info = |caps: &str| EspConnectInfo {
+            caps: caps.to_string(),
+            ..esp_info(0x1234, "v3.0.0")
+        };
+
+        assert_eq!(TransferWindow::negotiate(&info("hmac,ota_resume")), None);
+        assert_eq!(
+            TransferWindow::negotiate(&info("hmac,ota_window")),
+            Some(TransferWindow {
+                chunk_size: 4096,
+                window: 4
+            })
+        );
         assert_eq!(
-            crate::updater::Version::from_str("v2.1.2")
-                .is_newer(&crate::updater::Version::from_str("v2.2.0")),
-            true
+            TransferWindow::negotiate(&info("ota_window=8, ota_chunk=8192")),
+            Some(TransferWindow {
+                chunk_size: 8192,
+                window: 8
+            })
         );
+        assert_eq!(
+            TransferWindow::negotiate(&info("ota_window=1000,ota_chunk=1")),
+            Some(TransferWindow {
+                chunk_size: 512,
+                window: 16
+            })
+        );
+    }
+
+    #[test]
+    fn parse() {
+        use crate::updater::Version;
+
+        let cases = [
+            ("v2.1.0", Version::Stable("2.1.0".to_string())),
+            ("v2.4", Version::Stable("2.4".to_string())),
+            ("v3.0.0-rc.1", Version::Stable("3.0.0-rc.1".to_string())),
+            (
+                "v3.0.0-rc.1+build.5",
+                Version::Stable("3.0.0-rc.1+build.5".to_string()),
+            ),
+            (
+                "v3.0.0+20240101",
+                Version::Stable("3.0.0+20240101".to_string()),
+            ),
+            ("D1714320292", Version::Dev(1714320292)),
+            ("DV1714320292", Version::Dev(1714320292)),
+            ("DV", Version::Other),
+            ("Dabc", Version::Other),
+            ("v", Version::Other),
+            ("v3.x.0", Version::Other),
+            ("v3.0.0.1", Version::Other),
+            ("v03.0.0", Version::Other),
+            ("v3.0.0-", Version::Other),
+            ("v3.0.0-rc..1", Version::Other),
+            ("v3.0.0-rc.01", Version::Other),
+            ("v3.0.0+", Version::Other),
+            ("v3.0.0-rc_1", Version::Other),
+            ("3.0.0", Version::Other),
+            ("", Version::Other),
+        ];
+
+        for (string, expected) in cases {
+            assert_eq!(Version::from_str(string), expected, "{string}");
+        }
+    }
+
+    #[test]
+    fn ordering() {
+        use crate::updater::Version;
+
+        // (current, candidate, candidate is newer)
+        let cases = [
+            // stable
+            ("v2.0.0", "v2.0.1", true),
+            ("v2.0.1", "v2.0.0", false),
+            ("v2.0.0", "v2.0.0", false),
+            ("v2.1.0", "v2.1.12", true),
+            ("v2.1.2", "v2.2.0", true),
+            ("v2.2.0", "v2.1.2", false),
+            ("v2.9.0", "v2.10.0", true),
+            // missing patch is 0: `v2.1` and `v2.1.0` are the same release
+            // (old string comparison treated `v2.1.0` as newer)
+            ("v2.1", "v2.1.0", false),
+            ("v2.1", "v2.1.1", true),
+            ("v2.4", "v2.3.9", false),
+            // pre-release (SemVer 2.0 precedence)
+            ("v3.0.0-rc1", "v3.0.0", true),
+            ("v3.0.0", "v3.0.0-rc1", false),
+            ("v2.9.0", "v3.0.0-rc1", true),
+            ("v3.0.0-alpha", "v3.0.0-alpha.1", true),
+            ("v3.0.0-alpha.1", "v3.0.0-alpha.beta", true),
+            ("v3.0.0-alpha.beta", "v3.0.0-beta", true),
+            ("v3.0.0-beta", "v3.0.0-beta.2", true),
+            ("v3.0.0-beta.2", "v3.0.0-beta.11", true),
+            ("v3.0.0-beta.11", "v3.0.0-rc.1", true),
+            ("v3.0.0-rc.1", "v3.0.0", true),
+            ("v3.0.0-beta.11", "v3.0.0-beta.2", false),
+            ("v3.0.0-rc.1", "v3.0.0-rc.1", false),
+            // build metadata is ignored
+            ("v3.0.0+build.1", "v3.0.0+build.2", false),
+            ("v3.0.0+build.2", "v3.0.0+build.1", false),
+            ("v3.0.0+build.1", "v3.0.1+build.0", true),
+            // dev
+            ("D1714320292", "D1714320295", true),
+            ("D1714320292", "D1714320291", false),
+            // `DV` prefix is parsed as dev epoch too
+            // (old parser read them as epoch 0, so they never updated to newer `DV` builds)
+            ("DV1714320292", "DV1714320295", true),
+            ("DV1714320292", "D1714320295", true),
+            ("D1714320295", "DV1714320292", false),
+            // other and mixed (never compared in practice)
+            ("other", "v2.1.0", true),
+            ("other", "DV1321", true),
+            ("DV1714320292", "v2.1.0", true),
+            ("v2.1.0", "D1714320292", true),
+            ("v2.1.0", "other", false),
+            ("v2.1.0", "v3.x", false),
+        ];
+
+        for (current, candidate, newer) in cases {
+            assert_eq!(
+                Version::from_str(current).is_newer(&Version::from_str(candidate)),
+                newer,
+                "{current} -> {candidate}"
+            );
+        }
     }
 }
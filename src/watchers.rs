@@ -8,23 +8,30 @@ use std::{
 use tracing::error;
 
 const GITHUB_UPDATE_INTERVAL: u64 = 60000 * 5;
+const ROLLOUT_POLL_INTERVAL: u64 = 30000;
+const BATTERY_SAVE_INTERVAL: u64 = 60000;
+const CONNECTED_DEVICES_SAVE_INTERVAL: u64 = 5000;
 
 pub async fn spawn_watchers(state: SharedAppState) -> Result<()> {
-    let firmware_dir = std::env::var("FIRMWARE_DIR").expect("FIRMWARE_DIR not set");
-    let firmware_dir = std::path::PathBuf::from(firmware_dir);
+    let firmware_dir = state.config.read().await.firmware_dir.clone();
 
-    let mut dev_build_interval = tokio::time::interval(Duration::from_secs(1));
+    let mut firmware_dir_interval = tokio::time::interval(Duration::from_secs(1));
     let mut github_releases_interval =
         tokio::time::interval(Duration::from_millis(GITHUB_UPDATE_INTERVAL));
+    let mut update_queue = state.update_scheduler.subscribe();
+    let mut rollout_interval = tokio::time::interval(Duration::from_millis(ROLLOUT_POLL_INTERVAL));
+    let mut battery_save_interval =
+        tokio::time::interval(Duration::from_millis(BATTERY_SAVE_INTERVAL));
+    let mut connected_devices_interval =
+        tokio::time::interval(Duration::from_millis(CONNECTED_DEVICES_SAVE_INTERVAL));
 
-    let mut last_build_modified: u128 = 0;
     tokio::task::spawn(async move {
         loop {
             tokio::select! {
-                _ = dev_build_interval.tick() => {
-                    let res = dev_build_watcher(&state, &firmware_dir, &mut last_build_modified).await;
+                _ = firmware_dir_interval.tick() => {
+                    let res = firmware_dir_watcher(&state).await;
                     if let Err(e) = res {
-                        error!("Error in build watcher: {:?}", e);
+                        error!("Error in firmware dir watcher: {:?}", e);
                     }
                 }
                 _ = github_releases_interval.tick() => {
@@ -33,6 +40,32 @@ pub async fn spawn_watchers(state: SharedAppState) -> Result<()> {
                         error!("Error in github releases watcher: {:?}", e);
                     }
                 }
+                _ = rollout_interval.tick() => {
+                    crate::rollout::poll(&state).await;
+                    crate::updater::expire_updates(&state).await;
+                }
+                _ = battery_save_interval.tick() => {
+                    let res = state.battery_history.lock().await.flush().await;
+                    if let Err(e) = res {
+                        error!("Battery history save error: {:?}", e);
+                    }
+                }
+                _ = connected_devices_interval.tick() => {
+                    let res = state.connected_devices.lock().await.flush().await;
+                    if let Err(e) = res {
+                        error!("Connected devices save error: {:?}", e);
+                    }
+                }
+                Ok(_) = update_queue.changed() => {
+                    let queue = update_queue.borrow_and_update().clone();
+                    tracing::info!(
+                        "Update queue: updating {:X?}, waiting {:X?}",
+                        queue.active,
+                        queue.waiting
+                    );
+
+                    _ = crate::socket::api::send_update_queue(&queue).await;
+                }
             }
         }
     });
@@ -40,36 +73,14 @@ pub async fn spawn_watchers(state: SharedAppState) -> Result<()> {
     Ok(())
 }
 
-async fn dev_build_watcher(
-    state: &SharedAppState,
-    firmware_dir: &Path,
-    last_modified: &mut u128,
-) -> Result<()> {
-    if !state.dev_mode {
-        return Ok(());
-    }
-
-    let mut modified_state = false;
-    for entry in firmware_dir.read_dir()? {
-        let entry = entry?;
-
-        if entry.file_type()?.is_dir() {
-            continue;
-        }
-
-        let modified = entry
-            .metadata()?
-            .modified()?
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_millis();
-
-        if modified > *last_modified {
-            *last_modified = modified;
-            modified_state = true;
-        }
+/// Index changed firmware files (new dev builds are sent to devices immediately)
+async fn firmware_dir_watcher(state: &SharedAppState) -> Result<()> {
+    let changed = crate::firmware_catalogue::refresh(state).await?;
+    if changed {
+        crate::retention::run(state).await?;
     }
 
-    if modified_state {
+    if changed && state.dev_mode {
         _ = state.build_broadcast().await;
     }
 
@@ -78,7 +89,11 @@ async fn dev_build_watcher(
 
 async fn github_releases_watcher(state: &SharedAppState, firmware_dir: &Path) -> Result<()> {
     let client = reqwest::Client::builder().user_agent("Fkm/2.0").build()?;
-    let files = crate::github::get_releases(&client).await?;
+    let mut files = crate::github::get_releases(&client).await?;
+
+    // detached signatures are downloaded before images they sign
+    files.sort_by_key(|file| !file.name.ends_with(".sig"));
+    let deleted = crate::retention::load_deleted(firmware_dir).await;
 
     for file in files {
         let safe_name = std::path::Path::new(&file.name)
@@ -95,19 +110,25 @@ async fn github_releases_watcher(state: &SharedAppState, firmware_dir: &Path) ->
 
         let release_path = firmware_dir.join(safe_name);
         let tmp_path = PathBuf::from("/tmp").join(safe_name);
-        if release_path.extension() != Some(OsStr::new("bin")) {
+        let is_signature = release_path.extension() == Some(OsStr::new("sig"));
+        if release_path.extension() != Some(OsStr::new("bin")) && !is_signature {
             continue;
         }
 
+        // images archived or deleted by retention aren't downloaded again
         if let Ok(exists) = tokio::fs::try_exists(&release_path).await
             && !exists
+            && !crate::retention::is_removed(firmware_dir, safe_name, &deleted).await
         {
             let resp = client.get(&file.download_url).send().await?;
             tokio::fs::write(&tmp_path, resp.bytes().await?).await?;
             move_file(&tmp_path, &release_path).await?;
 
             tracing::info!("Downloaded new release: {}", file.name);
-            _ = state.build_broadcast().await;
+            if !is_signature {
+                crate::firmware_catalogue::refresh(state).await?;
+                _ = state.build_broadcast().await;
+            }
         }
     }
 
@@ -117,7 +138,10 @@ async fn github_releases_watcher(state: &SharedAppState, firmware_dir: &Path) ->
 async fn move_file(src: impl AsRef<Path>, dest: impl AsRef<Path>) -> Result<()> {
     if let Err(e) = tokio::fs::rename(&src, &dest).await {
         if e.kind().to_string().contains("cross-device link or rename") {
-            tokio::fs::copy(&src, &dest).await?;
+            // hidden copy isn't indexed before it's complete
+            let tmp_path = crate::fs_util::tmp_path(dest.as_ref());
+            tokio::fs::copy(&src, &tmp_path).await?;
+            tokio::fs::rename(&tmp_path, &dest).await?;
             tokio::fs::remove_file(&src).await?;
         } else {
             return Err(e.into());
@@ -40,6 +40,52 @@ pub enum UnixRequestData {
         esp_id: u32,
         battery_percentage: u8,
     },
+    BatteryAlert {
+        esp_id: u32,
+        battery_percentage: u8,
+        voltage: Option<f64>,
+        minutes_left: Option<u64>,
+    },
+    /// Device clock offset (device clock - server clock) estimated in sync round
+    ClockOffset {
+        esp_id: u32,
+        offset_ms: i64,
+        delay_ms: u64,
+    },
+    UpdateStarted {
+        esp_id: u32,
+        from_version: String,
+        to_version: String,
+    },
+    UpdateProgress {
+        esp_id: u32,
+        percentage: u8,
+    },
+
+    /// Whole image transferred (device is flashing it and rebooting)
+    UpdateCompleted {
+        esp_id: u32,
+        version: String,
+    },
+
+    /// Device reconnected with the target version
+    UpdateVerified {
+        esp_id: u32,
+        version: String,
+    },
+    UpdateFailed {
+        esp_id: u32,
+        version: String,
+        reason: UpdateFailureReason,
+
+        /// Version reported after reboot (for `VersionMismatch`)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reported_version: Option<String>,
+    },
+    UpdateQueue {
+        active: Vec<u32>,
+        waiting: Vec<u32>,
+    },
     RequestToConnectDevice {
         esp_id: u32,
         sign_key: u32,
@@ -60,4 +106,18 @@ pub enum UnixRequestData {
         esp_id: u32,
         snapshot: SnapshotData,
     },
+
+    /// Backend command (e.g. `PinFirmware`) couldn't be applied
+    CommandFailed {
+        command: String,
+        error: String,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum UpdateFailureReason {
+    Timeout,
+    Closed,
+    VersionMismatch,
 }
@@ -34,6 +34,12 @@ pub enum UnixResponseData {
     EnterAttemptResp {
         message: String,
     },
+    CreateAttendanceResp {
+        name: String,
+
+        #[serde(default)]
+        message: Option<String>,
+    },
     PersonInfoResp {
         id: String,
         registrant_id: Option<i64>,
@@ -44,8 +50,16 @@ pub enum UnixResponseData {
         can_compete: bool,
         possible_groups: Vec<PossibleGroup>,
     },
+    /// Sent to `esp_id`, devices in `rooms` or all devices (if `all` is set)
     CustomMessage {
-        esp_id: u32,
+        #[serde(default)]
+        esp_id: Option<u32>,
+
+        #[serde(default)]
+        rooms: Vec<String>,
+
+        #[serde(default)]
+        all: bool,
         line1: String,
         line2: String,
     },
@@ -69,9 +83,32 @@ pub enum UnixResponseData {
     UploadFirmware {
         file_name: String,
         file_data: String,
+
+        /// Detached ed25519 signature (base64), if not embedded in file
+        #[serde(default)]
+        signature: Option<String>,
+    },
+    /// Pin device (or every device with given hardware) to exact firmware version,
+    /// `version: None` removes pin
+    PinFirmware {
+        #[serde(default)]
+        esp_id: Option<u32>,
+
+        #[serde(default)]
+        hw: Option<String>,
+        version: Option<String>,
     },
+    /// Resume halted staged rollouts
+    ResumeRollouts,
     SetDeviceSettings {
+        #[serde(default)]
         devices: Vec<u32>,
+
+        #[serde(default)]
+        rooms: Vec<String>,
+
+        #[serde(default)]
+        all: bool,
         volume: Option<u8>,
     },
 }
@@ -108,6 +145,9 @@ pub struct CompetitionStatusResp {
 pub struct CompetitionStatusDevice {
     pub esp_id: u32,
     pub sign_key: Option<u32>,
+
+    #[serde(default)]
+    pub room: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]